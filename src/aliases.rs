@@ -0,0 +1,184 @@
+use crate::config::AliasesConfiguration;
+
+use failure::{format_err, Error};
+use fnv::FnvHashMap;
+use serde_json::Value as JsonValue;
+use shellexpand::tilde as TildeExpand;
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+/// The role a short name points at.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Alias {
+  /// The role to assume.
+  pub role_arn: String,
+  /// A profile in the AWS credentials file to assume the role with, instead of the base credentials.
+  pub source_profile: Option<String>,
+}
+
+impl Alias {
+  /// An alias for a role, assumed with the base credentials.
+  pub fn new(role_arn: &str) -> Self {
+    Alias {
+      role_arn: role_arn.to_owned(),
+      source_profile: None,
+    }
+  }
+}
+
+/// Loads every alias from rapture, the AWS config file, alias files, and the configuration itself.
+/// Later sources win when a name is in more than one.
+pub fn load_aliases(config: &AliasesConfiguration) -> Result<FnvHashMap<String, Alias>, Error> {
+  let mut aliases = FnvHashMap::default();
+
+  if config.get_rapture() {
+    aliases.extend(read_rapture_aliases(&config.get_rapture_path())?);
+  }
+  if config.get_aws_config() {
+    aliases.extend(read_aws_config_aliases(&config.get_aws_config_path())?);
+  }
+  for file in config.get_files() {
+    aliases.extend(read_alias_file(&file)?);
+  }
+  for (name, role_arn) in config.get_roles() {
+    aliases.insert(name, Alias::new(&role_arn));
+  }
+
+  Ok(aliases)
+}
+
+/// Reads rapture's aliases. It's fine for rapture to not be setup, so a missing, or malformed
+/// file simply has no aliases in it.
+fn read_rapture_aliases(path: &str) -> Result<FnvHashMap<String, Alias>, Error> {
+  let expanded_path = TildeExpand(path).into_owned();
+  let rapture_path = Path::new(&expanded_path);
+  let mut aliases = FnvHashMap::default();
+
+  if rapture_path.exists() {
+    let rapture_json: Result<JsonValue, _> = serde_json::from_reader(BufReader::new(File::open(rapture_path)?));
+    if let Ok(JsonValue::Object(rapture_obj)) = rapture_json {
+      for (key, value) in rapture_obj {
+        if let JsonValue::String(role) = value {
+          aliases.insert(key, Alias::new(&role));
+        }
+      }
+    }
+  }
+
+  Ok(aliases)
+}
+
+/// Reads an alias file someone asked for, which unlike rapture has to exist, and be well formed.
+fn read_alias_file(path: &str) -> Result<FnvHashMap<String, Alias>, Error> {
+  let expanded_path = TildeExpand(path).into_owned();
+  let contents = fs::read_to_string(&expanded_path)
+    .map_err(|err| format_err!("Failed to read alias file: {}: {}", expanded_path, err))?;
+  let parsed: JsonValue = if expanded_path.ends_with(".toml") {
+    toml::from_str(&contents).map_err(|err| format_err!("Failed to parse alias file: {}: {}", expanded_path, err))?
+  } else {
+    serde_json::from_str(&contents).map_err(|err| format_err!("Failed to parse alias file: {}: {}", expanded_path, err))?
+  };
+
+  let entries = match parsed {
+    JsonValue::Object(entries) => entries,
+    _ => return Err(format_err!("The alias file: {} isn't a table of aliases", expanded_path)),
+  };
+  let mut aliases = FnvHashMap::default();
+  for (name, value) in entries {
+    let alias = match value {
+      JsonValue::String(role_arn) => Alias::new(&role_arn),
+      JsonValue::Object(ref table) => match table.get("role_arn") {
+        Some(JsonValue::String(role_arn)) => Alias {
+          role_arn: role_arn.to_owned(),
+          source_profile: table
+            .get("source_profile")
+            .and_then(|profile| profile.as_str())
+            .map(|profile| profile.to_owned()),
+        },
+        _ => return Err(format_err!("The alias: {} in: {} has no `role_arn`", name, expanded_path)),
+      },
+      _ => {
+        return Err(format_err!(
+          "The alias: {} in: {} isn't a role arn, or a table with a `role_arn`",
+          name,
+          expanded_path
+        ))
+      }
+    };
+    aliases.insert(name, alias);
+  }
+
+  Ok(aliases)
+}
+
+/// Reads every profile with a `role_arn` out of the AWS config file, by its profile name.
+/// Like rapture it's fine for there to be no AWS config file.
+fn read_aws_config_aliases(path: &str) -> Result<FnvHashMap<String, Alias>, Error> {
+  let expanded_path = TildeExpand(path).into_owned();
+  if !Path::new(&expanded_path).is_file() {
+    return Ok(FnvHashMap::default());
+  }
+  let contents = fs::read_to_string(&expanded_path)?;
+
+  Ok(
+    parse_aws_config(&contents)
+      .into_iter()
+      .filter_map(|(profile, mut settings)| {
+        let role_arn = settings.remove("role_arn")?;
+        Some((
+          profile,
+          Alias {
+            role_arn,
+            source_profile: settings.remove("source_profile"),
+          },
+        ))
+      })
+      .collect(),
+  )
+}
+
+/// Parses the ini-ish AWS config file into the settings of each profile. Only `[default]`, and
+/// `[profile <name>]` sections are profiles, the indented settings nested under a key
+/// (like `s3 =`) are skipped.
+fn parse_aws_config(contents: &str) -> FnvHashMap<String, FnvHashMap<String, String>> {
+  let mut profiles: FnvHashMap<String, FnvHashMap<String, String>> = FnvHashMap::default();
+  let mut current: Option<String> = None;
+  let mut nested = false;
+
+  for raw_line in contents.lines() {
+    let line = raw_line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+      continue;
+    }
+    if line.starts_with('[') && line.ends_with(']') {
+      let section = line[1..line.len() - 1].trim();
+      current = if section == "default" {
+        Some(section.to_owned())
+      } else {
+        section.strip_prefix("profile ").map(|profile| profile.trim().to_owned())
+      };
+      nested = false;
+      continue;
+    }
+    if nested && raw_line.starts_with(char::is_whitespace) {
+      continue;
+    }
+
+    let mut parts = line.splitn(2, '=');
+    let key = parts.next().unwrap_or("").trim();
+    let value = parts.next().unwrap_or("").trim();
+    nested = value.is_empty();
+    if let Some(ref profile) = current {
+      if !key.is_empty() && !value.is_empty() {
+        profiles
+          .entry(profile.to_owned())
+          .or_default()
+          .insert(key.to_owned(), value.to_owned());
+      }
+    }
+  }
+
+  profiles
+}
@@ -1,127 +1,439 @@
+use crate::config::{CacheCompression, Configuration};
+use crate::ec2_utils::{to_safe, RegionInventory};
+
 use chrono::prelude::*;
-use failure::{Error, err_msg};
+use failure::{Error, err_msg, format_err};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression as GzCompression;
+use fnv::FnvHasher;
+use fs2::FileExt;
+use serde_derive::{Deserialize, Serialize};
 use shellexpand::tilde as TildeExpand;
-use serde_json::{from_str as JsonFromStr, to_string as JsonToStr, Value as JsonValue};
+use serde_json::to_string as JsonToStr;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+use std::fs::{
+  create_dir as CreateDir, create_dir_all as CreateDirAll, read_dir as ReadDir, remove_dir_all as RemoveDirAll,
+  remove_file as RemoveFile, rename as Rename, File, OpenOptions,
+};
+use std::hash::Hasher;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// The first line of a cache file. It holds everything needed to tell whether the inventory that
+/// follows it is still the inventory we'd produce today, so that can be decided without reading
+/// (or decompressing) the inventory itself.
+#[derive(Deserialize, Serialize)]
+struct CacheHeader {
+  /// When the inventory was scanned, as a unix timestamp.
+  generated_at: i64,
+  /// The version of ec2-rs that scanned it.
+  version: String,
+  /// A hash of the configuration it was scanned with, see: `hash_configuration`.
+  config_hash: String,
+  /// The role that was scanned.
+  role: String,
+  /// The region that was scanned.
+  region: String,
+  /// How many hosts are in the inventory.
+  host_count: usize,
+  /// How the inventory following the header is compressed.
+  compression: CacheCompression,
+}
+
+/// The roles found through Organizations, or the regions enabled in an account, cached so they aren't
+/// looked up on every run.
+#[derive(Deserialize, Serialize)]
+struct Discovered {
+  /// When they were looked up, as a unix timestamp.
+  generated_at: i64,
+  /// The version of ec2-rs that looked them up.
+  version: String,
+  /// A hash of the configuration they were looked up with, see: `hash_configuration`, and `hash_discovery`.
+  config_hash: String,
+  found: Vec<String>,
+}
+
+/// Hashes everything in the configuration that changes what the inventory of a region looks like.
+/// The cache settings, and the list of regions (along with `exclude_regions`, and `discovery_region`) are left
+/// out, so tweaking the max age, or adding a region doesn't throw away the cache.
+fn hash_configuration(config: &Configuration) -> Result<String, Error> {
+  let mut as_value = serde_json::to_value(config)?;
+  if let Some(as_object) = as_value.as_object_mut() {
+    as_object.remove("cache_path");
+    as_object.remove("cache_max_age");
+    as_object.remove("cache_max_stale");
+    as_object.remove("cache_compression");
+    if let Some(ec2) = as_object.get_mut("ec2").and_then(|ec2| ec2.as_object_mut()) {
+      ec2.remove("regions");
+      ec2.remove("exclude_regions");
+      ec2.remove("discovery_region");
+    }
+  }
+  // `serde_json::Value` keeps object keys sorted, so this is stable across runs.
+  let mut hasher = FnvHasher::default();
+  hasher.write(JsonToStr(&as_value)?.as_bytes());
+  Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Hashes the configuration hash along with `discovery_region`, for the enabled regions of each account. The regions
+/// looked up from one partition (like `aws-cn`, or `aws-us-gov`) don't apply once it's changed to another.
+fn hash_discovery(config_hash: &str, config: &Configuration) -> String {
+  let mut hasher = FnvHasher::default();
+  hasher.write(config_hash.as_bytes());
+  hasher.write(config.ec2.get_discovery_region().as_bytes());
+  format!("{:016x}", hasher.finish())
+}
+
+/// Whether a cache entry can be used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheEntryStatus {
+  /// Younger than `cache_max_age`.
+  Valid,
+  /// Expired, but younger than `cache_max_stale` so it can be served while it's refreshed.
+  Stale,
+  /// Expired.
+  Expired,
+  /// Written by another version of ec2-rs, or with a different configuration.
+  Outdated,
+}
 
-use std::fs::{create_dir as CreateDir, File, metadata as GetFileMetadata, OpenOptions};
-use std::io::{Read, Write};
-use std::path::Path;
+impl CacheEntryStatus {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      CacheEntryStatus::Valid => "valid",
+      CacheEntryStatus::Stale => "stale",
+      CacheEntryStatus::Expired => "expired",
+      CacheEntryStatus::Outdated => "outdated",
+    }
+  }
+}
 
-/// Handles Caching the Data returned from the EC2 API.
+/// The cached inventory of a single account, and region.
+pub struct CacheEntry {
+  /// The role that was scanned.
+  pub role: String,
+  /// The region that was scanned.
+  pub region: String,
+  /// When the inventory was scanned, as a unix timestamp.
+  pub generated_at: i64,
+  /// How many hosts are in the inventory.
+  pub host_count: usize,
+  /// Whether the entry can be used.
+  pub status: CacheEntryStatus,
+  compression: CacheCompression,
+  config_hash: String,
+  /// Where the entry lives. Only the header is read up front, the file is opened again to load the inventory
+  /// so listing thousands of entries doesn't hold thousands of files open.
+  path: PathBuf,
+}
+
+impl CacheEntry {
+  /// Determines if the entry is valid based on the timeout the user set.
+  pub fn is_valid(&self) -> bool {
+    self.status == CacheEntryStatus::Valid
+  }
+
+  /// Determines if the entry has expired, but is still young enough to serve while it's refreshed in the background.
+  pub fn is_servable_while_stale(&self) -> bool {
+    self.status == CacheEntryStatus::Stale
+  }
+
+  /// Gets how long ago the entry was scanned in seconds.
+  pub fn get_age(&self) -> i64 {
+    Utc::now().timestamp() - self.generated_at
+  }
+
+  /// Reads the inventory itself out of the entry. Consuming the entry as it should no longer be needed.
+  /// Fails when the entry was replaced since its header was read, rather than loading an inventory
+  /// the header didn't describe.
+  pub fn load_inventory(self) -> Result<RegionInventory, Error> {
+    let (header, body) = read_header(&self.path)?;
+    if header.generated_at != self.generated_at || header.config_hash != self.config_hash {
+      return Err(format_err!(
+        "The cache entry of {} in {} was replaced while it was being read",
+        self.role,
+        self.region
+      ));
+    }
+    let inventory = match self.compression {
+      CacheCompression::None => serde_json::from_reader(body)?,
+      CacheCompression::Gzip => serde_json::from_reader(GzDecoder::new(body))?,
+      CacheCompression::Zstd => serde_json::from_reader(ZstdDecoder::with_buffer(body)?)?,
+    };
+    Ok(inventory)
+  }
+}
+
+/// Opens a cache file, and reads its header, leaving the file just past it.
+fn read_header(path: &Path) -> Result<(CacheHeader, BufReader<File>), Error> {
+  let mut body = BufReader::new(File::open(path)?);
+  let mut header_line = Vec::new();
+  body.read_until(b'\n', &mut header_line)?;
+  Ok((serde_json::from_slice(&header_line)?, body))
+}
+
+/// Handles Caching the Data returned from the EC2 API. Each account, and region is cached on
+/// its own under `<cache_path>/ec2-rs/<role>/<region>`, so it can be shared between runs
+/// with different sets of roles, and refreshed on its own.
 pub struct Cache {
-  path_to_cache: String,
-  valid_cache: bool,
-  potential_json_value: Option<JsonValue>,
+  root: PathBuf,
+  config_hash: String,
+  /// The hash the enabled regions of each account are cached under, see: `hash_discovery`.
+  discovery_hash: String,
+  max_age: u64,
+  max_stale: Option<u64>,
+  compression: CacheCompression,
 }
 
 impl Cache {
   /// Creates a new instance of the Cache.
-  pub fn new(root_path: String, account_names: String, timeout_seconds: u64) -> Result<Self, Error> {
-    let expanded_path = TildeExpand(&root_path).into_owned();
+  pub fn new(config: &Configuration) -> Result<Self, Error> {
+    let expanded_path = TildeExpand(&config.get_cache_path()).into_owned();
     let path = Path::new(&expanded_path);
-    let parent = path.parent();
-    if !parent.is_some() {
-      return Err(err_msg("Can't find root path!"));
-    }
-    let parent = parent.unwrap();
+    let parent = path.parent().ok_or_else(|| err_msg("Can't find root path!"))?;
     if !parent.exists() {
-      CreateDir(parent.to_str().unwrap())?;
+      CreateDir(parent)?;
     }
 
-    let mut potential_json_value = None;
-    let final_path = path.join(account_names);
-
-    let valid_cache = if let Ok(metadata) = GetFileMetadata(Path::new(&final_path)) {
-      if let Ok(created_at) = metadata.created() {
-        if let Ok(duration) = created_at.elapsed() {
-          if duration.as_secs() < timeout_seconds {
-            true
-          } else {
-            false
-          }
-        } else {
-          false
-        }
-      } else {
-        use std::process::Command;
-        // Metadata.created() returns nothing on non-bsd linux's :(
-        if let Ok(output) = Command::new("date")
-          .arg("-R")
-          .arg("-r")
-          .arg(&final_path)
-          .output()
-        {
-          if let Ok(as_str) = String::from_utf8(output.stdout) {
-            if let Ok(dt) = DateTime::parse_from_rfc2822(as_str.trim()) {
-              if (dt.timestamp() + timeout_seconds as i64) >= Utc::now().timestamp() {
-                true
-              } else {
-                false
-              }
-            } else {
-              false
-            }
-          } else {
-            false
-          }
-        } else {
-          false
-        }
-      }
+    let config_hash = hash_configuration(config)?;
+    Ok(Cache {
+      root: path.join("ec2-rs"),
+      discovery_hash: hash_discovery(&config_hash, config),
+      config_hash,
+      max_age: config.get_cache_max_age(),
+      max_stale: config.get_cache_max_stale(),
+      compression: config.get_cache_compression(),
+    })
+  }
+
+  /// Gets the path an account, and region is cached at.
+  fn entry_path(&self, role: &str, region: &str) -> PathBuf {
+    self.root.join(to_safe(role)).join(to_safe(region))
+  }
+
+  /// Reads the cached inventory of an account, and region.
+  ///
+  /// An entry written by another version of ec2-rs, or with a different configuration is ignored
+  /// entirely. Otherwise it's valid as long as it's younger than `cache_max_age`, and can be served
+  /// while it's refreshed as long as it's younger than `cache_max_stale`.
+  pub fn read_entry(&self, role: &str, region: &str) -> Option<CacheEntry> {
+    self
+      .read_entry_at(&self.entry_path(role, region))
+      .filter(|entry| entry.role == role && entry.region == region && entry.status != CacheEntryStatus::Outdated)
+  }
+
+  fn read_entry_at(&self, path: &Path) -> Option<CacheEntry> {
+    // Only the header is needed, the file is closed again straight away.
+    let (header, _) = read_header(path).ok()?;
+
+    let age = Utc::now().timestamp() - header.generated_at;
+    let status = if header.version != env!("CARGO_PKG_VERSION") || header.config_hash != self.config_hash {
+      CacheEntryStatus::Outdated
+    } else if age < self.max_age as i64 {
+      CacheEntryStatus::Valid
+    } else if self.max_stale.map(|max_stale| age < max_stale as i64).unwrap_or(false) {
+      CacheEntryStatus::Stale
     } else {
-      false
+      CacheEntryStatus::Expired
     };
 
-    if path.exists() && final_path.exists() {
-      let mut non_optional_file = File::open(final_path.clone())?;
-
-      let mut as_str = String::new();
-      let result = non_optional_file.read_to_string(&mut as_str);
-      if result.is_ok() {
-        if as_str.len() != 0 {
-          let data = JsonFromStr(&as_str);
-          if data.is_ok() {
-            let value = data.unwrap();
-            potential_json_value = Some(value);
-          }
+    Some(CacheEntry {
+      role: header.role,
+      region: header.region,
+      generated_at: header.generated_at,
+      host_count: header.host_count,
+      status,
+      compression: header.compression,
+      config_hash: header.config_hash,
+      path: path.to_owned(),
+    })
+  }
+
+  /// Lists every entry in the cache, including the ones that can't be used, sorted by role, and region.
+  pub fn list_entries(&self) -> Result<Vec<CacheEntry>, Error> {
+    let mut entries = Vec::new();
+    if !self.root.exists() {
+      return Ok(entries);
+    }
+
+    for role_dir in ReadDir(&self.root)? {
+      let role_dir = role_dir?.path();
+      if !role_dir.is_dir() {
+        continue;
+      }
+      for entry_path in ReadDir(&role_dir)? {
+        let entry_path = entry_path?.path();
+        // Locks, and temporary files all have an extension, entries never do.
+        if entry_path.extension().is_some() {
+          continue;
+        }
+        if let Some(entry) = self.read_entry_at(&entry_path) {
+          entries.push(entry);
         }
       }
     }
 
-    Ok(Cache {
-      path_to_cache: final_path.to_str().unwrap().to_owned(),
-      potential_json_value: potential_json_value,
-      valid_cache: valid_cache,
-    })
+    entries.sort_by(|a, b| (&a.role, &a.region).cmp(&(&b.role, &b.region)));
+    Ok(entries)
+  }
+
+  /// Removes the cached entries of a role, optionally only in one region. Returns how many were removed.
+  pub fn purge_entries(&self, role: &str, region: Option<&str>) -> Result<usize, Error> {
+    let mut purged = 0;
+    for entry in self.list_entries()? {
+      if entry.role != role || region.map(|region| region != entry.region).unwrap_or(false) {
+        continue;
+      }
+      let entry_path = self.entry_path(&entry.role, &entry.region);
+      RemoveFile(&entry_path)?;
+      let _ = RemoveFile(entry_path.with_extension("lock"));
+      purged += 1;
+    }
+    Ok(purged)
+  }
+
+  /// Removes everything ec2-rs has cached.
+  pub fn purge_all(&self) -> Result<(), Error> {
+    if self.root.exists() {
+      RemoveDirAll(&self.root)?;
+    }
+    Ok(())
+  }
+
+  /// Writes a freshly scanned inventory of an account, and region to the cache, compressed with `cache_compression`.
+  ///
+  /// The data is written to a temporary file next to the entry, and then renamed over it, so
+  /// readers only ever see a complete entry. Writers hold an exclusive lock on `<entry>.lock`
+  /// while doing so, so parallel runs sharing a `cache_path` don't trample each other.
+  pub fn write_entry(&self, role: &str, region: &str, inventory: &RegionInventory) -> Result<(), Error> {
+    let header = CacheHeader {
+      generated_at: Utc::now().timestamp(),
+      version: env!("CARGO_PKG_VERSION").to_owned(),
+      config_hash: self.config_hash.clone(),
+      role: role.to_owned(),
+      region: region.to_owned(),
+      host_count: inventory.hostvars.len(),
+      compression: self.compression,
+    };
+    let mut to_write = serde_json::to_vec(&header)?;
+    to_write.push(b'\n');
+    match self.compression {
+      CacheCompression::None => serde_json::to_writer(&mut to_write, inventory)?,
+      CacheCompression::Gzip => {
+        let mut encoder = GzEncoder::new(to_write, GzCompression::default());
+        serde_json::to_writer(&mut encoder, inventory)?;
+        to_write = encoder.finish()?;
+      }
+      CacheCompression::Zstd => {
+        let mut encoder = ZstdEncoder::new(to_write, 0)?;
+        serde_json::to_writer(&mut encoder, inventory)?;
+        to_write = encoder.finish()?;
+      }
+    }
+
+    let final_path = self.entry_path(role, region);
+    if let Some(parent) = final_path.parent() {
+      CreateDirAll(parent)?;
+    }
+
+    let lock_file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .truncate(false)
+      .open(final_path.with_extension("lock"))?;
+    lock_file.lock_exclusive()?;
+
+    let temp_path = final_path.with_extension(format!("{}.tmp", process::id()));
+    let result = write_and_rename(&temp_path, &final_path, &to_write);
+    if result.is_err() {
+      let _ = RemoveFile(&temp_path);
+    }
+    let _ = FileExt::unlock(&lock_file);
+    result
+  }
+
+  /// Reads the roles found through Organizations from the account of the organization role, as long as they're
+  /// younger than `cache_max_age`.
+  pub fn read_discovered_roles(&self, role: &str) -> Option<Vec<String>> {
+    self.read_discovered(&self.root.join(to_safe(role)).join("organization.json"), &self.config_hash)
   }
 
-  /// Determines if the cache is valid based on the timeout the user set.
-  pub fn is_cache_valid(&self) -> bool {
-    self.valid_cache
+  /// Writes the roles found through Organizations to the cache, next to the organization account's entries.
+  pub fn write_discovered_roles(&self, role: &str, roles: &[String]) -> Result<(), Error> {
+    self.write_discovered(&self.root.join(to_safe(role)).join("organization.json"), &self.config_hash, roles)
   }
 
-  /// Determines if the Cache has data regardless of whether or not it's valid.
-  pub fn has_cache_data(&self) -> bool {
-    self.potential_json_value.is_some()
+  /// Reads the regions enabled in an account, as long as they're younger than `cache_max_age`, and were looked up
+  /// from the same `discovery_region`.
+  pub fn read_discovered_regions(&self, role: &str) -> Option<Vec<String>> {
+    self.read_discovered(&self.root.join(to_safe(role)).join("regions.json"), &self.discovery_hash)
   }
 
-  /// Grabs the data out of the cache. Consuming the cache as it should no longer be needed.
-  /// The cache will only be read when we need to respond with it.
-  pub fn get_cache_data(self) -> Result<String, Error> {
-    if self.potential_json_value.is_some() {
-      let json_value = self.potential_json_value.unwrap();
-      return Ok(JsonToStr(&json_value)?);
+  /// Writes the regions enabled in an account to the cache, next to the account's entries.
+  pub fn write_discovered_regions(&self, role: &str, regions: &[String]) -> Result<(), Error> {
+    self.write_discovered(&self.root.join(to_safe(role)).join("regions.json"), &self.discovery_hash, regions)
+  }
+
+  fn read_discovered(&self, path: &Path, hash: &str) -> Option<Vec<String>> {
+    let discovered: Discovered = serde_json::from_reader(File::open(path).ok()?).ok()?;
+    let age = Utc::now().timestamp() - discovered.generated_at;
+    if discovered.version != env!("CARGO_PKG_VERSION")
+      || discovered.config_hash != hash
+      || age >= self.max_age as i64
+    {
+      return None;
     }
-    Err(err_msg("No cache data!"))
+    Some(discovered.found)
   }
 
+  fn write_discovered(&self, final_path: &Path, hash: &str, found: &[String]) -> Result<(), Error> {
+    let discovered = Discovered {
+      generated_at: Utc::now().timestamp(),
+      version: env!("CARGO_PKG_VERSION").to_owned(),
+      config_hash: hash.to_owned(),
+      found: found.to_vec(),
+    };
+    if let Some(parent) = final_path.parent() {
+      CreateDirAll(parent)?;
+    }
+    let temp_path = final_path.with_extension(format!("{}.tmp", process::id()));
+    let result = write_and_rename(&temp_path, final_path, &serde_json::to_vec(&discovered)?);
+    if result.is_err() {
+      let _ = RemoveFile(&temp_path);
+    }
+    result
+  }
 
-  /// Writes the new cache data. Consuming the cache as it should no longer be needed.
-  pub fn write_cache_data(self, to_write: &str) -> Result<(), Error> {
-    let mut file = OpenOptions::new().create(true).write(true).open(
-      self.path_to_cache,
-    )?;
-    file.write_all(to_write.as_bytes())?;
-    Ok(())
+  /// Tries to become the one process refreshing the cache, by locking `<cache_path>/ec2-rs/refresh.lock`.
+  /// Returns the locked file, which holds the lock until it's dropped, or `None` if someone else
+  /// is already refreshing it.
+  pub fn try_lock_refresh(&self) -> Result<Option<File>, Error> {
+    CreateDirAll(&self.root)?;
+    let lock_file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .truncate(false)
+      .open(self.root.join("refresh.lock"))?;
+    match lock_file.try_lock_exclusive() {
+      Ok(()) => Ok(Some(lock_file)),
+      Err(ref err) if err.kind() == fs2::lock_contended_error().kind() => Ok(None),
+      Err(err) => Err(err.into()),
+    }
   }
 }
+
+fn write_and_rename(temp_path: &Path, final_path: &Path, to_write: &[u8]) -> Result<(), Error> {
+  let mut file = OpenOptions::new()
+    .create(true)
+    .write(true)
+    .truncate(true)
+    .open(temp_path)?;
+  file.write_all(to_write)?;
+  file.sync_all()?;
+  Rename(temp_path, final_path)?;
+  Ok(())
+}
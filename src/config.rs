@@ -1,23 +1,63 @@
 use failure::Error;
+use rusoto_core::Region;
 use shellexpand::tilde as TildeExpand;
 use serde_derive::{Serialize, Deserialize};
 use toml::from_str as parse_toml_string;
 
 use std::collections::HashMap;
+use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 /// Provides a Configuration Object for EC2-RS.
 /// This is very similar to EC2.py in and of the sense everything is optional.
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Configuration {
   /// The cache path to store our cache files at. Defaults to: `~/.ansible/tmp`.
   cache_path: Option<String>,
   /// The max age of the cache in seconds. Defaults to 300.
   cache_max_age: Option<u64>,
+  /// How old in seconds an expired cache may be while still being served, as it's refreshed in the background.
+  /// Past this we wait on a fresh scan. Defaults to never serving an expired cache.
+  cache_max_stale: Option<u64>,
+  /// How to compress cache files: `none`, `gzip`, or `zstd`. Defaults to `none`.
+  cache_compression: Option<CacheCompression>,
+  /// Whether to nest groups under parent groups like ec2.py (`regions`, `tags`, `types`, etc.). Defaults to false.
+  nested_groups: Option<bool>,
+  /// What to do when an account, or region can't be scanned. Defaults to: `fail_fast`.
+  failure_policy: Option<FailurePolicy>,
+  /// Whether to list the account, and region pairs that failed in `_meta.failures`. Defaults to false.
+  failures_in_meta: Option<bool>,
+  /// Overrides the EC2 endpoint, e.g. `http://localhost:5000` or `https://ec2-fips.{region}.amazonaws.com`.
+  /// `{region}` is replaced with the region being scanned. Defaults to AWS's endpoint for the region.
+  ec2_endpoint: Option<String>,
+  /// Overrides the STS endpoint used to assume roles. Defaults to AWS's global endpoint.
+  sts_endpoint: Option<String>,
+  /// Overrides the Route53 endpoint. Defaults to AWS's global endpoint.
+  route53_endpoint: Option<String>,
+  /// Overrides the RDS endpoint, `{region}` is replaced with the region being scanned.
+  /// Defaults to AWS's endpoint for the region.
+  rds_endpoint: Option<String>,
+  /// Overrides the Organizations endpoint. Defaults to AWS's global endpoint.
+  organizations_endpoint: Option<String>,
   /// The EC2 Configuration options.
   pub ec2: Ec2Configuration,
+  /// The Route53 Configuration options.
+  #[serde(default)]
+  pub route53: Route53Configuration,
+  /// The RDS Configuration options.
+  #[serde(default)]
+  pub rds: RdsConfiguration,
+  /// Where short names for roles come from.
+  #[serde(default)]
+  pub aliases: AliasesConfiguration,
+  /// How to assume particular roles, keyed by the alias, or arn they're passed as.
+  roles: Option<HashMap<String, RoleConfiguration>>,
+  /// How to find accounts through AWS Organizations.
+  #[serde(default)]
+  pub organizations: OrganizationsConfiguration,
 }
 
 impl Configuration {
@@ -32,13 +72,136 @@ impl Configuration {
   pub fn get_cache_max_age(&self) -> u64 {
     self.cache_max_age.clone().unwrap_or(300)
   }
+
+  /// Gets how old an expired cache may be in seconds while it's served, and refreshed in the background.
+  pub fn get_cache_max_stale(&self) -> Option<u64> {
+    self.cache_max_stale
+  }
+
+  /// Gets how cache files should be compressed.
+  pub fn get_cache_compression(&self) -> CacheCompression {
+    self.cache_compression.unwrap_or(CacheCompression::None)
+  }
+
+  /// Gets whether or not groups should be nested under their parent groups.
+  pub fn get_nested_groups(&self) -> bool {
+    self.nested_groups.unwrap_or(false)
+  }
+
+  /// Gets what to do when an account, or region can't be scanned.
+  pub fn get_failure_policy(&self) -> FailurePolicy {
+    self.failure_policy.unwrap_or(FailurePolicy::FailFast)
+  }
+
+  /// Gets whether or not failures should be written into `_meta`.
+  pub fn get_failures_in_meta(&self) -> bool {
+    self.failures_in_meta.unwrap_or(false)
+  }
+
+  /// Gets the region to talk to EC2 in, pointing at `ec2_endpoint` when it's set.
+  pub fn get_ec2_region(&self, region: &str) -> Result<Region, Error> {
+    get_endpoint_region(self.ec2_endpoint.as_ref(), region)
+  }
+
+  /// Gets the region to talk to STS in, pointing at `sts_endpoint` when it's set.
+  pub fn get_sts_region(&self) -> Result<Region, Error> {
+    get_endpoint_region(self.sts_endpoint.as_ref(), "us-east-1")
+  }
+
+  /// Gets the region to talk to Route53 in, pointing at `route53_endpoint` when it's set.
+  pub fn get_route53_region(&self) -> Result<Region, Error> {
+    get_endpoint_region(self.route53_endpoint.as_ref(), "us-east-1")
+  }
+
+  /// Gets the region to talk to RDS in, pointing at `rds_endpoint` when it's set.
+  pub fn get_rds_region(&self, region: &str) -> Result<Region, Error> {
+    get_endpoint_region(self.rds_endpoint.as_ref(), region)
+  }
+
+  /// Gets the region to call Organizations in, pointed at `organizations_endpoint` when it's set.
+  pub fn get_organizations_region(&self) -> Result<Region, Error> {
+    get_endpoint_region(self.organizations_endpoint.as_ref(), "us-east-1")
+  }
+
+  /// Gets how to assume a role, looking it up by the name it was passed as, and then by its arn.
+  /// Roles found through Organizations fall back to `[organizations.assume_role]`.
+  pub fn get_role(&self, name: &str, role_arn: &str) -> RoleConfiguration {
+    self
+      .roles
+      .as_ref()
+      .and_then(|roles| roles.get(name).or_else(|| roles.get(role_arn)))
+      .cloned()
+      .or_else(|| {
+        if self.organizations.is_discovered_role(role_arn) {
+          self.organizations.assume_role.clone()
+        } else {
+          None
+        }
+      })
+      .unwrap_or_default()
+  }
+
+  /// Gets a copy of this configuration with the Route53, and RDS lookups turned off, for building an inventory
+  /// without talking to AWS.
+  pub fn without_route53_and_rds(&self) -> Configuration {
+    let mut config = self.clone();
+    config.route53.enabled = Some(false);
+    config.rds.enabled = Some(false);
+    config
+  }
+}
+
+/// How cache files are compressed.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheCompression {
+  /// Plain JSON.
+  None,
+  /// Gzip, smaller, and widely readable.
+  Gzip,
+  /// Zstandard, smaller, and faster than gzip.
+  Zstd,
+}
+
+/// What to do when scanning an account, or region fails.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+  /// Fail the whole run, printing no inventory at all.
+  FailFast,
+  /// Leave the hosts of the failed account, and region out of the inventory, and log a warning.
+  SkipAndWarn,
+  /// Fill in the hosts of the failed account, and region from the last cached inventory,
+  /// regardless of how old it is. Acts like `skip_and_warn` when there is no cache.
+  UseStaleCacheForFailedRegion,
+}
+
+/// The regions to scan, either listed out, or `"all"`.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum RegionsConfiguration {
+  All(AllRegions),
+  List(Vec<String>),
+}
+
+/// The only word `regions` can be set to instead of a list.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AllRegions {
+  All,
 }
 
 /// Provides all the configuration options for the EC2 scanning of ec2.py
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Ec2Configuration {
-  /// The Regions to scan. Defaults to us-{east,west}-{1,2},eu-{west,central}-1,ap-southeast-{1,2}, ca-central-1.
-  regions: Option<Vec<String>>,
+  /// The Regions to scan, or `"all"` for every region enabled in each account.
+  /// Defaults to us-{east,west}-{1,2},eu-{west,central}-1,ap-southeast-{1,2}, ca-central-1.
+  regions: Option<RegionsConfiguration>,
+  /// Regions to never scan, even when they're in `regions`, or enabled with `regions = "all"`. Defaults to none.
+  exclude_regions: Option<Vec<String>>,
+  /// The region every enabled region is looked up from with `regions = "all"`. Defaults to `us-east-1`, which
+  /// GovCloud, and China accounts need to change to a region of their own partition (e.g. `us-gov-west-1`).
+  discovery_region: Option<String>,
   /// Determines if you want non running instances or not.
   all_instances: Option<bool>,
   /// The destination variable, defaults to: `private_dns_name`.
@@ -51,22 +214,74 @@ pub struct Ec2Configuration {
   include_filter: Option<String>,
   /// An exclude pattern to exclude certain hosts whose variable matches your regex.
   exclude_filter: Option<String>,
+  /// The amount of instances to ask for in each page of DescribeInstances (5 - 1000). Defaults to AWS's choice.
+  max_results: Option<i64>,
+  /// Group hosts by their instance id. Defaults to true.
+  group_by_instance_id: Option<bool>,
+  /// Group hosts by their region. Defaults to true.
+  group_by_region: Option<bool>,
+  /// Group hosts by their availability zone. Defaults to true.
+  group_by_availability_zone: Option<bool>,
+  /// Group hosts by their instance type (`type_*`). Defaults to true.
+  group_by_instance_type: Option<bool>,
+  /// Group hosts by their key pair (`key_*`). Defaults to true.
+  group_by_key_pair: Option<bool>,
+  /// Group hosts by their vpc (`vpc_id_*`). Defaults to true.
+  group_by_vpc_id: Option<bool>,
+  /// Group hosts by their security groups (`security_group_*`). Defaults to true.
+  group_by_security_group: Option<bool>,
+  /// Group hosts by their tags (`tag_*`). Defaults to true.
+  group_by_tag_keys: Option<bool>,
+  /// Group hosts by the comma seperated values of their `Flags` tag (`flag_*`). Defaults to true.
+  group_by_flags: Option<bool>,
+  /// Group hosts by the AMI they were launched from. Defaults to false.
+  group_by_ami_id: Option<bool>,
+  /// Group hosts by their platform (`platform_*`). Defaults to false.
+  group_by_platform: Option<bool>,
+  /// Group hosts by their subnet (`subnet_id_*`). Defaults to false.
+  group_by_subnet_id: Option<bool>,
+  /// Group hosts by their IAM instance profile (`instance_profile_*`). Defaults to false.
+  group_by_iam_instance_profile: Option<bool>,
+  /// Group hosts by the account they were found in (`account_*`). Defaults to false.
+  group_by_aws_account: Option<bool>,
 }
 
 impl Ec2Configuration {
-  /// Get the regions to scan in for EC2.
-  pub fn get_regions(&self) -> Vec<String> {
-    self.regions.clone().unwrap_or(vec![
-      "us-east-1".to_owned(),
-      "us-east-2".to_owned(),
-      "us-west-1".to_owned(),
-      "us-west-2".to_owned(),
-      "eu-west-1".to_owned(),
-      "eu-central-1".to_owned(),
-      "ap-southeast-1".to_owned(),
-      "ap-southeast-2".to_owned(),
-      "ca-central-1".to_owned(),
-    ])
+  /// Get the regions to scan in for EC2, without the excluded ones. `None` when `regions = "all"`, in which case
+  /// each account's regions are found with: `regions::discover_regions`.
+  pub fn get_regions(&self) -> Option<Vec<String>> {
+    let regions = match self.regions {
+      Some(RegionsConfiguration::All(_)) => return None,
+      Some(RegionsConfiguration::List(ref regions)) => regions.clone(),
+      None => vec![
+        "us-east-1".to_owned(),
+        "us-east-2".to_owned(),
+        "us-west-1".to_owned(),
+        "us-west-2".to_owned(),
+        "eu-west-1".to_owned(),
+        "eu-central-1".to_owned(),
+        "ap-southeast-1".to_owned(),
+        "ap-southeast-2".to_owned(),
+        "ca-central-1".to_owned(),
+      ],
+    };
+    Some(self.without_excluded_regions(regions))
+  }
+
+  /// Gets the regions to never scan.
+  pub fn get_exclude_regions(&self) -> Vec<String> {
+    self.exclude_regions.clone().unwrap_or_default()
+  }
+
+  /// Gets the region to look up every enabled region from.
+  pub fn get_discovery_region(&self) -> String {
+    self.discovery_region.clone().unwrap_or_else(|| "us-east-1".to_owned())
+  }
+
+  /// Drops every region in `exclude_regions` from a list of regions.
+  pub fn without_excluded_regions(&self, regions: Vec<String>) -> Vec<String> {
+    let exclude_regions = self.get_exclude_regions();
+    regions.into_iter().filter(|region| !exclude_regions.contains(region)).collect()
   }
 
   /// Gets the destination variable to write.
@@ -102,6 +317,358 @@ impl Ec2Configuration {
   pub fn get_exclude_filter(&self) -> String {
     self.exclude_filter.clone().unwrap_or("^$".to_owned())
   }
+
+  /// Gets the page size to use when describing instances.
+  pub fn get_max_results(&self) -> Option<i64> {
+    self.max_results
+  }
+
+  /// Gets whether or not to group hosts by instance id.
+  pub fn get_group_by_instance_id(&self) -> bool {
+    self.group_by_instance_id.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group hosts by region.
+  pub fn get_group_by_region(&self) -> bool {
+    self.group_by_region.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group hosts by availability zone.
+  pub fn get_group_by_availability_zone(&self) -> bool {
+    self.group_by_availability_zone.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group hosts by instance type.
+  pub fn get_group_by_instance_type(&self) -> bool {
+    self.group_by_instance_type.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group hosts by key pair.
+  pub fn get_group_by_key_pair(&self) -> bool {
+    self.group_by_key_pair.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group hosts by vpc.
+  pub fn get_group_by_vpc_id(&self) -> bool {
+    self.group_by_vpc_id.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group hosts by security group.
+  pub fn get_group_by_security_group(&self) -> bool {
+    self.group_by_security_group.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group hosts by tags.
+  pub fn get_group_by_tag_keys(&self) -> bool {
+    self.group_by_tag_keys.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group hosts by flags.
+  pub fn get_group_by_flags(&self) -> bool {
+    self.group_by_flags.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group hosts by AMI id.
+  pub fn get_group_by_ami_id(&self) -> bool {
+    self.group_by_ami_id.unwrap_or(false)
+  }
+
+  /// Gets whether or not to group hosts by platform.
+  pub fn get_group_by_platform(&self) -> bool {
+    self.group_by_platform.unwrap_or(false)
+  }
+
+  /// Gets whether or not to group hosts by subnet.
+  pub fn get_group_by_subnet_id(&self) -> bool {
+    self.group_by_subnet_id.unwrap_or(false)
+  }
+
+  /// Gets whether or not to group hosts by IAM instance profile.
+  pub fn get_group_by_iam_instance_profile(&self) -> bool {
+    self.group_by_iam_instance_profile.unwrap_or(false)
+  }
+
+  /// Gets whether or not to group hosts by account.
+  pub fn get_group_by_aws_account(&self) -> bool {
+    self.group_by_aws_account.unwrap_or(false)
+  }
+}
+
+/// Provides all the configuration options for looking up Route53 records that point at instances.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Route53Configuration {
+  /// Whether to look up Route53 records at all. Defaults to false.
+  enabled: Option<bool>,
+  /// The hosted zones (by name, or id) to look in. Defaults to every hosted zone in the account.
+  hosted_zones: Option<Vec<String>>,
+  /// Hosted zones (by name, or id) to never look in. Defaults to none.
+  #[serde(alias = "route53_excluded_zones")]
+  excluded_zones: Option<Vec<String>>,
+  /// A regex, when set the first record name (alphabetically) matching it becomes the host's name
+  /// instead of the destination variable. Defaults to not renaming hosts.
+  hostnames: Option<String>,
+}
+
+impl Route53Configuration {
+  /// Gets whether or not Route53 records should be looked up.
+  pub fn get_enabled(&self) -> bool {
+    self.enabled.unwrap_or(false)
+  }
+
+  /// Gets the hosted zones to look in, `None` meaning all of them.
+  pub fn get_hosted_zones(&self) -> Option<Vec<String>> {
+    self.hosted_zones.clone()
+  }
+
+  /// Gets the hosted zones to never look in.
+  pub fn get_excluded_zones(&self) -> Vec<String> {
+    self.excluded_zones.clone().unwrap_or_default()
+  }
+
+  /// Gets the pattern record names must match to be used as a host's name.
+  pub fn get_hostnames(&self) -> Option<String> {
+    self.hostnames.clone()
+  }
+}
+
+/// Provides all the configuration options for the RDS scanning of ec2.py
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct RdsConfiguration {
+  /// Whether to scan for RDS instances at all. Defaults to false.
+  enabled: Option<bool>,
+  /// Determines if you want RDS instances that aren't `available` or not. Defaults to false.
+  all_instances: Option<bool>,
+  /// Whether to add Aurora clusters as hosts, by their cluster endpoint. Defaults to false.
+  include_clusters: Option<bool>,
+  /// Group RDS hosts by their engine (`rds_<engine>`). Defaults to true.
+  group_by_engine: Option<bool>,
+  /// Group RDS hosts by their parameter group (`rds_parameter_group_*`). Defaults to true.
+  group_by_parameter_group: Option<bool>,
+  /// Group RDS instances by their instance class (`type_*`). Defaults to true.
+  group_by_instance_class: Option<bool>,
+  /// Group RDS hosts by their tags (`tag_*`). This is an extra API call per host. Defaults to true.
+  group_by_tag_keys: Option<bool>,
+}
+
+impl RdsConfiguration {
+  /// Gets whether or not RDS should be scanned.
+  pub fn get_enabled(&self) -> bool {
+    self.enabled.unwrap_or(false)
+  }
+
+  /// Gets whether or not you want all RDS instances.
+  pub fn get_all_instances(&self) -> bool {
+    self.all_instances.unwrap_or(false)
+  }
+
+  /// Gets whether or not Aurora clusters should be added as hosts.
+  pub fn get_include_clusters(&self) -> bool {
+    self.include_clusters.unwrap_or(false)
+  }
+
+  /// Gets whether or not to group RDS hosts by engine.
+  pub fn get_group_by_engine(&self) -> bool {
+    self.group_by_engine.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group RDS hosts by parameter group.
+  pub fn get_group_by_parameter_group(&self) -> bool {
+    self.group_by_parameter_group.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group RDS instances by instance class.
+  pub fn get_group_by_instance_class(&self) -> bool {
+    self.group_by_instance_class.unwrap_or(true)
+  }
+
+  /// Gets whether or not to group RDS hosts by tags.
+  pub fn get_group_by_tag_keys(&self) -> bool {
+    self.group_by_tag_keys.unwrap_or(true)
+  }
+}
+
+/// Provides all the configuration options for resolving short names into the roles to assume.
+/// When a name is in more than one place, inline aliases win over alias files, which win over
+/// `~/.aws/config`, which wins over rapture.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct AliasesConfiguration {
+  /// Whether to read rapture's aliases. Defaults to true.
+  rapture: Option<bool>,
+  /// Where rapture keeps its aliases. Defaults to: `~/.rapture/aliases.json`.
+  rapture_path: Option<String>,
+  /// Extra alias files, read in order. Each is a JSON, or TOML (by extension) table of names to either
+  /// a role arn, or a table with a `role_arn`. Defaults to none.
+  files: Option<Vec<String>>,
+  /// Whether profiles in the AWS config file with a `role_arn` can be used by their name. Defaults to true.
+  aws_config: Option<bool>,
+  /// Where the AWS config file is. Defaults to: `$AWS_CONFIG_FILE`, or `~/.aws/config`.
+  aws_config_path: Option<String>,
+  /// Aliases written inline, as names to role arns.
+  roles: Option<HashMap<String, String>>,
+}
+
+impl AliasesConfiguration {
+  /// Gets whether or not to read rapture's aliases.
+  pub fn get_rapture(&self) -> bool {
+    self.rapture.unwrap_or(true)
+  }
+
+  /// Gets where rapture keeps its aliases.
+  pub fn get_rapture_path(&self) -> String {
+    self.rapture_path.clone().unwrap_or_else(|| "~/.rapture/aliases.json".to_owned())
+  }
+
+  /// Gets the extra alias files to read.
+  pub fn get_files(&self) -> Vec<String> {
+    self.files.clone().unwrap_or_default()
+  }
+
+  /// Gets whether or not to read profiles from the AWS config file.
+  pub fn get_aws_config(&self) -> bool {
+    self.aws_config.unwrap_or(true)
+  }
+
+  /// Gets where the AWS config file is.
+  pub fn get_aws_config_path(&self) -> String {
+    self
+      .aws_config_path
+      .clone()
+      .or_else(|| env::var("AWS_CONFIG_FILE").ok())
+      .unwrap_or_else(|| "~/.aws/config".to_owned())
+  }
+
+  /// Gets the inline aliases.
+  pub fn get_roles(&self) -> HashMap<String, String> {
+    self.roles.clone().unwrap_or_default()
+  }
+}
+
+/// Provides all the configuration options for assuming a single role.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct RoleConfiguration {
+  /// The `ExternalId` the role's trust policy requires. Defaults to none.
+  external_id: Option<String>,
+  /// How long the role's credentials last in seconds, within the role's maximum. Defaults to STS's default of an hour.
+  duration: Option<i64>,
+  /// The session name to assume the role with. `{user}`, `{alias}`, `{account_id}`, and `{role}` are replaced with
+  /// the user running ec2-rs, the name the role was passed as, and the account id, and name out of its arn.
+  /// Defaults to: `ec2-rs-{user}`.
+  session_name: Option<String>,
+  /// A JSON policy to scope the session down with. Defaults to none.
+  policy: Option<String>,
+  /// The serial (or arn) of the MFA device the role requires. Defaults to none.
+  mfa_serial: Option<String>,
+  /// The roles (by alias, or arn) to assume in turn before this one, each with the credentials of the one
+  /// before it. The first is assumed with the base credentials (or its `source_profile`), its own `via` isn't
+  /// followed. Defaults to assuming this role directly.
+  via: Option<Vec<String>>,
+}
+
+impl RoleConfiguration {
+  /// Gets the external id to assume the role with.
+  pub fn get_external_id(&self) -> Option<String> {
+    self.external_id.clone()
+  }
+
+  /// Gets how long the role's credentials should last in seconds.
+  pub fn get_duration(&self) -> Option<i64> {
+    self.duration
+  }
+
+  /// Gets the template of the session name.
+  pub fn get_session_name(&self) -> String {
+    self.session_name.clone().unwrap_or_else(|| "ec2-rs-{user}".to_owned())
+  }
+
+  /// Gets the policy to scope the session down with.
+  pub fn get_policy(&self) -> Option<String> {
+    self.policy.clone()
+  }
+
+  /// Gets the MFA device the role requires.
+  pub fn get_mfa_serial(&self) -> Option<String> {
+    self.mfa_serial.clone()
+  }
+
+  /// Gets the roles to assume in turn before this one.
+  pub fn get_via(&self) -> Vec<String> {
+    self.via.clone().unwrap_or_default()
+  }
+}
+
+/// Provides all the configuration options for finding the accounts to scan through AWS Organizations.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct OrganizationsConfiguration {
+  /// The role (by alias, or arn) to list the organization's accounts with. Defaults to `current`.
+  role: Option<String>,
+  /// The role to assume in each account found, `{account_id}` is replaced with the account's id.
+  /// Defaults to: `arn:aws:iam::{account_id}:role/OrganizationAccountAccessRole`.
+  role_arn: Option<String>,
+  /// Only find accounts in these organizational units (by id), or any unit nested under them.
+  /// Defaults to every account in the organization.
+  organizational_units: Option<Vec<String>>,
+  /// Only find accounts with all of these tags. Defaults to not looking at tags.
+  tags: Option<HashMap<String, String>>,
+  /// Account ids to never scan. Defaults to none.
+  exclude_accounts: Option<Vec<String>>,
+  /// How to assume the role in each account found. Defaults to assuming it directly.
+  assume_role: Option<RoleConfiguration>,
+}
+
+impl OrganizationsConfiguration {
+  /// Gets the role to list the organization's accounts with.
+  pub fn get_role(&self) -> String {
+    self.role.clone().unwrap_or_else(|| "current".to_owned())
+  }
+
+  /// Gets the template of the role to assume in each account found.
+  pub fn get_role_arn(&self) -> String {
+    self
+      .role_arn
+      .clone()
+      .unwrap_or_else(|| "arn:aws:iam::{account_id}:role/OrganizationAccountAccessRole".to_owned())
+  }
+
+  /// Gets the organizational units to find accounts in, empty meaning the whole organization.
+  pub fn get_organizational_units(&self) -> Vec<String> {
+    self.organizational_units.clone().unwrap_or_default()
+  }
+
+  /// Gets the tags accounts need to have.
+  pub fn get_tags(&self) -> HashMap<String, String> {
+    self.tags.clone().unwrap_or_default()
+  }
+
+  /// Gets the account ids to never scan.
+  pub fn get_exclude_accounts(&self) -> Vec<String> {
+    self.exclude_accounts.clone().unwrap_or_default()
+  }
+
+  /// Gets whether a role arn is the role that'd be assumed in an account found through Organizations.
+  pub fn is_discovered_role(&self, role_arn: &str) -> bool {
+    let template = self.get_role_arn();
+    let mut parts = template.splitn(2, "{account_id}");
+    let (prefix, suffix) = match (parts.next(), parts.next()) {
+      (Some(prefix), Some(suffix)) => (prefix, suffix),
+      _ => return false,
+    };
+    role_arn.len() == prefix.len() + 12 + suffix.len()
+      && role_arn.starts_with(prefix)
+      && role_arn.ends_with(suffix)
+      && role_arn[prefix.len()..prefix.len() + 12].chars().all(|character| character.is_ascii_digit())
+  }
+}
+
+/// Turns a region name into a rusoto region, using a custom endpoint when one is configured.
+/// Requests are still signed for the region name, so the endpoint needs to accept that region.
+fn get_endpoint_region(endpoint: Option<&String>, region: &str) -> Result<Region, Error> {
+  match endpoint {
+    Some(endpoint) => Ok(Region::Custom {
+      name: region.to_owned(),
+      endpoint: endpoint.replace("{region}", region),
+    }),
+    None => Ok(Region::from_str(region)?),
+  }
 }
 
 /// Parses a Configuration from a specified path.
@@ -1,11 +1,15 @@
 use crate::config::Configuration;
 
+use failure::Error;
 use fnv::FnvHashMap;
 use lazy_static::lazy_static;
 use regex::Regex;
-use rusoto_ec2::{Instance, Tag};
+use rusoto_ec2::{DescribeInstancesRequest, Ec2, Instance, Tag};
+use serde_derive::{Deserialize, Serialize};
 use serde_json::{json, Map as JsonMap, Value as JsonValue};
 
+use std::time::Duration;
+
 macro_rules! get_value_from_struct {
   ($var:expr, $member:ident) => {
     ($var).$member.as_ref()
@@ -63,6 +67,26 @@ lazy_static! {
   static ref SAFE_REGEX: Regex = Regex::new("[^A-Za-z0-9-]").unwrap();
 }
 
+/// Describes every instance matching a request, following `next_token` until AWS runs out of pages.
+pub fn describe_all_instances<E: Ec2>(ec2: &E, request: &DescribeInstancesRequest) -> Result<Vec<Instance>, Error> {
+  let mut request = request.clone();
+  let mut instances = Vec::new();
+
+  loop {
+    let page = ec2.describe_instances(request.clone()).with_timeout(Duration::from_secs(300)).sync()?;
+    for reservation in page.reservations.unwrap_or_default() {
+      instances.extend(reservation.instances.unwrap_or_default());
+    }
+
+    match page.next_token {
+      Some(ref token) if !token.is_empty() => request.next_token = Some(token.to_owned()),
+      _ => break,
+    }
+  }
+
+  Ok(instances)
+}
+
 pub fn instance_should_be_added(config: &Configuration, instance: &mut Instance) -> bool {
   if instance.state.is_none() {
     return false;
@@ -259,3 +283,212 @@ pub fn merge_ec2_results(objects: Vec<JsonValue>) -> FnvHashMap<String, JsonValu
     },
   )
 }
+
+/// Finds the hostvars for a single host in a rendered inventory, as is needed for `--host`.
+/// Hosts are looked up by their destination variable first, and then by their instance id.
+/// Ansible expects an empty object for hosts it doesn't know about.
+pub fn find_host_vars(inventory: &JsonValue, host: &str) -> JsonValue {
+  let hostvars = inventory.get("_meta").and_then(|meta| meta.get("hostvars"));
+  if let Some(hostvars) = hostvars {
+    if let Some(vars) = hostvars.get(host) {
+      return vars.clone();
+    }
+    if let Some(hostvars) = hostvars.as_object() {
+      for vars in hostvars.values() {
+        if vars.get("ec2_id").and_then(|id| id.as_str()) == Some(host) {
+          return vars.clone();
+        }
+      }
+    }
+  }
+
+  json!({})
+}
+
+/// Pairs of (parent, child) groups, for nesting groups in the inventory.
+pub type GroupChildren = Vec<(String, String)>;
+
+/// The groups a single instance belongs to.
+#[derive(Default)]
+pub struct InstanceGroups {
+  /// The groups the instance is placed in directly.
+  pub groups: Vec<String>,
+  /// The groups to nest inside of each other. Only filled in when `nested_groups` is turned on.
+  pub children: GroupChildren,
+}
+
+impl InstanceGroups {
+  /// Places the host directly in a group, nesting that group under a parent when asked to.
+  pub fn push(&mut self, group: String, parent: Option<&str>, nested: bool) {
+    if nested {
+      if let Some(parent) = parent {
+        self.children.push((parent.to_owned(), group.clone()));
+      }
+    }
+    self.groups.push(group);
+  }
+
+  /// Nests one group under another, without placing the host in either.
+  pub fn nest(&mut self, parent: &str, child: &str, nested: bool) {
+    if nested {
+      self.children.push((parent.to_owned(), child.to_owned()));
+    }
+  }
+}
+
+/// Gets all the groups an instance belongs to, based on the `group_by_*` options. With `nested_groups`
+/// turned on the groups are also nested the same way ec2.py nests them, e.g. regions contain availability
+/// zones, and `tags` contains `tag_<key>` which contains `tag_<key>_<value>`. Any Route53 records
+/// pointing at the instance are added as groups as well.
+pub fn get_instance_groups(
+  config: &Configuration,
+  instance: &Instance,
+  account: &str,
+  route53_names: &[String],
+) -> InstanceGroups {
+  let ec2_config = &config.ec2;
+  let nested = config.get_nested_groups();
+  let mut groups = InstanceGroups::default();
+
+  if ec2_config.get_group_by_instance_id() {
+    if let Some(instance_id) = instance.instance_id.clone() {
+      groups.push(instance_id, Some("instances"), nested);
+    }
+  }
+  let region = get_raw_region_of_instance(instance);
+  if ec2_config.get_group_by_region() {
+    if let Some(ref region) = region {
+      groups.push(region.to_owned(), Some("regions"), nested);
+    }
+  }
+  if ec2_config.get_group_by_availability_zone() {
+    if let Some(az) = instance.placement.as_ref().and_then(|placement| placement.availability_zone.clone()) {
+      if let (true, Some(region)) = (ec2_config.get_group_by_region(), region.as_ref()) {
+        groups.nest(region, &az, nested);
+      }
+      groups.push(az, Some("zones"), nested);
+    }
+  }
+  if ec2_config.get_group_by_ami_id() {
+    if let Some(ref image_id) = instance.image_id {
+      groups.push(to_safe(image_id), Some("images"), nested);
+    }
+  }
+  if ec2_config.get_group_by_instance_type() {
+    if let Some(ref itype) = instance.instance_type {
+      groups.push(to_safe(&format!("type_{}", itype)), Some("types"), nested);
+    }
+  }
+  if ec2_config.get_group_by_platform() {
+    let platform = instance.platform.as_deref().unwrap_or("undefined");
+    groups.push(to_safe(&format!("platform_{}", platform)), Some("platforms"), nested);
+  }
+  if ec2_config.get_group_by_key_pair() {
+    if let Some(ref key_pair) = instance.key_name {
+      groups.push(to_safe(&format!("key_{}", key_pair)), Some("keys"), nested);
+    }
+  }
+  if ec2_config.get_group_by_vpc_id() {
+    if let Some(ref vpc_id) = instance.vpc_id {
+      groups.push(to_safe(&format!("vpc_id_{}", vpc_id)), Some("vpcs"), nested);
+    }
+  }
+  if ec2_config.get_group_by_subnet_id() {
+    if let Some(ref subnet_id) = instance.subnet_id {
+      groups.push(to_safe(&format!("subnet_id_{}", subnet_id)), Some("subnets"), nested);
+    }
+  }
+  if ec2_config.get_group_by_iam_instance_profile() {
+    let profile_name = instance
+      .iam_instance_profile
+      .as_ref()
+      .and_then(|profile| profile.arn.as_ref())
+      .and_then(|arn| arn.rsplit('/').next());
+    if let Some(profile_name) = profile_name {
+      groups.push(to_safe(&format!("instance_profile_{}", profile_name)), Some("instance_profiles"), nested);
+    }
+  }
+  if ec2_config.get_group_by_security_group() {
+    for sg_name in get_raw_security_group_names(instance).unwrap_or_default() {
+      groups.push(to_safe(&format!("security_group_{}", sg_name)), Some("security_groups"), nested);
+    }
+  }
+  if ec2_config.get_group_by_aws_account() {
+    groups.push(to_safe(&format!("account_{}", account)), Some("accounts"), nested);
+  }
+  for route53_name in route53_names {
+    groups.push(to_safe(route53_name), Some("route53"), nested);
+  }
+  for tag in instance.tags.iter().flatten() {
+    let (tag_key, tag_value) = match (tag.key.as_ref(), tag.value.as_ref()) {
+      (Some(key), Some(value)) => (key, value),
+      _ => continue,
+    };
+    if tag_key == "Flags" && ec2_config.get_group_by_flags() {
+      for flag in tag_value.split(',') {
+        groups.push(to_safe(&format!("flag_{}", flag)), Some("flags"), nested);
+      }
+    }
+    if ec2_config.get_group_by_tag_keys() {
+      let key_group = to_safe(&format!("tag_{}", tag_key).to_lowercase());
+      let value_group = to_safe(&format!("tag_{}={}", tag_key, tag_value).to_lowercase());
+      groups.nest("tags", &key_group, nested);
+      groups.push(value_group, Some(&key_group), nested);
+    }
+  }
+  groups.groups.push("ec2".to_owned());
+
+  groups
+}
+
+/// Gets the hosts directly inside of a group in a rendered inventory,
+/// whether it's a plain list, or a group with `hosts`, and `children`.
+fn get_group_hosts(group: &JsonValue) -> Option<&Vec<JsonValue>> {
+  group.as_array().or_else(|| group.get("hosts").and_then(|hosts| hosts.as_array()))
+}
+
+/// Nests merged groups under their parents for ansible. Any group that has children is turned
+/// into an object with `hosts`, and `children`. Parents that have no hosts of their own are added.
+pub fn nest_groups<I>(merged: &mut FnvHashMap<String, JsonValue>, children: I)
+where
+  I: IntoIterator<Item = (String, String)>,
+{
+  let mut by_parent: FnvHashMap<String, Vec<String>> = FnvHashMap::default();
+  for (parent, child) in children {
+    let siblings = by_parent.entry(parent).or_default();
+    if !siblings.contains(&child) {
+      siblings.push(child);
+    }
+  }
+
+  for (parent, mut group_children) in by_parent {
+    group_children.sort();
+    let hosts = merged.remove(&parent).and_then(|group| get_group_hosts(&group).cloned());
+    let group = match hosts {
+      Some(hosts) => json!({ "hosts": hosts, "children": group_children }),
+      None => json!({ "children": group_children }),
+    };
+    merged.insert(parent, group);
+  }
+}
+
+/// Everything found in a single account, and region. This is what gets cached, and what
+/// gets merged together into the full inventory.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct RegionInventory {
+  /// The groups of every host, in the shape `merge_ec2_results` expects.
+  pub groups: Vec<JsonValue>,
+  /// The (parent, child) group pairs to nest, when `nested_groups` is turned on.
+  pub children: GroupChildren,
+  /// The hostvars of every host, keyed by destination variable.
+  pub hostvars: FnvHashMap<String, JsonValue>,
+}
+
+impl RegionInventory {
+  /// Adds the hosts of another account, or region to this one.
+  pub fn extend(&mut self, other: RegionInventory) {
+    self.groups.extend(other.groups);
+    self.children.extend(other.children);
+    self.hostvars.extend(other.hostvars);
+  }
+}
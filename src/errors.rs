@@ -1,28 +1,43 @@
-use rusoto_core::{CredentialsError, TlsError};
-use serde_json::Error as SerdeError;
-use std::io as StdIo;
-use toml::de::Error as TomlDeError;
+use failure::Fail;
 
-error_chain!{
+use std::fmt;
 
-  foreign_links  {
-    IoError(StdIo::Error);
-    JsonError(SerdeError);
-    RusotoCredentialsError(CredentialsError);
-    RusotoTlsError(TlsError);
-    TomlDeserializeError(TomlDeError);
-  }
-
-  errors {
-    RootPathError {
-      description("Please don't create configs at the root path.")
-      display("Please don't create configs at the root path.")
-    }
+/// The errors ec2-rs raises itself, as opposed to the ones it passes along from AWS, or the filesystem.
+#[derive(Debug)]
+pub enum Ec2RsError {
+  /// STS answered an AssumeRole, but without any credentials in it.
+  NoCredentials { role_arn: String },
+  /// STS answered a GetSessionToken, but without any credentials in it.
+  NoSessionCredentials { mfa_serial: String },
+  /// STS answered a GetCallerIdentity, but without the account in it.
+  NoAccountId,
+  /// An inventory was built without a configuration.
+  MissingConfiguration,
+  /// An inventory was built without any roles to scan.
+  NoRoles,
+  /// There's no directory to replay a recording from.
+  NoRecording { path: String },
+  /// A recording was replayed, but nothing was recorded to it.
+  EmptyRecording { path: String },
+  /// Credentials were asked for while replaying a recording.
+  Offline,
+}
 
-    NoCacheData {
-      description("There is no data in the cache!")
-      display("There is no data inside this cache!")
+impl fmt::Display for Ec2RsError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Ec2RsError::NoCredentials { role_arn } => write!(f, "AssumeRole for {} returned no credentials!", role_arn),
+      Ec2RsError::NoSessionCredentials { mfa_serial } => {
+        write!(f, "GetSessionToken for {} returned no credentials!", mfa_serial)
+      }
+      Ec2RsError::NoAccountId => write!(f, "GetCallerIdentity returned no account!"),
+      Ec2RsError::MissingConfiguration => write!(f, "An inventory needs a configuration!"),
+      Ec2RsError::NoRoles => write!(f, "An inventory needs at least one role to scan!"),
+      Ec2RsError::NoRecording { path } => write!(f, "There's no recording at: {}", path),
+      Ec2RsError::EmptyRecording { path } => write!(f, "The recording at: {} is empty", path),
+      Ec2RsError::Offline => write!(f, "AWS isn't available while replaying a recording."),
     }
   }
-
 }
+
+impl Fail for Ec2RsError {}
@@ -0,0 +1,110 @@
+use crate::config::Configuration;
+use crate::ec2_utils::describe_all_instances;
+use crate::inventory::{ClientFactory, CredentialsProvider};
+
+use failure::Error;
+use fnv::FnvHashMap;
+use rusoto_ec2::{DescribeInstancesRequest, Instance};
+
+use std::sync::Arc;
+
+/// Where the instances of an account, and region come from.
+pub trait InstanceSource: Send + Sync {
+  /// Describes every instance of an account in a region matching `request`, across every page.
+  fn describe_instances(
+    &self,
+    config: &Configuration,
+    account: &str,
+    region: &str,
+    request: &DescribeInstancesRequest,
+  ) -> Result<Vec<Instance>, Error>;
+}
+
+/// Describes instances through EC2 itself. This is what ec2-rs uses unless told otherwise.
+pub struct RusotoInstanceSource {
+  credentials_provider: Arc<dyn CredentialsProvider>,
+  client_factory: Arc<dyn ClientFactory>,
+}
+
+impl RusotoInstanceSource {
+  pub fn new(credentials_provider: Arc<dyn CredentialsProvider>, client_factory: Arc<dyn ClientFactory>) -> Self {
+    RusotoInstanceSource {
+      credentials_provider,
+      client_factory,
+    }
+  }
+}
+
+impl InstanceSource for RusotoInstanceSource {
+  fn describe_instances(
+    &self,
+    config: &Configuration,
+    account: &str,
+    region: &str,
+    request: &DescribeInstancesRequest,
+  ) -> Result<Vec<Instance>, Error> {
+    let credentials = self.credentials_provider.get_credentials(config, account)?;
+    let ec2 = self.client_factory.ec2_client(config, &credentials, region)?;
+    describe_all_instances(&ec2, request)
+  }
+}
+
+/// Hands back instances held in memory, for tests, and replaying recorded scans. Only the `instance-id`
+/// filter is applied, any other filters are assumed to have been applied when the instances were gathered.
+/// Accounts, and regions without any instances are empty, rather than failing.
+#[derive(Default)]
+pub struct MemoryInstanceSource {
+  /// The instances of each (account, region).
+  instances: FnvHashMap<(String, String), Vec<Instance>>,
+}
+
+impl MemoryInstanceSource {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Sets the instances of an account in a region, replacing any that were there.
+  pub fn insert(&mut self, account: &str, region: &str, instances: Vec<Instance>) {
+    self.instances.insert((account.to_owned(), region.to_owned()), instances);
+  }
+
+  /// Sets the instances of an account in a region, for building up a source in one expression.
+  pub fn with_instances(mut self, account: &str, region: &str, instances: Vec<Instance>) -> Self {
+    self.insert(account, region, instances);
+    self
+  }
+}
+
+impl InstanceSource for MemoryInstanceSource {
+  fn describe_instances(
+    &self,
+    _config: &Configuration,
+    account: &str,
+    region: &str,
+    request: &DescribeInstancesRequest,
+  ) -> Result<Vec<Instance>, Error> {
+    let instance_ids: Option<&Vec<String>> = request
+      .filters
+      .iter()
+      .flatten()
+      .find(|filter| filter.name.as_deref() == Some("instance-id"))
+      .and_then(|filter| filter.values.as_ref());
+
+    let instances = self
+      .instances
+      .get(&(account.to_owned(), region.to_owned()))
+      .map(|instances| instances.as_slice())
+      .unwrap_or_default();
+    Ok(
+      instances
+        .iter()
+        .filter(|instance| match (instance_ids, instance.instance_id.as_ref()) {
+          (Some(instance_ids), Some(instance_id)) => instance_ids.contains(instance_id),
+          (Some(_), None) => false,
+          (None, _) => true,
+        })
+        .cloned()
+        .collect(),
+    )
+  }
+}
@@ -0,0 +1,630 @@
+use crate::config::{Configuration, FailurePolicy};
+use crate::ec2_utils::*;
+use crate::errors::Ec2RsError;
+use crate::instance_source::{InstanceSource, RusotoInstanceSource};
+use crate::provide_shallow_credentials::ProvideShallowCredentials;
+use crate::recording::RecordingInstanceSource;
+use crate::regions::discover_regions;
+use crate::role_assumer::RoleAssumer;
+use crate::rds_utils::scan_rds;
+use crate::route53_utils::list_route53_records;
+
+use failure::{format_err, Error};
+use fnv::FnvHashMap;
+use rayon::prelude::*;
+use regex::Regex;
+use rusoto_core::HttpClient;
+use rusoto_credential::AwsCredentials;
+use rusoto_ec2::{DescribeInstancesRequest, Ec2Client, Filter};
+use rusoto_rds::RdsClient;
+use rusoto_route53::Route53Client;
+use serde_derive::Serialize;
+use serde_json::{json, Value as JsonValue};
+use slog::{error, info, o, warn, Logger};
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Gets the credentials to scan an account with.
+pub trait CredentialsProvider: Send + Sync {
+  /// Gets the credentials for an account, `account` being the role (or alias of a role) that was asked for.
+  fn get_credentials(&self, config: &Configuration, account: &str) -> Result<AwsCredentials, Error>;
+
+  /// Gets the name the hosts of an account are tagged with (`ec2_account_value`, and the `account_*` group).
+  /// Defaults to the account as it was asked for.
+  fn get_account_name(&self, _config: &Configuration, account: &str) -> Result<String, Error> {
+    Ok(account.to_owned())
+  }
+}
+
+/// Lets a single provider (and the credentials it has cached) be shared by several inventories.
+impl<P: CredentialsProvider + ?Sized> CredentialsProvider for Arc<P> {
+  fn get_credentials(&self, config: &Configuration, account: &str) -> Result<AwsCredentials, Error> {
+    (**self).get_credentials(config, account)
+  }
+
+  fn get_account_name(&self, config: &Configuration, account: &str) -> Result<String, Error> {
+    (**self).get_account_name(config, account)
+  }
+}
+
+/// Creates the AWS clients used to scan an account, once its credentials are known.
+pub trait ClientFactory: Send + Sync {
+  /// Creates an EC2 client for a region.
+  fn ec2_client(&self, config: &Configuration, credentials: &AwsCredentials, region: &str) -> Result<Ec2Client, Error>;
+  /// Creates a Route53 client.
+  fn route53_client(&self, config: &Configuration, credentials: &AwsCredentials) -> Result<Route53Client, Error>;
+  /// Creates an RDS client for a region.
+  fn rds_client(&self, config: &Configuration, credentials: &AwsCredentials, region: &str) -> Result<RdsClient, Error>;
+}
+
+/// Creates rusoto clients over HTTP, pointed at the endpoints in the configuration.
+#[derive(Default)]
+pub struct RusotoClientFactory;
+
+impl ClientFactory for RusotoClientFactory {
+  fn ec2_client(&self, config: &Configuration, credentials: &AwsCredentials, region: &str) -> Result<Ec2Client, Error> {
+    Ok(Ec2Client::new_with(HttpClient::new()?, ProvideShallowCredentials::new(credentials.clone()), config.get_ec2_region(region)?))
+  }
+
+  fn route53_client(&self, config: &Configuration, credentials: &AwsCredentials) -> Result<Route53Client, Error> {
+    Ok(Route53Client::new_with(HttpClient::new()?, ProvideShallowCredentials::new(credentials.clone()), config.get_route53_region()?))
+  }
+
+  fn rds_client(&self, config: &Configuration, credentials: &AwsCredentials, region: &str) -> Result<RdsClient, Error> {
+    Ok(RdsClient::new_with(HttpClient::new()?, ProvideShallowCredentials::new(credentials.clone()), config.get_rds_region(region)?))
+  }
+}
+
+/// An account, and region pair that couldn't be scanned.
+#[derive(Clone, Debug, Serialize)]
+pub struct ScanFailure {
+  pub account: String,
+  pub region: String,
+  pub error: String,
+}
+
+impl ScanFailure {
+  pub fn new<E: Display>(account: &str, region: &str, error: E) -> Self {
+    ScanFailure {
+      account: account.to_owned(),
+      region: region.to_owned(),
+      error: error.to_string(),
+    }
+  }
+}
+
+/// The inventory of a single account, and region that was scanned.
+pub struct ScannedRegion {
+  pub account: String,
+  pub region: String,
+  pub inventory: RegionInventory,
+}
+
+/// Everything found while scanning every account, and region.
+pub struct ScanResults {
+  /// Every account, and region that was scanned. When looking up instance ids the hostvars
+  /// of each instance are keyed by instance id, and there are no groups.
+  pub scanned: Vec<ScannedRegion>,
+  /// The account, and region pairs that failed.
+  pub failures: Vec<ScanFailure>,
+}
+
+/// The inventory of a single region. Or why we couldn't scan it.
+type RegionOutcome = Result<ScannedRegion, ScanFailure>;
+
+/// A single host in the inventory.
+#[derive(Clone, Debug, Serialize)]
+pub struct Host {
+  /// The name ansible knows the host by. Its destination variable, Route53 name, or RDS endpoint.
+  pub name: String,
+  /// The role the host was found with.
+  pub account: String,
+  /// The region the host was found in.
+  pub region: String,
+  /// The host's variables, e.g. `ec2_id`, and `ec2_tag_*`.
+  pub vars: JsonValue,
+}
+
+/// A single group in the inventory.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Group {
+  pub name: String,
+  /// The hosts directly inside of the group, sorted by name.
+  pub hosts: Vec<String>,
+  /// The groups nested inside of this one, sorted by name. Only filled in with `nested_groups` turned on.
+  pub children: Vec<String>,
+}
+
+impl ScanResults {
+  /// Gets every host that was found, sorted by name.
+  pub fn hosts(&self) -> Vec<Host> {
+    let mut hosts: Vec<Host> = self
+      .scanned
+      .iter()
+      .flat_map(|scanned| {
+        scanned.inventory.hostvars.iter().map(move |(name, vars)| Host {
+          name: name.to_owned(),
+          account: scanned.account.to_owned(),
+          region: scanned.region.to_owned(),
+          vars: vars.clone(),
+        })
+      })
+      .collect();
+    hosts.sort_by(|left, right| left.name.cmp(&right.name));
+    hosts
+  }
+
+  /// Gets every group across every account, and region, sorted by name.
+  pub fn groups(&self) -> Vec<Group> {
+    fn get_group<'a>(groups: &'a mut BTreeMap<String, Group>, name: &str) -> &'a mut Group {
+      groups.entry(name.to_owned()).or_insert_with(|| Group {
+        name: name.to_owned(),
+        ..Group::default()
+      })
+    }
+
+    let mut groups: BTreeMap<String, Group> = BTreeMap::new();
+    for scanned in &self.scanned {
+      for host_groups in &scanned.inventory.groups {
+        for (name, host) in host_groups.as_object().into_iter().flatten() {
+          if let Some(host) = host.as_str() {
+            let group = get_group(&mut groups, name);
+            if !group.hosts.iter().any(|known| known == host) {
+              group.hosts.push(host.to_owned());
+            }
+          }
+        }
+      }
+      for (parent, child) in &scanned.inventory.children {
+        let group = get_group(&mut groups, parent);
+        if !group.children.contains(child) {
+          group.children.push(child.to_owned());
+        }
+      }
+    }
+
+    groups
+      .into_values()
+      .map(|mut group| {
+        group.hosts.sort();
+        group.children.sort();
+        group
+      })
+      .collect()
+  }
+}
+
+/// Turns failures into a single error listing every one of them.
+pub fn failures_to_error(failures: &[ScanFailure]) -> Error {
+  let summary = failures
+    .iter()
+    .map(|failure| format!("  [{}] {}: {}", failure.account, failure.region, failure.error))
+    .collect::<Vec<String>>()
+    .join("\n");
+  format_err!("Failed to scan {} account/region pair(s):\n{}", failures.len(), summary)
+}
+
+/// Renders the inventories of many accounts, and regions as one ec2.py style inventory.
+pub fn render_inventory<I>(config: &Configuration, inventories: I, failures: &[ScanFailure]) -> JsonValue
+where
+  I: IntoIterator<Item = RegionInventory>,
+{
+  let mut all = RegionInventory::default();
+  for inventory in inventories {
+    all.extend(inventory);
+  }
+
+  let mut meta = FnvHashMap::default();
+  meta.insert("hostvars", json!(all.hostvars));
+  if config.get_failures_in_meta() {
+    meta.insert("failures", json!(failures));
+  }
+  let mut base: FnvHashMap<String, JsonValue> = FnvHashMap::default();
+  base.insert("_meta".to_owned(), json!(meta));
+  let mut merged_groups = merge_ec2_results(all.groups);
+  nest_groups(&mut merged_groups, all.children);
+  base.extend(merged_groups);
+
+  json!(base)
+}
+
+/// Builds an `Inventory`. Only the configuration, and roles are required.
+#[derive(Default)]
+pub struct InventoryBuilder {
+  config: Option<Configuration>,
+  roles: Vec<String>,
+  regions: Option<Vec<String>>,
+  targets: Vec<(String, Vec<String>)>,
+  instance_ids: Option<Vec<String>>,
+  credentials_provider: Option<Box<dyn CredentialsProvider>>,
+  client_factory: Option<Box<dyn ClientFactory>>,
+  instance_source: Option<Box<dyn InstanceSource>>,
+  record_to: Option<PathBuf>,
+  logger: Option<Logger>,
+}
+
+impl InventoryBuilder {
+  /// The configuration to scan with.
+  pub fn config(mut self, config: Configuration) -> Self {
+    self.config = Some(config);
+    self
+  }
+
+  /// The roles (or aliases of roles) to scan, each in every region given to `regions`.
+  pub fn roles<I, S>(mut self, roles: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    self.roles.extend(roles.into_iter().map(Into::into));
+    self
+  }
+
+  /// The regions to scan each role in. Defaults to the regions in the configuration, finding the regions
+  /// enabled in each account when it has `regions = "all"`.
+  pub fn regions<I, S>(mut self, regions: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    self.regions = Some(regions.into_iter().map(Into::into).collect());
+    self
+  }
+
+  /// Scans a role in its own set of regions, on top of any `roles`.
+  pub fn target<S: Into<String>>(mut self, role: S, regions: Vec<String>) -> Self {
+    self.targets.push((role.into(), regions));
+    self
+  }
+
+  /// Only looks up these instances. Their hostvars are keyed by instance id, and they aren't grouped.
+  pub fn instance_ids(mut self, instance_ids: Vec<String>) -> Self {
+    self.instance_ids = Some(instance_ids);
+    self
+  }
+
+  /// Where credentials for each role come from. Defaults to a `RoleAssumer`. Credentials are asked for
+  /// whenever a client is created, so providers that go over the network should cache them.
+  pub fn credentials_provider<P: CredentialsProvider + 'static>(mut self, provider: P) -> Self {
+    self.credentials_provider = Some(Box::new(provider));
+    self
+  }
+
+  /// Creates the AWS clients. Defaults to `RusotoClientFactory`.
+  pub fn client_factory<F: ClientFactory + 'static>(mut self, factory: F) -> Self {
+    self.client_factory = Some(Box::new(factory));
+    self
+  }
+
+  /// Where instances come from. Defaults to a `RusotoInstanceSource` using the credentials provider,
+  /// and client factory.
+  pub fn instance_source<S: InstanceSource + 'static>(mut self, source: S) -> Self {
+    self.instance_source = Some(Box::new(source));
+    self
+  }
+
+  /// Records every list of instances the instance source hands back to a directory, for replaying
+  /// with `recording::load_recording`.
+  pub fn record_to<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+    self.record_to = Some(dir.into());
+    self
+  }
+
+  /// Where to log to. Defaults to nowhere.
+  pub fn logger(mut self, logger: Logger) -> Self {
+    self.logger = Some(logger);
+    self
+  }
+
+  pub fn build(self) -> Result<Inventory, Error> {
+    let config = self.config.ok_or(Ec2RsError::MissingConfiguration)?;
+    let regions = self.regions.or_else(|| config.ec2.get_regions());
+    let mut targets: Vec<(String, Option<Vec<String>>)> = self
+      .roles
+      .into_iter()
+      .map(|role| (role, regions.clone()))
+      .collect();
+    targets.extend(self.targets.into_iter().map(|(role, regions)| (role, Some(regions))));
+    if targets.is_empty() {
+      return Err(Ec2RsError::NoRoles.into());
+    }
+
+    let route53_hostnames = match config.route53.get_hostnames() {
+      Some(pattern) => Some(Regex::new(&pattern)?),
+      None => None,
+    };
+    let include_regex = Regex::new(&config.ec2.get_include_filter())?;
+    let exclude_regex = Regex::new(&config.ec2.get_exclude_filter())?;
+
+    let credentials_provider: Arc<dyn CredentialsProvider> = match self.credentials_provider {
+      Some(credentials_provider) => Arc::from(credentials_provider),
+      None => Arc::new(RoleAssumer::from_config(&config)?),
+    };
+    let client_factory: Arc<dyn ClientFactory> = match self.client_factory {
+      Some(client_factory) => Arc::from(client_factory),
+      None => Arc::new(RusotoClientFactory),
+    };
+    let mut instance_source = self.instance_source.unwrap_or_else(|| {
+      Box::new(RusotoInstanceSource::new(credentials_provider.clone(), client_factory.clone()))
+    });
+    if let Some(dir) = self.record_to {
+      instance_source = Box::new(RecordingInstanceSource::new(instance_source, dir, credentials_provider.clone()));
+    }
+
+    Ok(Inventory {
+      config,
+      targets,
+      instance_ids: self.instance_ids,
+      credentials_provider,
+      client_factory,
+      instance_source,
+      logger: self.logger.unwrap_or_else(|| Logger::root(slog::Discard, o!())),
+      route53_hostnames,
+      include_regex,
+      exclude_regex,
+      scanned: Mutex::new(None),
+    })
+  }
+}
+
+/// Scans a set of accounts, and regions for hosts.
+pub struct Inventory {
+  config: Configuration,
+  /// Each role to scan, with the regions to scan it in. `None` scans every region enabled in the account.
+  targets: Vec<(String, Option<Vec<String>>)>,
+  instance_ids: Option<Vec<String>>,
+  credentials_provider: Arc<dyn CredentialsProvider>,
+  client_factory: Arc<dyn ClientFactory>,
+  instance_source: Box<dyn InstanceSource>,
+  logger: Logger,
+  route53_hostnames: Option<Regex>,
+  include_regex: Regex,
+  exclude_regex: Regex,
+  /// The scan `hosts`, `groups`, and `to_json` share, once one of them has asked for it.
+  scanned: Mutex<Option<Arc<ScanResults>>>,
+}
+
+impl Inventory {
+  pub fn builder() -> InventoryBuilder {
+    InventoryBuilder::default()
+  }
+
+  /// Gets every host, sorted by name. Failures are handled as the `failure_policy` says, except
+  /// `use_stale_cache_for_failed_region` which skips them as there's no cache to use. Every account, and
+  /// region is scanned the first time `hosts`, `groups`, or `to_json` is called, and the others reuse
+  /// that scan. Call `scan` for a fresh one.
+  pub fn hosts(&self) -> Result<Vec<Host>, Error> {
+    Ok(self.scan_checked()?.hosts())
+  }
+
+  /// Gets every group, sorted by name, from the same scan as `hosts`. Failures are handled like `hosts`.
+  pub fn groups(&self) -> Result<Vec<Group>, Error> {
+    Ok(self.scan_checked()?.groups())
+  }
+
+  /// Renders the inventory from the same scan as `hosts` exactly as `ec2-rs --list` would without a cache.
+  pub fn to_json(&self) -> Result<JsonValue, Error> {
+    let results = self.scan_checked()?;
+    let inventories = results.scanned.iter().map(|scanned| scanned.inventory.clone());
+    Ok(render_inventory(&self.config, inventories, &results.failures))
+  }
+
+  /// Gets the shared scan, scanning the first time around.
+  fn scan_checked(&self) -> Result<Arc<ScanResults>, Error> {
+    let results = self
+      .scanned
+      .lock()
+      .expect("Scan lock was poisoned!")
+      .get_or_insert_with(|| Arc::new(self.scan()))
+      .clone();
+    if !results.failures.is_empty() && self.config.get_failure_policy() == FailurePolicy::FailFast {
+      return Err(failures_to_error(&results.failures));
+    }
+    Ok(results)
+  }
+
+  /// Whether a host by this name passes the `include_filter`, and `exclude_filter` regexes.
+  fn is_included(&self, name: &str) -> bool {
+    self.include_regex.is_match(name) && !self.exclude_regex.is_match(name)
+  }
+
+  /// Scans every region of each account. Failures are logged, and returned rather than stopping
+  /// the rest of the scan.
+  pub fn scan(&self) -> ScanResults {
+    let config = &self.config;
+    let logger = &self.logger;
+
+    let mut to_filter: Vec<Filter> = config
+      .ec2
+      .get_instance_filters()
+      .into_iter()
+      .map(|(key, value)| {
+        Filter {
+          name: Some(key.to_owned()),
+          values: Some(value.split(',').map(|val| val.to_owned()).collect()),
+        }
+      })
+      .collect();
+
+    let only_instance_ids = self.instance_ids.is_some();
+    if let Some(ref hosts_split) = self.instance_ids {
+      to_filter.push(Filter {
+        name: Some("instance-id".to_owned()),
+        values: Some(hosts_split.clone()),
+      });
+    }
+    let to_filter = if to_filter.is_empty() {
+      None
+    } else {
+      Some(to_filter)
+    };
+
+    let initial_request = DescribeInstancesRequest {
+      dry_run: Some(false),
+      filters: to_filter.clone(),
+      instance_ids: None,
+      max_results: config.ec2.get_max_results(),
+      next_token: None,
+    };
+
+    let outcomes: Vec<RegionOutcome> = self
+      .targets
+      .par_iter()
+      .map(|(account, regions)| {
+        let regions = match regions {
+          Some(regions) => regions.clone(),
+          None => {
+            info!(logger, "[{}] Finding enabled regions", account);
+            match discover_regions(config, &*self.credentials_provider, &*self.client_factory, account) {
+              Ok(regions) => config.ec2.without_excluded_regions(regions),
+              Err(err) => return vec![Err(ScanFailure::new(account, "regions", err))],
+            }
+          }
+        };
+        let account_name = match self.credentials_provider.get_account_name(config, account) {
+          Ok(account_name) => account_name,
+          Err(err) => return regions.iter().map(|region| Err(ScanFailure::new(account, region, &err))).collect(),
+        };
+
+        // Route53 is global, so records are looked up once per account, and shared across every region.
+        let mut route53_failure = None;
+        let route53_records = if config.route53.get_enabled() && !only_instance_ids {
+          info!(logger, "[{}] Listing Route53 records", account);
+          let records = self
+            .credentials_provider
+            .get_credentials(config, account)
+            .and_then(|creds| self.client_factory.route53_client(config, &creds))
+            .and_then(|route53| list_route53_records(&route53, &config.route53));
+          match records {
+            Ok(records) => Some(records),
+            Err(err) => {
+              route53_failure = Some(ScanFailure::new(account, "route53", err));
+              None
+            }
+          }
+        } else {
+          None
+        };
+
+        let mut outcomes = regions
+          .par_iter()
+          .map(|region| {
+            info!(logger, "[{}] Parsing region: {}", account, region);
+            let instances = match self.instance_source.describe_instances(config, account, region, &initial_request) {
+              Ok(instances) => instances,
+              Err(err) => return vec![Err(ScanFailure::new(account, region, err))],
+            };
+
+            let mut inventory = RegionInventory::default();
+            if only_instance_ids {
+              for instance in instances {
+                if let Some(ref instance_id) = instance.instance_id {
+                  inventory.hostvars.insert(instance_id.to_owned(), format_for_host_output(&instance, &account_name));
+                }
+              }
+              return vec![Ok(ScannedRegion {
+                account: account.to_owned(),
+                region: region.to_owned(),
+                inventory,
+              })];
+            }
+
+            for mut instance in instances {
+              if !instance_should_be_added(config, &mut instance) {
+                continue;
+              }
+
+              let route53_names = route53_records
+                .as_ref()
+                .map(|records| records.get_names_for_instance(&instance))
+                .unwrap_or_default();
+              let route53_hostname = self
+                .route53_hostnames
+                .as_ref()
+                .and_then(|pattern| route53_names.iter().find(|name| pattern.is_match(name)));
+
+              let dest_variable = route53_hostname.or_else(|| get_instance_dest_variable(config, &instance));
+              if dest_variable.is_none() {
+                continue;
+              }
+              let dest_variable = dest_variable.unwrap().to_owned();
+
+              if !self.is_included(&dest_variable) {
+                continue;
+              }
+
+              inventory.hostvars.insert(
+                dest_variable.clone(),
+                format_for_host_output(&instance, &account_name),
+              );
+              let instance_groups = get_instance_groups(config, &instance, &account_name, &route53_names);
+              let mut map = FnvHashMap::with_capacity_and_hasher(instance_groups.groups.len(), Default::default());
+              for group in instance_groups.groups {
+                map.insert(group, json!(&dest_variable));
+              }
+              inventory.children.extend(instance_groups.children);
+
+              inventory.groups.push(json!(map));
+            }
+            // RDS fails on its own under `rds:<region>`, so the EC2 hosts of the region are still kept.
+            let mut outcomes = Vec::new();
+            if config.rds.get_enabled() {
+              info!(logger, "[{}] Parsing RDS in region: {}", account, region);
+              let rds_hosts = self
+                .credentials_provider
+                .get_credentials(config, account)
+                .and_then(|creds| self.client_factory.rds_client(config, &creds, region))
+                .and_then(|rds| scan_rds(config, &rds, &account_name, region, |name| self.is_included(name)));
+              match rds_hosts {
+                Ok(rds_hosts) => {
+                  inventory.hostvars.extend(rds_hosts.hostvars);
+                  inventory.groups.extend(rds_hosts.groups);
+                  inventory.children.extend(rds_hosts.children);
+                }
+                Err(err) => outcomes.push(Err(ScanFailure::new(account, &format!("rds:{}", region), err))),
+              }
+            }
+
+            outcomes.push(Ok(ScannedRegion {
+              account: account.to_owned(),
+              region: region.to_owned(),
+              inventory,
+            }));
+            outcomes
+          })
+          .collect::<Vec<Vec<RegionOutcome>>>()
+          .into_iter()
+          .flatten()
+          .collect::<Vec<RegionOutcome>>();
+
+        if let Some(failure) = route53_failure {
+          outcomes.push(Err(failure));
+        }
+        outcomes
+      })
+      .collect::<Vec<Vec<RegionOutcome>>>()
+      .into_iter()
+      .flatten()
+      .collect();
+
+    let mut scanned = Vec::new();
+    let mut failures = Vec::new();
+    for outcome in outcomes {
+      match outcome {
+        Ok(scanned_region) => scanned.push(scanned_region),
+        Err(failure) => {
+          warn!(logger, "[{}] Failed to scan region: {}: {}", failure.account, failure.region, failure.error);
+          failures.push(failure);
+        }
+      }
+    }
+    if !failures.is_empty() {
+      error!(logger, "Failed to scan {} account/region pair(s)", failures.len());
+    }
+
+    ScanResults { scanned, failures }
+  }
+}
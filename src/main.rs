@@ -1,38 +1,146 @@
 #![recursion_limit="128"]
 
+pub mod aliases;
 pub mod cache;
 pub mod config;
 #[macro_use]
 pub mod ec2_utils;
-
-use concurrent_hashmap::*;
+pub mod errors;
+pub mod instance_source;
+pub mod inventory;
+pub mod organizations;
+pub mod provide_shallow_credentials;
+pub mod rds_utils;
+pub mod recording;
+pub mod regions;
+pub mod role_assumer;
+pub mod route53_utils;
+
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
 use fnv::FnvHashMap;
+use failure::Error;
 use rayon::prelude::*;
-use rusoto_core::{HttpClient, Region};
-use rusoto_credential::AutoRefreshingProvider;
-use rusoto_ec2::{DescribeInstancesRequest, Ec2, Ec2Client, Filter};
-use rusoto_sts::{StsClient, StsAssumeRoleSessionCredentialsProvider};
-use serde_json::{json, Value as JsonValue};
+use serde_json::Value as JsonValue;
 use shellexpand::tilde as TildeExpand;
-use slog::*;
+use slog::{error, info, o, warn, Drain, Logger};
 
 use std::env;
-use std::fs::{File, OpenOptions};
-use std::iter::FromIterator;
+use std::fs::OpenOptions;
 use std::path::Path;
-use std::str::FromStr;
+use std::process;
 use std::sync::Arc;
-use std::time::Duration;
 
-use crate::cache::Cache;
+use crate::cache::{Cache, CacheEntry};
+use crate::config::{Configuration, FailurePolicy};
 use crate::ec2_utils::*;
+use crate::inventory::{failures_to_error, render_inventory, Inventory, RusotoClientFactory, ScanFailure, ScanResults};
+use crate::recording::{load_recording, OfflineCredentials};
+use crate::organizations::{discover_roles, ORGANIZATION};
+use crate::regions::discover_regions;
+use crate::role_assumer::{RoleAssumer, CURRENT_ACCOUNT};
+
+/// What ansible (or a human) has asked us to print.
+enum Mode {
+  /// `--list`: The full inventory, including `_meta.hostvars`.
+  List,
+  /// `--host <name>`: The hostvars of a single host in the inventory.
+  Host(String),
+  /// `EC2_HOSTS`: The hostvars of a list of instance ids, looked up directly in EC2.
+  InstanceIds(Vec<String>),
+  /// `--background-refresh`: Quietly rebuild a stale cache, see: `spawn_background_refresh`.
+  BackgroundRefresh,
+}
 
-// Lib export thinks "main" is dead code, but we need lib export for benchmarks.
-#[allow(dead_code)]
-fn main() {
-  openssl_probe::init_ssl_cert_env_vars();
+fn parse_arguments<'a>() -> ArgMatches<'a> {
+  App::new("ec2-rs")
+    .version(env!("CARGO_PKG_VERSION"))
+    .about("An ansible dynamic inventory for EC2, across many accounts and regions.")
+    .arg(
+      Arg::with_name("list")
+        .long("list")
+        .conflicts_with("host")
+        .help("Prints the full inventory as JSON. This is the default."),
+    )
+    .arg(
+      Arg::with_name("host")
+        .long("host")
+        .takes_value(true)
+        .value_name("HOSTNAME")
+        .help("Prints the variables for a single host, by destination variable or instance id."),
+    )
+    .arg(
+      Arg::with_name("refresh-cache")
+        .long("refresh-cache")
+        .help("Ignores any cached inventory, and re-scans every account and region."),
+    )
+    .arg(
+      Arg::with_name("background-refresh")
+        .long("background-refresh")
+        .hidden(true)
+        .conflicts_with_all(&["list", "host"]),
+    )
+    .arg(
+      Arg::with_name("replay")
+        .long("replay")
+        .takes_value(true)
+        .value_name("DIR")
+        .conflicts_with_all(&["background-refresh", "refresh-cache"])
+        .help("Builds the inventory from a recording made with `record`, instead of AWS. Also set by EC2_RS_REPLAY."),
+    )
+    .subcommand(
+      SubCommand::with_name("cache")
+        .about("Inspects, and manages the cache.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("list").about("Lists every cache entry, with its age, host count, and status."))
+        .subcommand(
+          SubCommand::with_name("purge")
+            .about("Removes the cache entries of a role, or everything.")
+            .arg(Arg::with_name("role").value_name("ROLE").help("The role to remove the entries of."))
+            .arg(
+              Arg::with_name("region")
+                .long("region")
+                .takes_value(true)
+                .value_name("REGION")
+                .requires("role")
+                .help("Only removes the entry of the role in this region."),
+            )
+            .arg(Arg::with_name("all").long("all").help("Removes everything in the cache."))
+            .group(ArgGroup::with_name("target").args(&["role", "all"]).required(true)),
+        )
+        .subcommand(
+          SubCommand::with_name("warm")
+            .about("Scans any roles, and regions without a valid cache entry, without printing the inventory.")
+            .arg(
+              Arg::with_name("roles")
+                .long("roles")
+                .takes_value(true)
+                .value_name("ROLES")
+                .help("The comma separated roles to warm. Defaults to EC2_RS_ASSUME_ROLE."),
+            ),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("record")
+        .about("Scans every role, and region, recording the instances EC2 hands back for replaying with --replay.")
+        .arg(
+          Arg::with_name("dir")
+            .value_name("DIR")
+            .required(true)
+            .help("The directory to record to."),
+        )
+        .arg(
+          Arg::with_name("roles")
+            .long("roles")
+            .takes_value(true)
+            .value_name("ROLES")
+            .help("The comma separated roles to record. Defaults to EC2_RS_ASSUME_ROLE."),
+        ),
+    )
+    .get_matches()
+}
 
-  let logger = if env::var("EC2_RS_LOG_TO_FILE").is_ok() {
+fn setup_logger() -> Logger {
+  if env::var("EC2_RS_LOG_TO_FILE").is_ok() {
     let log_path = "ec2_rs_log.log";
     let file = OpenOptions::new()
       .create(true)
@@ -57,7 +165,16 @@ fn main() {
       drain,
       o!()
     )
-  };
+  }
+}
+
+// Lib export thinks "main" is dead code, but we need lib export for benchmarks.
+#[allow(dead_code)]
+fn main() {
+  openssl_probe::init_ssl_cert_env_vars();
+
+  let matches = parse_arguments();
+  let logger = setup_logger();
 
   let path = env::var("EC2_RS_PATH").unwrap_or(
     env::current_dir()
@@ -73,274 +190,687 @@ fn main() {
   let config = config::parse_configuration(&config_path).expect(
     "Failed to parse config file! Please make sure your config is valid!",
   );
-  let role_to_assume: String = env::var("EC2_RS_ASSUME_ROLE").expect("Assuming a role is needed!");
-
-  let cache = Cache::new(
-    config.get_cache_path(),
-    role_to_assume.clone(),
-    config.get_cache_max_age(),
-  ).expect("Failed to setup cache!");
-
-  if env::var("EC2_RS_FORCE_CACHE").is_ok() {
-    if cache.has_cache_data() {
-      let finalized_data = cache.get_cache_data().expect("Failed to read from cache!");
-      return println!("{}", finalized_data);
-    }
-    panic!("Failed to fetch cache data!");
+
+  if let ("cache", Some(cache_matches)) = matches.subcommand() {
+    run_cache_command(&config, logger, cache_matches);
+    return;
+  }
+  if let ("record", Some(record_matches)) = matches.subcommand() {
+    run_record_command(&config, logger, record_matches);
+    return;
   }
 
-  if cache.is_cache_valid() && cache.has_cache_data() {
-    info!(logger, "Found valid cache!");
-    let finalized_data = cache.get_cache_data().expect("Failed to read from cache!");
-    return println!("{}", finalized_data);
+  // Explicit flags always win, `EC2_HOSTS` is only honoured when ansible hasn't told us what it wants.
+  let mode = if matches.is_present("background-refresh") {
+    Mode::BackgroundRefresh
+  } else if let Some(host) = matches.value_of("host") {
+    Mode::Host(host.to_owned())
+  } else if matches.is_present("list") {
+    Mode::List
+  } else if let Ok(hosts) = env::var("EC2_HOSTS") {
+    Mode::InstanceIds(hosts.split(',').map(|val| val.to_owned()).collect())
+  } else {
+    Mode::List
+  };
+
+  let replay = matches
+    .value_of("replay")
+    .map(|dir| dir.to_owned())
+    .or_else(|| env::var("EC2_RS_REPLAY").ok());
+  if let Some(replay_dir) = replay {
+    run_replay(&config, logger, mode, Path::new(&TildeExpand(&replay_dir).into_owned()));
+    return;
   }
 
-  let mut to_filter: Vec<Filter> = config
-    .ec2
-    .get_instance_filters()
-    .into_iter()
-    .map(|(key, value)| {
-      Filter {
-        name: Some(key.to_owned()),
-        values: Some(
-          value
-            .to_owned()
-            .split(",")
-            .map(|val| val.to_owned())
-            .collect(),
-        ),
+  let role_assumer = match RoleAssumer::from_config(&config) {
+    // Nobody is at the terminal of a background refresh, and reading it would stop the refresh while it holds the
+    // refresh lock.
+    Ok(role_assumer) if matches!(mode, Mode::BackgroundRefresh) => Arc::new(role_assumer.without_mfa_prompts()),
+    Ok(role_assumer) => Arc::new(role_assumer),
+    Err(err) => exit_with_error(logger, err),
+  };
+  let role_to_assume = roles_from_env();
+  let cache_use = if matches.is_present("refresh-cache") {
+    CacheUse::Refresh
+  } else {
+    CacheUse::Normal
+  };
+
+  match mode {
+    Mode::InstanceIds(instance_ids) => {
+      let roles = match expand_roles(&config, &logger, &role_assumer, &role_to_assume, cache_use) {
+        Ok(roles) => roles,
+        Err(err) => exit_with_error(logger, err),
+      };
+      let (targets, region_failures) = match resolve_targets(&config, &logger, &role_assumer, &roles, cache_use) {
+        Ok(resolved) => resolved,
+        Err(err) => exit_with_error(logger, err),
+      };
+      let mut results = match scan_accounts(&config, &logger, &role_assumer, &targets, Some(instance_ids)) {
+        Ok(results) => results,
+        Err(err) => exit_with_error(logger, err),
+      };
+      results.failures.extend(region_failures);
+      if !results.failures.is_empty() && config.get_failure_policy() == FailurePolicy::FailFast {
+        exit_with_error(logger, failures_to_error(&results.failures));
       }
-    })
+      print_instance_hosts(results);
+    }
+    Mode::List => {
+      let inventory = match get_inventory(&config, &logger, &role_assumer, &role_to_assume, cache_use) {
+        Ok((inventory, _)) => inventory,
+        Err(err) => exit_with_error(logger, err),
+      };
+      println!(
+        "{}",
+        serde_json::to_string(&inventory).expect("Failed to render ec2.py output as JSON!")
+      );
+    }
+    Mode::Host(host) => {
+      let inventory = match get_inventory(&config, &logger, &role_assumer, &role_to_assume, cache_use) {
+        Ok((inventory, _)) => inventory,
+        Err(err) => exit_with_error(logger, err),
+      };
+      println!(
+        "{}",
+        serde_json::to_string(&find_host_vars(&inventory, &host)).expect("Failed to render host info as JSON!")
+      );
+    }
+    Mode::BackgroundRefresh => refresh_stale_cache(&config, &logger, &role_assumer, &role_to_assume),
+  }
+}
+
+/// Prints the hostvars of every instance looked up by instance id, as `EC2_HOSTS` expects.
+fn print_instance_hosts(results: ScanResults) {
+  let hosts: Vec<JsonValue> = results
+    .scanned
+    .into_iter()
+    .flat_map(|scanned| scanned.inventory.hostvars.into_values())
     .collect();
+  println!(
+    "{}",
+    serde_json::to_string(&hosts).expect("Failed to render host info as JSON!")
+  );
+}
 
-  if let Ok(hosts) = env::var("EC2_HOSTS") {
-    let hosts_split: Vec<String> = hosts.split(',').map(|val| val.to_owned()).collect();
-    to_filter.push(Filter {
-      name: Some("instance-id".to_owned()),
-      values: Some(hosts_split),
-    });
+/// Runs `ec2-rs record <DIR>`, scanning every role, and region while recording what EC2 hands back.
+fn run_record_command(config: &Configuration, logger: Logger, matches: &ArgMatches) {
+  let roles = match matches.value_of("roles").map(|roles| roles.to_owned()) {
+    Some(roles) => roles,
+    None => roles_from_env(),
+  };
+  let dir = Path::new(matches.value_of("dir").expect("clap requires a directory")).to_owned();
+
+  let inventory = RoleAssumer::from_config(config).map(Arc::new).and_then(|role_assumer| {
+    let roles = expand_roles(config, &logger, &role_assumer, &roles, CacheUse::Normal)?;
+    Inventory::builder()
+      .config(config.clone())
+      .logger(logger.clone())
+      .credentials_provider(role_assumer)
+      .roles(roles)
+      .record_to(dir.clone())
+      .build()
+  });
+  let results = match inventory {
+    Ok(inventory) => inventory.scan(),
+    Err(err) => exit_with_error(logger, err),
+  };
+  if !results.failures.is_empty() && config.get_failure_policy() == FailurePolicy::FailFast {
+    exit_with_error(logger, failures_to_error(&results.failures));
   }
-  let to_filter = if to_filter.len() == 0 {
-    None
-  } else {
-    Some(to_filter)
+
+  let recorded = results.scanned.len();
+  println!("Recorded {} account/region pair(s) to: {}", recorded, dir.display());
+}
+
+/// Prints the inventory built from a recording made by `ec2-rs record`, instead of scanning AWS.
+/// The cache is neither read, nor written.
+fn run_replay(config: &Configuration, logger: Logger, mode: Mode, dir: &Path) {
+  let recording = match load_recording(dir) {
+    Ok(recording) => recording,
+    Err(err) => exit_with_error(logger, err),
   };
+  if config.route53.get_enabled() || config.rds.get_enabled() {
+    warn!(logger, "Route53 records, and RDS instances aren't recorded, leaving them out of the replay.");
+  }
+  let config = &config.without_route53_and_rds();
+
+  let mut builder = Inventory::builder()
+    .config(config.clone())
+    .logger(logger.clone())
+    .credentials_provider(OfflineCredentials::new(recording.account_names))
+    .instance_source(recording.source);
+  for (account, regions) in recording.targets {
+    builder = builder.target(account, regions);
+  }
+  if let Mode::InstanceIds(ref instance_ids) = mode {
+    builder = builder.instance_ids(instance_ids.clone());
+  }
+  let results = match builder.build() {
+    Ok(inventory) => inventory.scan(),
+    Err(err) => exit_with_error(logger, err),
+  };
+  if !results.failures.is_empty() && config.get_failure_policy() == FailurePolicy::FailFast {
+    exit_with_error(logger, failures_to_error(&results.failures));
+  }
+
+  let render = |results: ScanResults| {
+    let inventories = results.scanned.into_iter().map(|scanned| scanned.inventory);
+    render_inventory(config, inventories, &results.failures)
+  };
+  match mode {
+    Mode::InstanceIds(_) => print_instance_hosts(results),
+    Mode::List => println!(
+      "{}",
+      serde_json::to_string(&render(results)).expect("Failed to render ec2.py output as JSON!")
+    ),
+    Mode::Host(host) => println!(
+      "{}",
+      serde_json::to_string(&find_host_vars(&render(results), &host)).expect("Failed to render host info as JSON!")
+    ),
+    Mode::BackgroundRefresh => unreachable!("clap doesn't allow replaying in the background"),
+  }
+}
+
+/// Formats an age in seconds the way a human would say it, e.g. `42s`, `5m`, `3h`, or `2d`.
+fn format_age(seconds: i64) -> String {
+  match seconds {
+    s if s < 60 => format!("{}s", s),
+    s if s < 60 * 60 => format!("{}m", s / 60),
+    s if s < 60 * 60 * 24 => format!("{}h", s / (60 * 60)),
+    s => format!("{}d", s / (60 * 60 * 24)),
+  }
+}
 
-  let initial_request = DescribeInstancesRequest {
-    dry_run: Some(false),
-    filters: to_filter.clone(),
-    instance_ids: None,
-    max_results: None,
-    next_token: None,
+/// Runs `ec2-rs cache <list|purge|warm>`.
+fn run_cache_command(config: &Configuration, logger: Logger, matches: &ArgMatches) {
+  let cache = match Cache::new(config) {
+    Ok(cache) => cache,
+    Err(err) => exit_with_error(logger, err),
   };
 
-  let include_regex = regex::Regex::new(&config.ec2.get_include_filter()).expect("Failed to compile include regex!");
-  let exclude_regex = regex::Regex::new(&config.ec2.get_exclude_filter()).expect("Failed to compile exclude regex!");
+  match matches.subcommand() {
+    ("list", Some(_)) => {
+      let entries = match cache.list_entries() {
+        Ok(entries) => entries,
+        Err(err) => exit_with_error(logger, err),
+      };
+      let role_width = entries.iter().map(|entry| entry.role.len()).max().unwrap_or(0).max(4);
+      let region_width = entries.iter().map(|entry| entry.region.len()).max().unwrap_or(0).max(6);
+      println!(
+        "{:<rw$}  {:<gw$}  {:>5}  {:>6}  STATUS",
+        "ROLE",
+        "REGION",
+        "AGE",
+        "HOSTS",
+        rw = role_width,
+        gw = region_width
+      );
+      for entry in entries {
+        println!(
+          "{:<rw$}  {:<gw$}  {:>5}  {:>6}  {}",
+          entry.role,
+          entry.region,
+          format_age(entry.get_age()),
+          entry.host_count,
+          entry.status.as_str(),
+          rw = role_width,
+          gw = region_width
+        );
+      }
+    }
+    ("purge", Some(purge_matches)) => {
+      if purge_matches.is_present("all") {
+        if let Err(err) = cache.purge_all() {
+          exit_with_error(logger, err);
+        }
+        println!("Purged the cache.");
+      } else {
+        let role = purge_matches.value_of("role").expect("clap requires a role, or --all");
+        let role_assumer = match RoleAssumer::from_config(config) {
+          Ok(role_assumer) => role_assumer,
+          Err(err) => exit_with_error(logger, err),
+        };
+        let account = match role_assumer.resolve_account(config, role) {
+          Ok(account) => account,
+          Err(err) => exit_with_error(logger, err),
+        };
+        match cache.purge_entries(&account, purge_matches.value_of("region")) {
+          Ok(purged) => println!("Purged {} cache entry(s).", purged),
+          Err(err) => exit_with_error(logger, err),
+        }
+      }
+    }
+    ("warm", Some(warm_matches)) => {
+      let roles = match warm_matches.value_of("roles").map(|roles| roles.to_owned()) {
+        Some(roles) => roles,
+        None => roles_from_env(),
+      };
+      let role_assumer = match RoleAssumer::from_config(config) {
+        Ok(role_assumer) => Arc::new(role_assumer),
+        Err(err) => exit_with_error(logger, err),
+      };
+      let report = match get_inventory(config, &logger, &role_assumer, &roles, CacheUse::ValidOnly) {
+        Ok((_, report)) => report,
+        Err(err) => exit_with_error(logger, err),
+      };
+      let mut warm_roles: Vec<&String> = report.warm.iter().map(|(role, _)| role).collect();
+      warm_roles.sort();
+      warm_roles.dedup();
+      let mut warm_regions: Vec<&String> = report.warm.iter().map(|(_, region)| region).collect();
+      warm_regions.sort();
+      warm_regions.dedup();
+      println!(
+        "The cache is warm for {} role(s) in {} region(s).",
+        warm_roles.len(),
+        warm_regions.len()
+      );
+      if !report.failures.is_empty() {
+        println!("Couldn't warm {} account/region pair(s):", report.failures.len());
+        for failure in &report.failures {
+          println!("  [{}] {}: {}", failure.account, failure.region, failure.error);
+        }
+      }
+    }
+    _ => unreachable!("clap requires a cache subcommand"),
+  }
+}
+
+/// Prints an error for ansible to show, and exits. The logger is dropped first so
+/// anything still queued up in the async drain gets written out.
+fn exit_with_error(logger: Logger, err: Error) -> ! {
+  eprintln!("{}", err);
+  drop(logger);
+  process::exit(1)
+}
 
-  let mut listed_roles: Vec<String> = role_to_assume.split(",").map(|val| val.to_owned()).collect();
+/// How much of the cache `get_inventory` may use.
+#[derive(Clone, Copy, PartialEq)]
+enum CacheUse {
+  /// Serve valid entries, (or stale entries while they're refreshed in the background), and scan the rest.
+  Normal,
+  /// Serve valid entries, and scan the rest. Used when refreshing in the background.
+  ValidOnly,
+  /// `--refresh-cache`: Ignore the cache, and scan everything.
+  Refresh,
+}
 
-  let meta_vars = ConcHashMap::<String, JsonValue>::new();
+/// Gets the roles to scan out of `EC2_RS_ASSUME_ROLE`, scanning just the current account when it isn't set.
+fn roles_from_env() -> String {
+  match env::var("EC2_RS_ASSUME_ROLE") {
+    Ok(ref roles) if !roles.is_empty() => roles.to_owned(),
+    _ => CURRENT_ACCOUNT.to_owned(),
+  }
+}
 
-  let mut role_assumption_mapping = FnvHashMap::default();
-  let file_path = TildeExpand("~/.rapture/aliases.json").into_owned();
-  let aliases_path = Path::new(&file_path);
+/// Splits `EC2_RS_ASSUME_ROLE` into its roles, dropping any repeats.
+fn split_roles(role_to_assume: &str) -> Vec<String> {
+  let mut roles: Vec<String> = Vec::new();
+  for role in role_to_assume.split(',') {
+    if !roles.iter().any(|seen| seen == role) {
+      roles.push(role.to_owned());
+    }
+  }
+  roles
+}
 
-  // If the path exists.
-  if aliases_path.exists() {
-    // And we can open the file.
-    if let Ok(fd) = File::open(aliases_path) {
-      // And we can parse it as json.
-      if let Ok(json) = serde_json::from_reader(fd) {
-        // Arbitrary Type-Ascription is not fully supported, so
-        // give it some clue with a expression the compiler will
-        // optimize away anyway.
-        {
-          let _a: &JsonValue = &json;
+/// Splits `EC2_RS_ASSUME_ROLE` into its roles like `split_roles`, replacing `organization` with the role of
+/// every account found through Organizations. The accounts found are cached for `cache_max_age`.
+fn expand_roles(
+  config: &Configuration,
+  logger: &Logger,
+  role_assumer: &RoleAssumer,
+  role_to_assume: &str,
+  cache_use: CacheUse,
+) -> Result<Vec<String>, Error> {
+  let mut roles: Vec<String> = Vec::new();
+  for role in split_roles(role_to_assume) {
+    let expanded = if role == ORGANIZATION {
+      discover_organization(config, logger, role_assumer, cache_use)?
+    } else {
+      vec![role]
+    };
+    for role in expanded {
+      if !roles.contains(&role) {
+        roles.push(role);
+      }
+    }
+  }
+  Ok(roles)
+}
+
+/// Lists the accounts in the organization, unless they were listed within `cache_max_age`.
+fn discover_organization(
+  config: &Configuration,
+  logger: &Logger,
+  role_assumer: &RoleAssumer,
+  cache_use: CacheUse,
+) -> Result<Vec<String>, Error> {
+  let cache = Cache::new(config)?;
+  let account = role_assumer.resolve_account(config, &config.organizations.get_role())?;
+  if cache_use != CacheUse::Refresh {
+    if let Some(roles) = cache.read_discovered_roles(&account) {
+      return Ok(roles);
+    }
+  }
+
+  let roles = discover_roles(config, role_assumer)?;
+  info!(logger, "Found {} account(s) in the organization", roles.len());
+  if let Err(err) = cache.write_discovered_roles(&account, &roles) {
+    warn!(logger, "Failed to cache the accounts in the organization: {}", err);
+  }
+  Ok(roles)
+}
+
+/// A role, with the regions to scan it in.
+type Target = (String, Vec<String>);
+
+/// Pairs each role with the regions to scan it in. With `regions = "all"` the regions enabled in each account
+/// are looked up, (and cached for `cache_max_age`), and an account whose regions can't be looked up is a failure.
+fn resolve_targets(
+  config: &Configuration,
+  logger: &Logger,
+  role_assumer: &RoleAssumer,
+  roles: &[String],
+  cache_use: CacheUse,
+) -> Result<(Vec<Target>, Vec<ScanFailure>), Error> {
+  if let Some(regions) = config.ec2.get_regions() {
+    return Ok((roles.iter().map(|role| (role.to_owned(), regions.clone())).collect(), Vec::new()));
+  }
+
+  let cache = Cache::new(config)?;
+  let outcomes: Vec<Result<Target, ScanFailure>> = roles
+    .par_iter()
+    .map(|role| {
+      let account = role_assumer
+        .resolve_account(config, role)
+        .map_err(|err| ScanFailure::new(role, "regions", err))?;
+      let cached = if cache_use == CacheUse::Refresh {
+        None
+      } else {
+        cache.read_discovered_regions(&account)
+      };
+      let regions = match cached {
+        Some(regions) => regions,
+        None => {
+          info!(logger, "[{}] Finding enabled regions", role);
+          let regions = discover_regions(config, role_assumer, &RusotoClientFactory, role)
+            .map_err(|err| ScanFailure::new(role, "regions", err))?;
+          if let Err(err) = cache.write_discovered_regions(&account, &regions) {
+            warn!(logger, "[{}] Failed to cache the enabled regions: {}", role, err);
+          }
+          regions
         }
-        // And the root item is an object.
-        if let Some(json_obj) = json.as_object() {
-          // For each key.
-          for (key, val) in json_obj.into_iter() {
-            // If we have a string to string mapping...
-            if val.is_string() {
-              // Insert it as a role mapping.
-              role_assumption_mapping.insert(key.to_owned(), val.as_str().unwrap().to_owned());
-            }
+      };
+      Ok((role.to_owned(), config.ec2.without_excluded_regions(regions)))
+    })
+    .collect();
+
+  let mut targets = Vec::new();
+  let mut failures = Vec::new();
+  for outcome in outcomes {
+    match outcome {
+      Ok(target) => targets.push(target),
+      Err(failure) => {
+        warn!(logger, "[{}] Failed to find enabled regions: {}", failure.account, failure.error);
+        failures.push(failure);
+      }
+    }
+  }
+  Ok((targets, failures))
+}
+
+/// Reads the inventories out of cache entries that are about to be served.
+fn load_entries<I>(entries: I) -> Result<Vec<RegionInventory>, Error>
+where
+  I: IntoIterator<Item = CacheEntry>,
+{
+  entries.into_iter().map(CacheEntry::load_inventory).collect()
+}
+
+/// Which account/region pairs `get_inventory` left a valid cache entry for, and which it couldn't scan.
+struct CacheReport {
+  /// Every (role, region) with a valid cache entry, whether it already had one, or was just written.
+  warm: Vec<(String, String)>,
+  /// Every failure, including the `rds:<region>`, and `route53` failures that kept a region out of the cache.
+  failures: Vec<ScanFailure>,
+}
+
+/// Gets the full inventory. Every account, and region is cached on its own, so only those without
+/// a valid cache entry are scanned, and the fresh scans are merged with the cached ones. A region is
+/// only written back to the cache when it, its RDS instances, (and the Route53 records of its account) scanned cleanly.
+/// Along with the inventory a report of what's now in the cache is handed back. With `CacheUse::ValidOnly` only the
+/// cache is brought up to date, no cache entries are loaded, and the inventory is `null`.
+fn get_inventory(
+  config: &Configuration,
+  logger: &Logger,
+  role_assumer: &Arc<RoleAssumer>,
+  role_to_assume: &str,
+  cache_use: CacheUse,
+) -> Result<(JsonValue, CacheReport), Error> {
+  let cache = Cache::new(config)?;
+  let roles = expand_roles(config, logger, role_assumer, role_to_assume, cache_use)?;
+  let (all_targets, region_failures) = resolve_targets(config, logger, role_assumer, &roles, cache_use)?;
+  if !region_failures.is_empty() && config.get_failure_policy() == FailurePolicy::FailFast {
+    return Err(failures_to_error(&region_failures));
+  }
+
+  // Roles are cached by the account behind them, so an alias, and its role share their cache, and `current` is
+  // cached by its account id. A role whose account can't be found is scanned without the cache.
+  let mut cache_keys: FnvHashMap<&String, String> = FnvHashMap::default();
+  for (role, _) in &all_targets {
+    match role_assumer.resolve_account(config, role) {
+      Ok(account) => {
+        cache_keys.insert(role, account);
+      }
+      Err(err) => warn!(logger, "[{}] Failed to find the account to cache under: {}", role, err),
+    }
+  }
+  let mut cached: FnvHashMap<(String, String), CacheEntry> = FnvHashMap::default();
+  for (role, regions) in &all_targets {
+    for region in regions {
+      if let Some(entry) = cache_keys.get(role).and_then(|account| cache.read_entry(account, region)) {
+        cached.insert((role.to_owned(), region.to_owned()), entry);
+      }
+    }
+  }
+  let fully_cached = cached.len() == all_targets.iter().map(|(_, regions)| regions.len()).sum::<usize>();
+  let mut warm: Vec<(String, String)> = if cache_use == CacheUse::Refresh {
+    Vec::new()
+  } else {
+    cached.iter().filter(|(_, entry)| entry.is_valid()).map(|(key, _)| key.clone()).collect()
+  };
+
+  if cache_use == CacheUse::Normal {
+    if env::var("EC2_RS_FORCE_CACHE").is_ok() {
+      if cached.is_empty() {
+        panic!("Failed to fetch cache data!");
+      }
+      let inventory = render_inventory(config, load_entries(cached.into_values())?, &region_failures);
+      return Ok((inventory, CacheReport { warm, failures: region_failures }));
+    }
+
+    if fully_cached && cached.values().all(|entry| entry.is_valid()) {
+      info!(logger, "Found valid cache!");
+      let inventory = render_inventory(config, load_entries(cached.into_values())?, &region_failures);
+      return Ok((inventory, CacheReport { warm, failures: region_failures }));
+    }
+
+    if fully_cached && cached.values().all(|entry| entry.is_valid() || entry.is_servable_while_stale()) {
+      info!(logger, "Serving stale cache while it's refreshed in the background.");
+      match cache.try_lock_refresh() {
+        // Only spawn a refresher when nobody else is refreshing, the refresher takes the lock itself.
+        Ok(Some(lock)) => {
+          drop(lock);
+          if let Err(err) = spawn_background_refresh(role_to_assume) {
+            warn!(logger, "Failed to start refreshing the cache in the background: {}", err);
           }
         }
+        Ok(None) => info!(logger, "Cache is already being refreshed."),
+        Err(err) => warn!(logger, "Failed to check if the cache is being refreshed: {}", err),
       }
+      let inventory = render_inventory(config, load_entries(cached.into_values())?, &region_failures);
+      return Ok((inventory, CacheReport { warm, failures: region_failures }));
     }
   }
 
-  let result: Vec<JsonValue> = listed_roles
-    .par_iter_mut()
-    .map(|account| {
-
-      let sts = StsClient::new(Region::UsEast1);
-      let creds = if role_assumption_mapping.contains_key(account) {
-        Arc::new(AutoRefreshingProvider::new(
-          StsAssumeRoleSessionCredentialsProvider::new(
-            sts,
-            role_assumption_mapping.get(account).unwrap().to_owned(),
-            "default".to_owned(),
-            None, None, None, None
-          )
-        ).expect("Failed to setup refreshing creds provider!"))
-      } else {
-        Arc::new(AutoRefreshingProvider::new(
-          StsAssumeRoleSessionCredentialsProvider::new(
-            sts,
-            account.to_owned(),
-            "default".to_owned(),
-            None, None, None, None
-          )
-        ).expect("Failed to setup refreshing creds provider!"))
-      };
+  let needs_scan = |role: &String, region: &String| {
+    cache_use == CacheUse::Refresh
+      || !cached
+        .get(&(role.to_owned(), region.to_owned()))
+        .map(|entry| entry.is_valid())
+        .unwrap_or(false)
+  };
+  let targets: Vec<(String, Vec<String>)> = all_targets
+    .iter()
+    .map(|(role, regions)| {
+      let stale_regions = regions.iter().filter(|region| needs_scan(role, region)).cloned().collect();
+      (role.to_owned(), stale_regions)
+    })
+    .filter(|(_, stale_regions): &(String, Vec<String>)| !stale_regions.is_empty())
+    .collect();
+  info!(
+    logger,
+    "Scanning {} account/region pair(s), {} cached",
+    targets.iter().map(|(_, stale_regions)| stale_regions.len()).sum::<usize>(),
+    cached.values().filter(|entry| entry.is_valid()).count()
+  );
 
-      config
-        .ec2
-        .get_regions()
-        .par_iter()
-        .map(|region| {
-          info!(logger, "[{}] Parsing region: {}", account, region);
-          let ec2 = Ec2Client::new_with(
-            HttpClient::new().expect("Failed to create HTTP(s) client!"),
-            creds.clone(),
-            Region::from_str(region).expect("Failed to read region"),
-          );
-
-          if env::var("EC2_HOSTS").is_ok() {
-            let mut the_results = Vec::with_capacity(25);
-            if let Ok(described_instances) = ec2.describe_instances(initial_request.clone()).with_timeout(Duration::from_secs(300)).sync() {
-              if let Some(reservations) = described_instances.reservations {
-                for reservation in reservations {
-                  if let Some(instances) = reservation.instances {
-                    for instance in instances {
-                      the_results.push(format_for_host_output(&instance, &account));
-                    }
-                  }
-                }
-              }
-            }
-            the_results
-          } else {
-            let mut the_results = Vec::with_capacity(250);
-            if let Ok(described_instances) = ec2.describe_instances(initial_request.clone()).with_timeout(Duration::from_secs(300)).sync() {
-              if let Some(reservations) = described_instances.reservations {
-                for reservation in reservations {
-                  if let Some(instances) = reservation.instances {
-                    for mut instance in instances {
-                      if !instance_should_be_added(&config, &mut instance) {
-                        continue;
-                      }
-
-                      let dest_variable = get_instance_dest_variable(&config, &instance);
-                      if dest_variable.is_none() {
-                        continue;
-                      }
-                      let dest_variable = dest_variable.unwrap();
-
-                      if !include_regex.is_match(&dest_variable) || exclude_regex.is_match(&dest_variable) {
-                        continue;
-                      }
-
-                      meta_vars.insert(
-                        dest_variable.clone(),
-                        format_for_host_output(&instance, &account),
-                      );
-                      let mut map = FnvHashMap::with_capacity_and_hasher(10, Default::default());
-
-                      if let Some(iinstance_id) = instance.instance_id.clone() {
-                        map.insert(iinstance_id, json!(&dest_variable));
-                      }
-                      if let Some(iregion) = get_raw_region_of_instance(&instance) {
-                        map.insert(iregion, json!(&dest_variable));
-                      }
-                      if let Some(iplacement) = instance.placement.clone() {
-                        if let Some(az) = iplacement.availability_zone {
-                          map.insert(az, json!(&dest_variable));
-                        }
-                      }
-                      if let Some(itype) = instance.instance_type.clone() {
-                        map.insert(to_safe(&format!("type_{}", itype)), json!(&dest_variable));
-                      }
-                      if let Some(key_pair) = instance.key_name.clone() {
-                        map.insert(to_safe(&format!("key_{}", key_pair)), json!(&dest_variable));
-                      }
-                      if let Some(ivpc_id) = instance.vpc_id.clone() {
-                        map.insert(
-                          to_safe(&format!("vpc_id_{}", ivpc_id)),
-                          json!(&dest_variable),
-                        );
-                      }
-                      if let Some(sg_names) = get_raw_security_group_names(&instance) {
-                        for isg in sg_names {
-                          map.insert(
-                            to_safe(&format!("security_group_{}", isg)),
-                            json!(&dest_variable),
-                          );
-                        }
-                      }
-                      instance.tags.as_ref().and_then(|tags| {
-                        for tag in tags {
-                          let tag_key = tag.key.as_ref().unwrap();
-                          if tag_key == "Flags" {
-                            let cloned_value = tag.value.as_ref().unwrap();
-                            for icv in cloned_value.to_owned().split(",") {
-                              map.insert(to_safe(&format!("flag_{}", icv)), json!(&dest_variable));
-                            }
-                          }
-                          let itagkey = to_safe(&format!("tag_{}={}", tag_key, tag.value.as_ref().unwrap())
-                            .to_lowercase());
-                          map.insert(itagkey, json!(&dest_variable));
-                        }
-                        Some(())
-                      });
-                      map.insert("ec2".to_owned(), json!(&dest_variable));
-
-                      the_results.push(json!(map));
-                    }
-                  }
-                }
-              }
-            } else {
-              panic!("Failed to describe instances!");
+  let ScanResults { scanned, mut failures } = scan_accounts(config, logger, role_assumer, &targets, None)?;
+
+  let mut inventories = Vec::new();
+  if !failures.is_empty() {
+    match config.get_failure_policy() {
+      FailurePolicy::FailFast => return Err(failures_to_error(&failures)),
+      // Stale entries are only served, there's nothing to fill in when only the cache is being brought up to date.
+      FailurePolicy::SkipAndWarn => {}
+      FailurePolicy::UseStaleCacheForFailedRegion if cache_use == CacheUse::ValidOnly => {}
+      FailurePolicy::UseStaleCacheForFailedRegion => {
+        // The EC2 hosts of a region are kept when its RDS instances, or its account's Route53 records fail, so only
+        // regions whose EC2 scan failed have anything to fill in.
+        let ec2_failures = failures
+          .iter()
+          .filter(|failure| failure.region != "route53" && !failure.region.starts_with("rds:"));
+        for failure in ec2_failures {
+          match cached.remove(&(failure.account.to_owned(), failure.region.to_owned())) {
+            Some(entry) => {
+              warn!(
+                logger,
+                "[{}] Using {} stale host(s) for region: {}",
+                failure.account,
+                entry.host_count,
+                failure.region
+              );
+              inventories.push(entry.load_inventory()?);
             }
-            the_results
+            None => warn!(
+              logger,
+              "[{}] No cache to fill in failed region: {}, skipping it.", failure.account, failure.region
+            ),
           }
-        })
-        .collect::<Vec<Vec<JsonValue>>>()
-        .into_iter()
-        .fold(Vec::new(), |mut acc, mut values| {
-          acc.append(&mut values);
-          acc
-        })
+        }
+      }
+    }
+  }
 
-    })
-    .collect::<Vec<Vec<JsonValue>>>()
-    .into_iter()
-    .fold(Vec::new(), |mut acc, mut values| {
-      acc.append(&mut values);
-      acc
+  for scanned_region in scanned {
+    let rds_region = format!("rds:{}", scanned_region.region);
+    let clean = !failures.iter().any(|failure| {
+      failure.account == scanned_region.account && (failure.region == "route53" || failure.region == rds_region)
     });
+    if let (true, Some(account)) = (clean, cache_keys.get(&scanned_region.account)) {
+      match cache.write_entry(account, &scanned_region.region, &scanned_region.inventory) {
+        Ok(()) => warm.push((scanned_region.account.clone(), scanned_region.region.clone())),
+        Err(err) => warn!(logger, "Failed to write cache: {}", err),
+      }
+    }
+    cached.remove(&(scanned_region.account, scanned_region.region));
+    inventories.push(scanned_region.inventory);
+  }
+  failures.extend(region_failures);
+  if cache_use == CacheUse::ValidOnly {
+    return Ok((JsonValue::Null, CacheReport { warm, failures }));
+  }
 
-  if env::var("EC2_HOSTS").is_ok() {
-    println!(
-      "{}",
-      serde_json::to_string(&result).expect("Failed to render host info as JSON!")
-    );
-  } else {
-    let hostvars = FnvHashMap::from_iter(meta_vars.iter());
-    let mut meta = FnvHashMap::default();
-    meta.insert("hostvars", json!(hostvars));
-    let mut base: FnvHashMap<String, JsonValue> = FnvHashMap::default();
-    base.insert("_meta".to_owned(), json!(meta));
-    base.extend(merge_ec2_results(result).into_iter());
+  // Whatever is left over was valid, and didn't need scanning.
+  inventories.extend(load_entries(
+    cached
+      .into_values()
+      .filter(|entry| entry.is_valid() && cache_use != CacheUse::Refresh),
+  )?);
+  let inventory = render_inventory(config, inventories, &failures);
+  Ok((inventory, CacheReport { warm, failures }))
+}
+
+/// Re-runs ec2-rs as a detached process that rebuilds the cache, so a stale cache can be handed to
+/// ansible straight away. Its output goes nowhere, it only exists to write the cache.
+fn spawn_background_refresh(role_to_assume: &str) -> Result<(), Error> {
+  let mut command = process::Command::new(env::current_exe()?);
+  command
+    .arg("--background-refresh")
+    .env("EC2_RS_ASSUME_ROLE", role_to_assume)
+    .stdin(process::Stdio::null())
+    .stdout(process::Stdio::null())
+    .stderr(process::Stdio::null());
+  // Keep ansible's Ctrl-C from taking down the refresh along with it.
+  #[cfg(unix)]
+  {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+  }
+  command.spawn()?;
+  Ok(())
+}
+
+/// Rebuilds the expired parts of the cache in the background, unless another process already is.
+fn refresh_stale_cache(config: &Configuration, logger: &Logger, role_assumer: &Arc<RoleAssumer>, role_to_assume: &str) {
+  let cache = match Cache::new(config) {
+    Ok(cache) => cache,
+    Err(err) => {
+      error!(logger, "Failed to setup the cache for refreshing: {}", err);
+      return;
+    }
+  };
+  let _lock = match cache.try_lock_refresh() {
+    Ok(Some(lock)) => lock,
+    Ok(None) => {
+      info!(logger, "Cache is already being refreshed.");
+      return;
+    }
+    Err(err) => {
+      error!(logger, "Failed to lock the cache for refreshing: {}", err);
+      return;
+    }
+  };
 
-    let merged: JsonValue = json!(base);
+  if let Err(err) = get_inventory(config, logger, role_assumer, role_to_assume, CacheUse::ValidOnly) {
+    error!(logger, "Failed to refresh the cache: {}", err);
+  }
+}
+
+/// Scans the given regions of each account through an `Inventory`. When given a list of instance ids
+/// only those instances are looked up, and their hostvars are keyed by instance id.
+fn scan_accounts(
+  config: &Configuration,
+  logger: &Logger,
+  role_assumer: &Arc<RoleAssumer>,
+  targets: &[(String, Vec<String>)],
+  instance_ids: Option<Vec<String>>,
+) -> Result<ScanResults, Error> {
+  if targets.is_empty() {
+    return Ok(ScanResults { scanned: Vec::new(), failures: Vec::new() });
+  }
 
-    let as_string = serde_json::to_string(&merged).expect("Failed to render ec2.py output as JSON!");
-    let _ = cache.write_cache_data(&as_string);
-    println!("{}", as_string);
+  let mut builder = Inventory::builder()
+    .config(config.clone())
+    .logger(logger.clone())
+    .credentials_provider(role_assumer.clone());
+  for (role, regions) in targets {
+    builder = builder.target(role.to_owned(), regions.to_owned());
   }
+  if let Some(instance_ids) = instance_ids {
+    builder = builder.instance_ids(instance_ids);
+  }
+
+  Ok(builder.build()?.scan())
 }
@@ -0,0 +1,239 @@
+use crate::config::Configuration;
+use crate::inventory::CredentialsProvider;
+use crate::provide_shallow_credentials::ProvideShallowCredentials;
+
+use failure::{format_err, Error};
+use futures::Future;
+use rusoto_core::request::{BufferedHttpResponse, HttpDispatchError};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, HttpClient, Region};
+use rusoto_credential::{AwsCredentials, CredentialsError};
+use rusoto_organizations::{
+  Account, ListAccountsForParentRequest, ListAccountsRequest, ListOrganizationalUnitsForParentRequest, Organizations,
+  OrganizationsClient,
+};
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+/// The role name that's replaced with the role of every account found through Organizations.
+pub const ORGANIZATION: &str = "organization";
+
+/// Finds every active account in the organization matching `[organizations]`, handing back the role to
+/// assume in each of them, sorted.
+pub fn discover_roles(config: &Configuration, credentials_provider: &dyn CredentialsProvider) -> Result<Vec<String>, Error> {
+  let org_config = &config.organizations;
+  let credentials = credentials_provider.get_credentials(config, &org_config.get_role())?;
+  let region = config.get_organizations_region()?;
+  let organizations = OrganizationsClient::new_with(
+    HttpClient::new()?,
+    ProvideShallowCredentials::new(credentials.clone()),
+    region.clone(),
+  );
+
+  let organizational_units = org_config.get_organizational_units();
+  let accounts = if organizational_units.is_empty() {
+    list_accounts(&organizations)?
+  } else {
+    let mut accounts = Vec::new();
+    for organizational_unit in organizational_units {
+      accounts.extend(list_accounts_under(&organizations, &organizational_unit)?);
+    }
+    accounts
+  };
+
+  let exclude_accounts = org_config.get_exclude_accounts();
+  let tags = org_config.get_tags();
+  let mut roles = Vec::new();
+  for account in accounts {
+    let account_id = match account.id {
+      Some(ref account_id) => account_id,
+      None => continue,
+    };
+    if account.status.as_deref() != Some("ACTIVE") || exclude_accounts.contains(account_id) {
+      continue;
+    }
+    if !tags.is_empty() {
+      let account_tags = list_account_tags(&credentials, &region, account_id)?;
+      if tags.iter().any(|(key, value)| account_tags.get(key) != Some(value)) {
+        continue;
+      }
+    }
+    roles.push(org_config.get_role_arn().replace("{account_id}", account_id));
+  }
+
+  roles.sort();
+  roles.dedup();
+  Ok(roles)
+}
+
+/// Lists every account in the organization, across every page.
+fn list_accounts(organizations: &OrganizationsClient) -> Result<Vec<Account>, Error> {
+  let mut accounts = Vec::new();
+  let mut next_token = None;
+  loop {
+    let response = organizations
+      .list_accounts(ListAccountsRequest {
+        next_token,
+        ..ListAccountsRequest::default()
+      })
+      .with_timeout(Duration::from_secs(60))
+      .sync()?;
+    accounts.extend(response.accounts.unwrap_or_default());
+    next_token = response.next_token;
+    if next_token.is_none() {
+      return Ok(accounts);
+    }
+  }
+}
+
+/// Lists every account in an organizational unit, and every unit nested under it.
+fn list_accounts_under(organizations: &OrganizationsClient, organizational_unit: &str) -> Result<Vec<Account>, Error> {
+  let mut accounts = Vec::new();
+  let mut to_list = vec![organizational_unit.to_owned()];
+
+  while let Some(parent_id) = to_list.pop() {
+    let mut next_token = None;
+    loop {
+      let response = organizations
+        .list_accounts_for_parent(ListAccountsForParentRequest {
+          parent_id: parent_id.clone(),
+          next_token,
+          ..ListAccountsForParentRequest::default()
+        })
+        .with_timeout(Duration::from_secs(60))
+        .sync()?;
+      accounts.extend(response.accounts.unwrap_or_default());
+      next_token = response.next_token;
+      if next_token.is_none() {
+        break;
+      }
+    }
+
+    let mut next_token = None;
+    loop {
+      let response = organizations
+        .list_organizational_units_for_parent(ListOrganizationalUnitsForParentRequest {
+          parent_id: parent_id.clone(),
+          next_token,
+          ..ListOrganizationalUnitsForParentRequest::default()
+        })
+        .with_timeout(Duration::from_secs(60))
+        .sync()?;
+      to_list.extend(
+        response
+          .organizational_units
+          .unwrap_or_default()
+          .into_iter()
+          .filter_map(|unit| unit.id),
+      );
+      next_token = response.next_token;
+      if next_token.is_none() {
+        break;
+      }
+    }
+  }
+
+  Ok(accounts)
+}
+
+#[derive(Default, Serialize)]
+struct ListTagsForResourceRequest {
+  #[serde(rename = "ResourceId")]
+  resource_id: String,
+  #[serde(rename = "NextToken", skip_serializing_if = "Option::is_none")]
+  next_token: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct ListTagsForResourceResponse {
+  #[serde(rename = "Tags", default)]
+  tags: Vec<OrganizationsTag>,
+  #[serde(rename = "NextToken")]
+  next_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OrganizationsTag {
+  #[serde(rename = "Key")]
+  key: String,
+  #[serde(rename = "Value")]
+  value: String,
+}
+
+/// Why a call rusoto doesn't know about yet failed.
+#[derive(Debug)]
+enum ListTagsForResourceError {
+  Credentials(CredentialsError),
+  HttpDispatch(HttpDispatchError),
+  Response(String),
+}
+
+impl From<CredentialsError> for ListTagsForResourceError {
+  fn from(err: CredentialsError) -> Self {
+    ListTagsForResourceError::Credentials(err)
+  }
+}
+
+impl From<HttpDispatchError> for ListTagsForResourceError {
+  fn from(err: HttpDispatchError) -> Self {
+    ListTagsForResourceError::HttpDispatch(err)
+  }
+}
+
+impl fmt::Display for ListTagsForResourceError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ListTagsForResourceError::Credentials(err) => write!(f, "{}", err),
+      ListTagsForResourceError::HttpDispatch(err) => write!(f, "{}", err),
+      ListTagsForResourceError::Response(body) => write!(f, "ListTagsForResource failed: {}", body),
+    }
+  }
+}
+
+impl std::error::Error for ListTagsForResourceError {}
+
+/// Lists the tags of an account. The version of rusoto we're on predates tagging accounts, so this signs,
+/// and sends the request itself.
+fn list_account_tags(
+  credentials: &AwsCredentials,
+  region: &Region,
+  account_id: &str,
+) -> Result<HashMap<String, String>, Error> {
+  let client = Client::new_with(ProvideShallowCredentials::new(credentials.clone()), HttpClient::new()?);
+  let mut tags = HashMap::new();
+  let mut next_token = None;
+
+  loop {
+    let mut request = SignedRequest::new("POST", "organizations", region, "/");
+    request.set_content_type("application/x-amz-json-1.1".to_owned());
+    request.add_header("x-amz-target", "AWSOrganizationsV20161128.ListTagsForResource");
+    let payload = serde_json::to_vec(&ListTagsForResourceRequest {
+      resource_id: account_id.to_owned(),
+      next_token,
+    })?;
+    request.set_payload(Some(payload));
+
+    let response: ListTagsForResourceResponse = client
+      .sign_and_dispatch(request, |response| {
+        Box::new(response.buffer().from_err().and_then(|response: BufferedHttpResponse| {
+          let body = String::from_utf8_lossy(&response.body).into_owned();
+          if !response.status.is_success() {
+            return Err(ListTagsForResourceError::Response(body));
+          }
+          serde_json::from_str(&body).map_err(|err| ListTagsForResourceError::Response(err.to_string()))
+        }))
+      })
+      .with_timeout(Duration::from_secs(60))
+      .sync()
+      .map_err(|err| format_err!("Failed to list the tags of account: {}: {}", account_id, err))?;
+
+    tags.extend(response.tags.into_iter().map(|tag| (tag.key, tag.value)));
+    next_token = response.next_token;
+    if next_token.is_none() {
+      return Ok(tags);
+    }
+  }
+}
@@ -1,4 +1,5 @@
-use rusoto_core::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
+use futures::future::{ok, FutureResult};
+use rusoto_credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
 
 /// Rusoto accepts only traits, but you cant say "Variable X is a trait"
 /// because it's size isn't known at compile time, so we wrap it in this shallow
@@ -10,12 +11,14 @@ pub struct ProvideShallowCredentials {
 
 impl ProvideShallowCredentials {
   pub fn new(creds: AwsCredentials) -> Self {
-    Self { creds: creds }
+    Self { creds }
   }
 }
 
 impl ProvideAwsCredentials for ProvideShallowCredentials {
-  fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
-    Ok(self.creds.clone())
+  type Future = FutureResult<AwsCredentials, CredentialsError>;
+
+  fn credentials(&self) -> Self::Future {
+    ok(self.creds.clone())
   }
 }
@@ -0,0 +1,308 @@
+use crate::config::Configuration;
+use crate::ec2_utils::{to_safe, GroupChildren, InstanceGroups};
+
+use failure::Error;
+use fnv::FnvHashMap;
+use rusoto_rds::{
+  DBCluster, DBInstance, DescribeDBClustersMessage, DescribeDBInstancesMessage, ListTagsForResourceMessage, Rds, Tag,
+};
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+
+use std::time::Duration;
+
+/// The RDS hosts found in a single account, and region.
+#[derive(Default)]
+pub struct RdsHosts {
+  /// The hostvars of each host, keyed by endpoint.
+  pub hostvars: Vec<(String, JsonValue)>,
+  /// The groups of each host, in the shape `merge_ec2_results` expects.
+  pub groups: Vec<JsonValue>,
+  /// The groups to nest inside of each other, when `nested_groups` is turned on.
+  pub children: GroupChildren,
+}
+
+/// Describes every DB instance in a region, following `marker` until AWS runs out of pages.
+pub fn describe_all_db_instances<R: Rds>(rds: &R) -> Result<Vec<DBInstance>, Error> {
+  let mut request = DescribeDBInstancesMessage::default();
+  let mut instances = Vec::new();
+
+  loop {
+    let page = rds.describe_db_instances(request.clone()).with_timeout(Duration::from_secs(300)).sync()?;
+    instances.extend(page.db_instances.unwrap_or_default());
+
+    match page.marker {
+      Some(ref marker) if !marker.is_empty() => request.marker = Some(marker.to_owned()),
+      _ => break,
+    }
+  }
+
+  Ok(instances)
+}
+
+/// Describes every DB cluster in a region, following `marker` until AWS runs out of pages.
+pub fn describe_all_db_clusters<R: Rds>(rds: &R) -> Result<Vec<DBCluster>, Error> {
+  let mut request = DescribeDBClustersMessage::default();
+  let mut clusters = Vec::new();
+
+  loop {
+    let page = rds.describe_db_clusters(request.clone()).with_timeout(Duration::from_secs(300)).sync()?;
+    clusters.extend(page.db_clusters.unwrap_or_default());
+
+    match page.marker {
+      Some(ref marker) if !marker.is_empty() => request.marker = Some(marker.to_owned()),
+      _ => break,
+    }
+  }
+
+  Ok(clusters)
+}
+
+fn list_tags<R: Rds>(rds: &R, config: &Configuration, arn: Option<&String>) -> Result<Vec<Tag>, Error> {
+  match arn {
+    Some(arn) if config.rds.get_group_by_tag_keys() => {
+      let request = ListTagsForResourceMessage {
+        resource_name: arn.to_owned(),
+        ..Default::default()
+      };
+      let tags = rds.list_tags_for_resource(request).with_timeout(Duration::from_secs(300)).sync()?;
+      Ok(tags.tag_list.unwrap_or_default())
+    }
+    _ => Ok(Vec::new()),
+  }
+}
+
+fn insert_tags(map: &mut JsonMap<String, JsonValue>, tags: &[Tag]) {
+  for tag in tags {
+    if let (Some(key), Some(value)) = (tag.key.as_ref(), tag.value.as_ref()) {
+      map.insert(to_safe(&format!("rds_tag_{}", key)).to_lowercase(), json!(value.to_lowercase()));
+    }
+  }
+}
+
+fn push_tag_groups(groups: &mut InstanceGroups, tags: &[Tag], nested: bool) {
+  for tag in tags {
+    if let (Some(key), Some(value)) = (tag.key.as_ref(), tag.value.as_ref()) {
+      let key_group = to_safe(&format!("tag_{}", key).to_lowercase());
+      groups.nest("tags", &key_group, nested);
+      groups.push(to_safe(&format!("tag_{}={}", key, value).to_lowercase()), Some(&key_group), nested);
+    }
+  }
+}
+
+/// Formats a DB instance for Output of Host, mirroring the `ec2_*` variables of EC2 instances.
+pub fn format_db_instance_for_host_output(instance: &DBInstance, tags: &[Tag], account: &str, region: &str) -> JsonValue {
+  let mut map =
+    json_map! {
+    "rds_account_value" => json!(account),
+    "rds_arn" => get_as_json!(instance, db_instance_arn),
+    "rds_availability_zone" => get_as_json!(instance, availability_zone),
+    "rds_cluster_id" => get_as_json!(instance, db_cluster_identifier),
+    "rds_endpoint" => get_as_json!(instance, endpoint, address),
+    "rds_engine" => get_as_json!(instance, engine),
+    "rds_engine_version" => get_as_json!(instance, engine_version),
+    "rds_id" => get_as_json!(instance, db_instance_identifier),
+    "rds_instance_class" => get_as_json!(instance, db_instance_class),
+    "rds_multi_az" => get_as_json!(instance, multi_az),
+    "rds_parameter_groups" => json!(get_parameter_group_names(instance).join(",")),
+    "rds_port" => get_as_json!(instance, endpoint, port),
+    "rds_region" => json!(region),
+    "rds_security_group_ids" => json!(
+      instance
+        .vpc_security_groups
+        .iter()
+        .flatten()
+        .filter_map(|group| group.vpc_security_group_id.clone())
+        .collect::<Vec<String>>()
+        .join(",")
+    ),
+    "rds_status" => get_as_json!(instance, db_instance_status),
+    "rds_storage_type" => get_as_json!(instance, storage_type),
+    "rds_vpc_id" => get_as_json!(instance, db_subnet_group, vpc_id)
+  };
+  insert_tags(&mut map, tags);
+
+  JsonValue::Object(map)
+}
+
+/// Formats a DB cluster for Output of Host, the cluster is reached through its writer endpoint.
+pub fn format_db_cluster_for_host_output(cluster: &DBCluster, tags: &[Tag], account: &str, region: &str) -> JsonValue {
+  let mut map =
+    json_map! {
+    "rds_account_value" => json!(account),
+    "rds_arn" => get_as_json!(cluster, db_cluster_arn),
+    "rds_cluster_id" => get_as_json!(cluster, db_cluster_identifier),
+    "rds_cluster_members" => json!(
+      cluster
+        .db_cluster_members
+        .iter()
+        .flatten()
+        .filter_map(|member| member.db_instance_identifier.clone())
+        .collect::<Vec<String>>()
+    ),
+    "rds_endpoint" => get_as_json!(cluster, endpoint),
+    "rds_engine" => get_as_json!(cluster, engine),
+    "rds_engine_version" => get_as_json!(cluster, engine_version),
+    "rds_parameter_groups" => get_as_json!(cluster, db_cluster_parameter_group),
+    "rds_port" => get_as_json!(cluster, port),
+    "rds_reader_endpoint" => get_as_json!(cluster, reader_endpoint),
+    "rds_region" => json!(region),
+    "rds_status" => get_as_json!(cluster, status)
+  };
+  insert_tags(&mut map, tags);
+
+  JsonValue::Object(map)
+}
+
+fn get_parameter_group_names(instance: &DBInstance) -> Vec<String> {
+  instance
+    .db_parameter_groups
+    .iter()
+    .flatten()
+    .filter_map(|group| group.db_parameter_group_name.clone())
+    .collect()
+}
+
+/// Gets all the groups a DB instance belongs to. Region, availability zone, instance id, and vpc
+/// groups follow the `group_by_*` options of EC2 so both end up in the same groups.
+pub fn get_db_instance_groups(config: &Configuration, instance: &DBInstance, tags: &[Tag], region: &str) -> InstanceGroups {
+  let nested = config.get_nested_groups();
+  let mut groups = InstanceGroups::default();
+
+  if config.ec2.get_group_by_instance_id() {
+    if let Some(ref identifier) = instance.db_instance_identifier {
+      groups.push(identifier.to_owned(), Some("instances"), nested);
+    }
+  }
+  if config.ec2.get_group_by_region() {
+    groups.push(region.to_owned(), Some("regions"), nested);
+  }
+  if config.ec2.get_group_by_availability_zone() {
+    if let Some(ref az) = instance.availability_zone {
+      if config.ec2.get_group_by_region() {
+        groups.nest(region, az, nested);
+      }
+      groups.push(az.to_owned(), Some("zones"), nested);
+    }
+  }
+  if config.rds.get_group_by_instance_class() {
+    if let Some(ref class) = instance.db_instance_class {
+      groups.push(to_safe(&format!("type_{}", class)), Some("types"), nested);
+    }
+  }
+  if config.ec2.get_group_by_vpc_id() {
+    if let Some(vpc_id) = instance.db_subnet_group.as_ref().and_then(|group| group.vpc_id.as_ref()) {
+      groups.push(to_safe(&format!("vpc_id_{}", vpc_id)), Some("vpcs"), nested);
+    }
+  }
+  if config.rds.get_group_by_engine() {
+    if let Some(ref engine) = instance.engine {
+      groups.push(to_safe(&format!("rds_{}", engine)), Some("rds_engines"), nested);
+    }
+  }
+  if config.rds.get_group_by_parameter_group() {
+    for name in get_parameter_group_names(instance) {
+      groups.push(to_safe(&format!("rds_parameter_group_{}", name)), Some("rds_parameter_groups"), nested);
+    }
+  }
+  if let Some(ref cluster_id) = instance.db_cluster_identifier {
+    groups.push(to_safe(&format!("rds_cluster_{}", cluster_id)), Some("rds_clusters"), nested);
+  }
+  if config.rds.get_group_by_tag_keys() {
+    push_tag_groups(&mut groups, tags, nested);
+  }
+  groups.groups.push("rds".to_owned());
+
+  groups
+}
+
+/// Gets all the groups a DB cluster belongs to.
+pub fn get_db_cluster_groups(config: &Configuration, cluster: &DBCluster, tags: &[Tag], region: &str) -> InstanceGroups {
+  let nested = config.get_nested_groups();
+  let mut groups = InstanceGroups::default();
+
+  if config.ec2.get_group_by_region() {
+    groups.push(region.to_owned(), Some("regions"), nested);
+  }
+  if config.rds.get_group_by_engine() {
+    if let Some(ref engine) = cluster.engine {
+      groups.push(to_safe(&format!("rds_{}", engine)), Some("rds_engines"), nested);
+    }
+  }
+  if config.rds.get_group_by_parameter_group() {
+    if let Some(ref name) = cluster.db_cluster_parameter_group {
+      groups.push(
+        to_safe(&format!("rds_cluster_parameter_group_{}", name)),
+        Some("rds_parameter_groups"),
+        nested,
+      );
+    }
+  }
+  if let Some(ref cluster_id) = cluster.db_cluster_identifier {
+    groups.push(to_safe(&format!("rds_cluster_{}", cluster_id)), Some("rds_clusters"), nested);
+  }
+  if config.rds.get_group_by_tag_keys() {
+    push_tag_groups(&mut groups, tags, nested);
+  }
+  groups.groups.push("rds_cluster_endpoints".to_owned());
+  groups.groups.push("rds".to_owned());
+
+  groups
+}
+
+fn to_group_map(groups: Vec<String>, dest: &str) -> JsonValue {
+  let mut map = FnvHashMap::with_capacity_and_hasher(groups.len(), Default::default());
+  for group in groups {
+    map.insert(group, json!(dest));
+  }
+  json!(map)
+}
+
+/// Scans a single account, and region for RDS instances (and clusters, when asked to). Only hosts whose endpoint
+/// passes `is_included` are kept.
+pub fn scan_rds<R: Rds, F: Fn(&str) -> bool>(
+  config: &Configuration,
+  rds: &R,
+  account: &str,
+  region: &str,
+  is_included: F,
+) -> Result<RdsHosts, Error> {
+  let mut hosts = RdsHosts::default();
+
+  for instance in describe_all_db_instances(rds)? {
+    let available = instance.db_instance_status.as_ref().map(|status| status == "available").unwrap_or(false);
+    if !available && !config.rds.get_all_instances() {
+      continue;
+    }
+    let dest = match instance.endpoint.as_ref().and_then(|endpoint| endpoint.address.clone()) {
+      Some(ref dest) if is_included(dest) => dest.to_owned(),
+      _ => continue,
+    };
+
+    let tags = list_tags(rds, config, instance.db_instance_arn.as_ref())?;
+    let instance_groups = get_db_instance_groups(config, &instance, &tags, region);
+    hosts.hostvars.push((dest.clone(), format_db_instance_for_host_output(&instance, &tags, account, region)));
+    hosts.groups.push(to_group_map(instance_groups.groups, &dest));
+    hosts.children.extend(instance_groups.children);
+  }
+
+  if config.rds.get_include_clusters() {
+    for cluster in describe_all_db_clusters(rds)? {
+      let available = cluster.status.as_ref().map(|status| status == "available").unwrap_or(false);
+      if !available && !config.rds.get_all_instances() {
+        continue;
+      }
+      let dest = match cluster.endpoint.clone() {
+        Some(ref dest) if is_included(dest) => dest.to_owned(),
+        _ => continue,
+      };
+
+      let tags = list_tags(rds, config, cluster.db_cluster_arn.as_ref())?;
+      let cluster_groups = get_db_cluster_groups(config, &cluster, &tags, region);
+      hosts.hostvars.push((dest.clone(), format_db_cluster_for_host_output(&cluster, &tags, account, region)));
+      hosts.groups.push(to_group_map(cluster_groups.groups, &dest));
+      hosts.children.extend(cluster_groups.children);
+    }
+  }
+
+  Ok(hosts)
+}
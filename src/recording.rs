@@ -0,0 +1,283 @@
+use crate::config::Configuration;
+use crate::ec2_utils::to_safe;
+use crate::errors::Ec2RsError;
+use crate::instance_source::{InstanceSource, MemoryInstanceSource};
+use crate::inventory::CredentialsProvider;
+
+use chrono::prelude::*;
+use failure::{format_err, Error};
+use fnv::FnvHashMap;
+use rusoto_credential::AwsCredentials;
+use rusoto_ec2::{DescribeInstancesRequest, GroupIdentifier, IamInstanceProfile, Instance, InstanceState, Placement, Tag};
+use serde_derive::{Deserialize, Serialize};
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// rusoto's `Instance` can't be serialized, so this mirrors every field of it that ec2-rs reads.
+/// Fields that are missing from a recording are left empty, so recordings can be written by hand.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct RecordedInstance {
+  pub architecture: Option<String>,
+  pub hypervisor: Option<String>,
+  pub iam_instance_profile_arn: Option<String>,
+  pub image_id: Option<String>,
+  pub instance_id: Option<String>,
+  pub instance_type: Option<String>,
+  pub key_name: Option<String>,
+  pub availability_zone: Option<String>,
+  pub platform: Option<String>,
+  pub private_dns_name: Option<String>,
+  pub private_ip_address: Option<String>,
+  pub public_dns_name: Option<String>,
+  pub public_ip_address: Option<String>,
+  pub root_device_name: Option<String>,
+  pub root_device_type: Option<String>,
+  /// Each security group, as (id, name).
+  pub security_groups: Option<Vec<(Option<String>, Option<String>)>>,
+  pub state_code: Option<i64>,
+  pub state_name: Option<String>,
+  pub subnet_id: Option<String>,
+  /// Each tag, as (key, value).
+  pub tags: Option<Vec<(Option<String>, Option<String>)>>,
+  pub virtualization_type: Option<String>,
+  pub vpc_id: Option<String>,
+}
+
+impl<'a> From<&'a Instance> for RecordedInstance {
+  fn from(instance: &'a Instance) -> Self {
+    RecordedInstance {
+      architecture: instance.architecture.clone(),
+      hypervisor: instance.hypervisor.clone(),
+      iam_instance_profile_arn: instance.iam_instance_profile.as_ref().and_then(|profile| profile.arn.clone()),
+      image_id: instance.image_id.clone(),
+      instance_id: instance.instance_id.clone(),
+      instance_type: instance.instance_type.clone(),
+      key_name: instance.key_name.clone(),
+      availability_zone: instance.placement.as_ref().and_then(|placement| placement.availability_zone.clone()),
+      platform: instance.platform.clone(),
+      private_dns_name: instance.private_dns_name.clone(),
+      private_ip_address: instance.private_ip_address.clone(),
+      public_dns_name: instance.public_dns_name.clone(),
+      public_ip_address: instance.public_ip_address.clone(),
+      root_device_name: instance.root_device_name.clone(),
+      root_device_type: instance.root_device_type.clone(),
+      security_groups: instance.security_groups.as_ref().map(|groups| {
+        groups
+          .iter()
+          .map(|group| (group.group_id.clone(), group.group_name.clone()))
+          .collect()
+      }),
+      state_code: instance.state.as_ref().and_then(|state| state.code),
+      state_name: instance.state.as_ref().and_then(|state| state.name.clone()),
+      subnet_id: instance.subnet_id.clone(),
+      tags: instance
+        .tags
+        .as_ref()
+        .map(|tags| tags.iter().map(|tag| (tag.key.clone(), tag.value.clone())).collect()),
+      virtualization_type: instance.virtualization_type.clone(),
+      vpc_id: instance.vpc_id.clone(),
+    }
+  }
+}
+
+impl From<RecordedInstance> for Instance {
+  fn from(recorded: RecordedInstance) -> Self {
+    let state = if recorded.state_code.is_some() || recorded.state_name.is_some() {
+      Some(InstanceState {
+        code: recorded.state_code,
+        name: recorded.state_name,
+      })
+    } else {
+      None
+    };
+
+    Instance {
+      architecture: recorded.architecture,
+      hypervisor: recorded.hypervisor,
+      iam_instance_profile: recorded.iam_instance_profile_arn.map(|arn| IamInstanceProfile {
+        arn: Some(arn),
+        id: None,
+      }),
+      image_id: recorded.image_id,
+      instance_id: recorded.instance_id,
+      instance_type: recorded.instance_type,
+      key_name: recorded.key_name,
+      placement: recorded.availability_zone.map(|availability_zone| Placement {
+        availability_zone: Some(availability_zone),
+        ..Placement::default()
+      }),
+      platform: recorded.platform,
+      private_dns_name: recorded.private_dns_name,
+      private_ip_address: recorded.private_ip_address,
+      public_dns_name: recorded.public_dns_name,
+      public_ip_address: recorded.public_ip_address,
+      root_device_name: recorded.root_device_name,
+      root_device_type: recorded.root_device_type,
+      security_groups: recorded.security_groups.map(|groups| {
+        groups
+          .into_iter()
+          .map(|(group_id, group_name)| GroupIdentifier { group_id, group_name })
+          .collect()
+      }),
+      state,
+      subnet_id: recorded.subnet_id,
+      tags: recorded
+        .tags
+        .map(|tags| tags.into_iter().map(|(key, value)| Tag { key, value }).collect()),
+      virtualization_type: recorded.virtualization_type,
+      vpc_id: recorded.vpc_id,
+      ..Instance::default()
+    }
+  }
+}
+
+/// Every instance DescribeInstances handed back for an account in a region.
+#[derive(Deserialize, Serialize)]
+pub struct RecordedRegion {
+  pub account: String,
+  /// The name the hosts of the account were tagged with, like the account id of `current`. Defaults to `account`,
+  /// so hand written recordings can leave it out.
+  #[serde(default)]
+  pub account_name: Option<String>,
+  pub region: String,
+  /// When the instances were described, as a unix timestamp.
+  pub recorded_at: i64,
+  pub instances: Vec<RecordedInstance>,
+}
+
+/// Gets where the instances of an account, and region are recorded inside of a recording.
+fn recorded_region_path(dir: &Path, account: &str, region: &str) -> PathBuf {
+  dir.join(to_safe(account)).join(format!("{}.json", to_safe(region)))
+}
+
+/// Passes through to another source, recording every list of instances it hands back to a directory
+/// as `<dir>/<account>/<region>.json`. Each account is recorded along with the name the credentials
+/// provider gives it, so a replay names its hosts the same way.
+pub struct RecordingInstanceSource {
+  inner: Box<dyn InstanceSource>,
+  dir: PathBuf,
+  credentials_provider: Arc<dyn CredentialsProvider>,
+}
+
+impl RecordingInstanceSource {
+  pub fn new(inner: Box<dyn InstanceSource>, dir: PathBuf, credentials_provider: Arc<dyn CredentialsProvider>) -> Self {
+    RecordingInstanceSource {
+      inner,
+      dir,
+      credentials_provider,
+    }
+  }
+}
+
+impl InstanceSource for RecordingInstanceSource {
+  fn describe_instances(
+    &self,
+    config: &Configuration,
+    account: &str,
+    region: &str,
+    request: &DescribeInstancesRequest,
+  ) -> Result<Vec<Instance>, Error> {
+    let instances = self.inner.describe_instances(config, account, region, request)?;
+    let account_name = self.credentials_provider.get_account_name(config, account)?;
+
+    let path = recorded_region_path(&self.dir, account, region);
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let recorded = RecordedRegion {
+      account: account.to_owned(),
+      account_name: Some(account_name),
+      region: region.to_owned(),
+      recorded_at: Utc::now().timestamp(),
+      instances: instances.iter().map(RecordedInstance::from).collect(),
+    };
+    serde_json::to_writer_pretty(File::create(&path)?, &recorded)?;
+
+    Ok(instances)
+  }
+}
+
+/// A recording loaded back up for replaying.
+pub struct Recording {
+  /// Hands back the recorded instances.
+  pub source: MemoryInstanceSource,
+  /// Every recorded account, with the regions recorded for it. Both are sorted.
+  pub targets: Vec<(String, Vec<String>)>,
+  /// The name each recorded account's hosts were tagged with, for the accounts that recorded one.
+  pub account_names: FnvHashMap<String, String>,
+}
+
+/// Loads every account, and region recorded to a directory by a `RecordingInstanceSource`.
+pub fn load_recording(dir: &Path) -> Result<Recording, Error> {
+  if !dir.is_dir() {
+    return Err(Ec2RsError::NoRecording { path: dir.display().to_string() }.into());
+  }
+
+  let mut source = MemoryInstanceSource::new();
+  let mut targets: Vec<(String, Vec<String>)> = Vec::new();
+  let mut account_names = FnvHashMap::default();
+  for account_dir in fs::read_dir(dir)? {
+    let account_dir = account_dir?.path();
+    if !account_dir.is_dir() {
+      continue;
+    }
+    for file in fs::read_dir(&account_dir)? {
+      let file = file?.path();
+      if file.extension().and_then(|extension| extension.to_str()) != Some("json") {
+        continue;
+      }
+      let recorded: RecordedRegion = serde_json::from_reader(File::open(&file)?)
+        .map_err(|err| format_err!("Failed to read recording: {}: {}", file.display(), err))?;
+
+      match targets.iter_mut().find(|(account, _)| account == &recorded.account) {
+        Some((_, regions)) => regions.push(recorded.region.clone()),
+        None => targets.push((recorded.account.clone(), vec![recorded.region.clone()])),
+      }
+      if let Some(account_name) = recorded.account_name {
+        account_names.insert(recorded.account.clone(), account_name);
+      }
+      let instances = recorded.instances.into_iter().map(Instance::from).collect();
+      source.insert(&recorded.account, &recorded.region, instances);
+    }
+  }
+  if targets.is_empty() {
+    return Err(Ec2RsError::EmptyRecording { path: dir.display().to_string() }.into());
+  }
+
+  targets.sort();
+  for (_, regions) in targets.iter_mut() {
+    regions.sort();
+  }
+  Ok(Recording {
+    source,
+    targets,
+    account_names,
+  })
+}
+
+/// Refuses to hand out credentials, so replaying a recording never reaches out to AWS. Anything that
+/// isn't recorded (Route53, and RDS) fails, and is handled by the `failure_policy`. Accounts are named
+/// as they were recorded.
+#[derive(Default)]
+pub struct OfflineCredentials {
+  account_names: FnvHashMap<String, String>,
+}
+
+impl OfflineCredentials {
+  pub fn new(account_names: FnvHashMap<String, String>) -> Self {
+    OfflineCredentials { account_names }
+  }
+}
+
+impl CredentialsProvider for OfflineCredentials {
+  fn get_credentials(&self, _config: &Configuration, _account: &str) -> Result<AwsCredentials, Error> {
+    Err(Ec2RsError::Offline.into())
+  }
+
+  fn get_account_name(&self, _config: &Configuration, account: &str) -> Result<String, Error> {
+    Ok(self.account_names.get(account).cloned().unwrap_or_else(|| account.to_owned()))
+  }
+}
@@ -0,0 +1,41 @@
+use crate::config::Configuration;
+use crate::inventory::{ClientFactory, CredentialsProvider};
+
+use failure::Error;
+use rusoto_ec2::{DescribeRegionsRequest, Ec2, Filter};
+
+use std::time::Duration;
+
+/// Finds every region enabled in an account, sorted, by calling DescribeRegions in `discovery_region`. Regions that
+/// need opting into are only found once the account has opted in. `exclude_regions` is left for the caller to apply,
+/// see: `Ec2Configuration::without_excluded_regions`.
+pub fn discover_regions(
+  config: &Configuration,
+  credentials_provider: &dyn CredentialsProvider,
+  client_factory: &dyn ClientFactory,
+  account: &str,
+) -> Result<Vec<String>, Error> {
+  let credentials = credentials_provider.get_credentials(config, account)?;
+  let ec2 = client_factory.ec2_client(config, &credentials, &config.ec2.get_discovery_region())?;
+
+  let response = ec2
+    .describe_regions(DescribeRegionsRequest {
+      filters: Some(vec![Filter {
+        name: Some("opt-in-status".to_owned()),
+        values: Some(vec!["opt-in-not-required".to_owned(), "opted-in".to_owned()]),
+      }]),
+      ..DescribeRegionsRequest::default()
+    })
+    .with_timeout(Duration::from_secs(60))
+    .sync()?;
+
+  let mut regions: Vec<String> = response
+    .regions
+    .unwrap_or_default()
+    .into_iter()
+    .filter_map(|region| region.region_name)
+    .collect();
+  regions.sort();
+  regions.dedup();
+  Ok(regions)
+}
@@ -1,87 +1,434 @@
+use crate::aliases::{load_aliases, Alias};
+use crate::config::{AliasesConfiguration, Configuration, RoleConfiguration};
+use crate::errors::Ec2RsError;
+use crate::inventory::CredentialsProvider;
+use crate::provide_shallow_credentials::ProvideShallowCredentials;
+
+use chrono::{Duration as ChronoDuration, Utc};
+use failure::{format_err, Error};
 use fnv::FnvHashMap;
-use rusoto_core::{DefaultCredentialsProvider, default_tls_client as GetRusotoTlsClient, ProvideAwsCredentials, Region};
-use rusoto_sts::{StsClient, StsAssumeRoleSessionCredentialsProvider};
-use shellexpand::tilde as TildeExpand;
-use serde_json::{from_slice as JsonFromSlice, Map, Value as JsonValue};
+use futures::sync::oneshot::spawn;
+use futures::Future;
+use lazy_static::lazy_static;
+use rusoto_core::HttpClient;
+use rusoto_credential::{AwsCredentials, ChainProvider, ProfileProvider, ProvideAwsCredentials};
+use rusoto_sts::{
+  AssumeRoleRequest, GetCallerIdentityRequest, GetSessionTokenRequest, NewAwsCredsForStsCreds, Sts, StsClient,
+};
+use tokio::runtime::Runtime;
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use std::io::BufReader;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+/// The role name that scans the account the base credentials belong to, rather than assuming a role.
+pub const CURRENT_ACCOUNT: &str = "current";
 
-use errors::*;
-use provide_shallow_credentials::ProvideShallowCredentials;
+lazy_static! {
+  /// Runs the credential providers that go over the network (like instance metadata) to completion.
+  static ref CREDENTIALS_RUNTIME: Runtime = Runtime::new().expect("Failed to start the credentials runtime!");
+}
 
 /// Handles the assuming of Roles. Also reads from places that contain mapping of
-/// normalized names -> role to assume. (Like Rapture).
+/// normalized names -> role to assume. (Like Rapture, or `~/.aws/config`).
+///
+/// Credentials are cached per role until they're about to expire, so a role is assumed once
+/// rather than once per region, and service.
 pub struct RoleAssumer {
   /// A Map of <Easy Role Name, Role>. To assume.
-  roles_to_assume: FnvHashMap<String, String>,
+  roles_to_assume: FnvHashMap<String, Alias>,
+  /// Who is running ec2-rs, so CloudTrail can show it in session names.
+  user: String,
+  /// The credentials to call STS with, and to scan the current account with. Defaults to rusoto's
+  /// default credentials chain (env, profile, container, and instance metadata).
+  base_credentials: Option<AwsCredentials>,
+  /// The credentials of each role by the role arns of the chain leading to it, locked on their own so roles
+  /// don't wait on each other.
+  cached: Mutex<FnvHashMap<String, Arc<Mutex<Option<AwsCredentials>>>>>,
+  /// The session started with a code from each MFA device, along with the access key it was started from.
+  /// STS only accepts a code once, so every role needing a device is assumed from its one session. Locked
+  /// as a whole, which also keeps roles assumed in parallel from prompting over each other.
+  mfa_sessions: Mutex<FnvHashMap<String, (String, AwsCredentials)>>,
+  /// Whether MFA codes can be asked for on the terminal, when `EC2_RS_MFA_TOKEN` isn't set.
+  mfa_prompts: bool,
+  /// The account the base credentials belong to, once STS has been asked.
+  current_account_id: Mutex<Option<String>>,
 }
 
 impl RoleAssumer {
-  /// Creates a new thing that can assume roles.
-  pub fn new() -> Result<Self> {
-    let mut role_names = FnvHashMap::default();
-
-    let expanded_path = TildeExpand("~/.rapture/aliases.json").into_owned();
-    let rapture_path = Path::new(&expanded_path);
-
-    if rapture_path.exists() {
-      let file_handle = try!(File::open(rapture_path));
-      let mut bytes = if let Ok(metadata) = file_handle.metadata() {
-        Vec::with_capacity(metadata.len() as usize)
-      } else {
-        Vec::with_capacity(3000)
-      };
-      let mut buf_reader = BufReader::new(file_handle);
-      try!(buf_reader.read_to_end(&mut bytes));
-
-      let potential_rapture_json = JsonFromSlice(&bytes);
-      if potential_rapture_json.is_ok() {
-        let potential_rapture_json: JsonValue = potential_rapture_json.unwrap();
-        let potential_rapture_json_obj: Option<&Map<String, JsonValue>> = potential_rapture_json.as_object();
-        if potential_rapture_json_obj.is_some() {
-          let rapture_obj = potential_rapture_json_obj.unwrap();
-          for (key, value) in rapture_obj.into_iter() {
-            if value.is_string() {
-              role_names.insert(key.to_owned(), value.as_str().unwrap().to_owned());
-            }
-          }
+  /// Creates a new thing that can assume roles, with the aliases from rapture, and `~/.aws/config`.
+  pub fn new() -> Result<Self, Error> {
+    Self::with_aliases_from(&AliasesConfiguration::default())
+  }
+
+  /// Creates a new thing that can assume roles, with the aliases from the `[aliases]` of a configuration.
+  pub fn from_config(config: &Configuration) -> Result<Self, Error> {
+    Self::with_aliases_from(&config.aliases)
+  }
+
+  fn with_aliases_from(aliases: &AliasesConfiguration) -> Result<Self, Error> {
+    Ok(Self {
+      roles_to_assume: load_aliases(aliases)?,
+      user: current_user(),
+      base_credentials: None,
+      cached: Mutex::new(FnvHashMap::default()),
+      mfa_sessions: Mutex::new(FnvHashMap::default()),
+      mfa_prompts: true,
+      current_account_id: Mutex::new(None),
+    })
+  }
+
+  /// Adds an alias for a role, on top of the ones that were read in.
+  pub fn with_alias(mut self, alias: &str, role_arn: &str) -> Self {
+    self.roles_to_assume.insert(alias.to_owned(), Alias::new(role_arn));
+    self
+  }
+
+  /// Calls STS with these credentials instead of the default credentials chain.
+  pub fn with_base_credentials(mut self, credentials: AwsCredentials) -> Self {
+    self.base_credentials = Some(credentials);
+    self
+  }
+
+  /// Never asks for MFA codes on the terminal, so roles needing one fail unless `EC2_RS_MFA_TOKEN` is set.
+  /// For running without anyone at the terminal, like refreshing the cache in the background.
+  pub fn without_mfa_prompts(mut self) -> Self {
+    self.mfa_prompts = false;
+    self
+  }
+
+  /// Gets the role an alias points at, or the role itself when it isn't an alias.
+  pub fn resolve_alias(&self, to_assume: &str) -> String {
+    self.get_alias(to_assume).role_arn
+  }
+
+  /// Gets the account a role scans, to tell roles apart by: the role behind an alias, or the id of the account
+  /// the base credentials belong to for `current`.
+  pub fn resolve_account(&self, config: &Configuration, to_assume: &str) -> Result<String, Error> {
+    if to_assume == CURRENT_ACCOUNT {
+      self.get_current_account_id(config)
+    } else {
+      Ok(self.resolve_alias(to_assume))
+    }
+  }
+
+  /// Gets the id of the account the base credentials belong to, asking STS the first time around.
+  pub fn get_current_account_id(&self, config: &Configuration) -> Result<String, Error> {
+    let mut current_account_id = self.current_account_id.lock().expect("Account id lock was poisoned!");
+    if let Some(ref account_id) = *current_account_id {
+      return Ok(account_id.to_owned());
+    }
+
+    let sts = StsClient::new_with(
+      HttpClient::new()?,
+      ProvideShallowCredentials::new(self.assume_role(config, CURRENT_ACCOUNT)?),
+      config.get_sts_region()?,
+    );
+    let identity = sts
+      .get_caller_identity(GetCallerIdentityRequest {})
+      .with_timeout(Duration::from_secs(60))
+      .sync()?;
+    let account_id = identity.account.ok_or(Ec2RsError::NoAccountId)?;
+    *current_account_id = Some(account_id.clone());
+    Ok(account_id)
+  }
+
+  /// Gets everything an alias points at, or the role itself when it isn't an alias.
+  /// `current` is never an alias.
+  pub fn get_alias(&self, to_assume: &str) -> Alias {
+    if to_assume == CURRENT_ACCOUNT {
+      return Alias::new(to_assume);
+    }
+    self
+      .roles_to_assume
+      .get(to_assume)
+      .cloned()
+      .unwrap_or_else(|| Alias::new(to_assume))
+  }
+
+  /// Gets the session name roles are assumed with, when they don't have one configured.
+  pub fn get_session_name(&self) -> String {
+    self.render_session_name(&RoleConfiguration::default().get_session_name(), "", "")
+  }
+
+  /// Fills in a session name template for a role. STS only allows letters, digits, and `_+=,.@-` in up
+  /// to 64 characters, so anything else is replaced with a `-`.
+  pub fn render_session_name(&self, template: &str, alias: &str, role_arn: &str) -> String {
+    let mut arn_parts = role_arn.splitn(6, ':');
+    let account_id = arn_parts.nth(4).unwrap_or("");
+    let role = arn_parts.next().and_then(|resource| resource.rsplit('/').next()).unwrap_or("");
+
+    template
+      .replace("{user}", &self.user)
+      .replace("{alias}", alias)
+      .replace("{account_id}", account_id)
+      .replace("{role}", role)
+      .chars()
+      .map(|character| {
+        if character.is_ascii_alphanumeric() || "+=,.@-_".contains(character) {
+          character
+        } else {
+          '-'
         }
-      }
+      })
+      .take(64)
+      .collect()
+  }
+
+  /// Assumes a role (or the role behind an alias), reusing its credentials while they're still good.
+  /// `current` hands back the base credentials themselves. Roles with a `via` are assumed through each
+  /// of its roles in turn.
+  pub fn assume_role(&self, config: &Configuration, to_assume: &str) -> Result<AwsCredentials, Error> {
+    self.check_role(config, to_assume, &mut Vec::new())?;
+    self.assume_chain(config, &self.get_chain(config, to_assume))
+  }
+
+  /// Gets the roles a role is assumed through (its `via`), followed by the role itself.
+  fn get_chain(&self, config: &Configuration, to_assume: &str) -> Vec<String> {
+    let alias = self.get_alias(to_assume);
+    let mut chain = config.get_role(to_assume, &alias.role_arn).get_via();
+    chain.push(to_assume.to_owned());
+    chain
+  }
+
+  /// Follows the roles a role is assumed through, before any of them are assumed, so a `via`, or
+  /// `source_profile` that loops back around fails instead of waiting on itself forever.
+  fn check_role(&self, config: &Configuration, to_assume: &str, assuming: &mut Vec<String>) -> Result<(), Error> {
+    self.check_chain(config, &self.get_chain(config, to_assume), assuming)
+  }
+
+  /// Follows a chain the way `assume_chain` assumes it, remembering the role arns along the way.
+  fn check_chain(&self, config: &Configuration, chain: &[String], assuming: &mut Vec<String>) -> Result<(), Error> {
+    let (to_assume, via) = chain.split_last().expect("A role chain always has a role to assume!");
+    let alias = self.get_alias(to_assume);
+    if alias.role_arn == CURRENT_ACCOUNT {
+      return Ok(());
     }
+    if assuming.contains(&alias.role_arn) {
+      return Err(format_err!("The role: {} is assumed through itself", to_assume));
+    }
+    assuming.push(alias.role_arn.clone());
 
-    Ok(Self { roles_to_assume: role_names })
+    if !via.is_empty() {
+      return self.check_chain(config, via, assuming);
+    }
+    match alias.source_profile {
+      Some(ref profile) if self.roles_to_assume.contains_key(profile) => self.check_role(config, profile, assuming),
+      _ => Ok(()),
+    }
   }
 
-  pub fn assume_role(&self, to_assume: String) -> Result<ProvideShallowCredentials> {
-    let tls_client = try!(GetRusotoTlsClient());
-    let creds = try!(DefaultCredentialsProvider::new());
-    let sts = StsClient::new(tls_client, creds, Region::UsEast1);
+  /// Assumes each role of a chain with the credentials of the one before it. Every link is cached by
+  /// the role arns leading up to it, so chains through the same roles share their credentials. The roles
+  /// before a link are assumed without holding its lock, so links shared between chains never wait on each other.
+  fn assume_chain(&self, config: &Configuration, chain: &[String]) -> Result<AwsCredentials, Error> {
+    let (to_assume, via) = chain.split_last().expect("A role chain always has a role to assume!");
+    let key = chain.iter().map(|role| self.resolve_alias(role)).collect::<Vec<_>>().join(" > ");
+    let slot = self
+      .cached
+      .lock()
+      .expect("Credentials cache lock was poisoned!")
+      .entry(key)
+      .or_default()
+      .clone();
+    if let Some(credentials) = still_good(&slot.lock().expect("Credentials cache lock was poisoned!")) {
+      return Ok(credentials);
+    }
 
-    let to_assume_frd = if self.roles_to_assume.contains_key(&to_assume) {
-      self.roles_to_assume.get(&to_assume).unwrap().to_owned()
+    let alias = self.get_alias(to_assume);
+    let source_credentials = if alias.role_arn == CURRENT_ACCOUNT {
+      None
+    } else if !via.is_empty() {
+      Some(self.assume_chain(config, via)?)
     } else {
-      to_assume
+      Some(match alias.source_profile {
+        // Like the AWS cli, a source profile that's a role itself is assumed first.
+        Some(ref profile) if self.roles_to_assume.contains_key(profile) => {
+          self.assume_chain(config, &self.get_chain(config, profile))?
+        }
+        Some(ref profile) => read_profile_credentials(profile)?,
+        None => self.assume_chain(config, &[CURRENT_ACCOUNT.to_owned()])?,
+      })
+    };
+
+    // Another thread may have assumed the role while we were getting its source credentials.
+    let mut cached = slot.lock().expect("Credentials cache lock was poisoned!");
+    if let Some(credentials) = still_good(&cached) {
+      return Ok(credentials);
+    }
+    let credentials = match source_credentials {
+      Some(source_credentials) => self.request_credentials(config, to_assume, &alias, source_credentials)?,
+      None => self.base_credentials()?,
     };
+    *cached = Some(credentials.clone());
+    Ok(credentials)
+  }
+
+  /// Calls STS to assume a role with some credentials, without looking at the cache.
+  fn request_credentials(
+    &self,
+    config: &Configuration,
+    to_assume: &str,
+    alias: &Alias,
+    source_credentials: AwsCredentials,
+  ) -> Result<AwsCredentials, Error> {
+    let role_arn = &alias.role_arn;
+    let role_config = config.get_role(to_assume, role_arn);
+    let source_credentials = match role_config.get_mfa_serial() {
+      Some(ref mfa_serial) => self.get_mfa_session(config, mfa_serial, source_credentials)?,
+      None => source_credentials,
+    };
+    let sts = StsClient::new_with(
+      HttpClient::new()?,
+      ProvideShallowCredentials::new(source_credentials),
+      config.get_sts_region()?,
+    );
+    let request = AssumeRoleRequest {
+      role_arn: role_arn.to_owned(),
+      role_session_name: self.render_session_name(&role_config.get_session_name(), to_assume, role_arn),
+      external_id: role_config.get_external_id(),
+      duration_seconds: role_config.get_duration(),
+      policy: role_config.get_policy(),
+      ..Default::default()
+    };
+
+    let response = sts.assume_role(request).with_timeout(Duration::from_secs(60)).sync()?;
+    let credentials = response.credentials.ok_or_else(|| Ec2RsError::NoCredentials {
+      role_arn: role_arn.to_owned(),
+    })?;
+    Ok(AwsCredentials::new_for_credentials(credentials)?)
+  }
+
+  /// Gets the credentials of a session started with a code from an MFA device, starting it with the source
+  /// credentials of the first role that needs the device.
+  fn get_mfa_session(
+    &self,
+    config: &Configuration,
+    mfa_serial: &str,
+    source_credentials: AwsCredentials,
+  ) -> Result<AwsCredentials, Error> {
+    let mut mfa_sessions = self.mfa_sessions.lock().expect("MFA session lock was poisoned!");
+    if let Some((ref source_key, ref credentials)) = mfa_sessions.get(mfa_serial) {
+      if source_key != source_credentials.aws_access_key_id() {
+        return Err(format_err!(
+          "{} is needed by roles assumed from different credentials, but one code only starts one session",
+          mfa_serial
+        ));
+      }
+      if !expires_soon(credentials) {
+        return Ok(credentials.clone());
+      }
+    }
+
+    let token_code = self.get_mfa_token(mfa_serial)?;
+    let source_key = source_credentials.aws_access_key_id().to_owned();
+    let sts = StsClient::new_with(
+      HttpClient::new()?,
+      ProvideShallowCredentials::new(source_credentials),
+      config.get_sts_region()?,
+    );
+    let request = GetSessionTokenRequest {
+      serial_number: Some(mfa_serial.to_owned()),
+      token_code: Some(token_code),
+      ..Default::default()
+    };
+    let response = sts.get_session_token(request).with_timeout(Duration::from_secs(60)).sync()?;
+    let credentials = response.credentials.ok_or_else(|| Ec2RsError::NoSessionCredentials {
+      mfa_serial: mfa_serial.to_owned(),
+    })?;
+    let credentials = AwsCredentials::new_for_credentials(credentials)?;
+    mfa_sessions.insert(mfa_serial.to_owned(), (source_key, credentials.clone()));
+    Ok(credentials)
+  }
+
+  /// Gets the code of an MFA device, from `EC2_RS_MFA_TOKEN`, or by asking on the terminal.
+  fn get_mfa_token(&self, mfa_serial: &str) -> Result<String, Error> {
+    match env::var("EC2_RS_MFA_TOKEN") {
+      Ok(ref token) if !token.is_empty() => Ok(token.to_owned()),
+      _ if self.mfa_prompts => prompt_for_mfa_token(mfa_serial),
+      _ => Err(format_err!(
+        "{} needs an MFA code, but there's nobody to ask for one. Set EC2_RS_MFA_TOKEN instead.",
+        mfa_serial
+      )),
+    }
+  }
+
+  /// Gets the base credentials, without looking at the cache.
+  fn base_credentials(&self) -> Result<AwsCredentials, Error> {
+    match self.base_credentials {
+      Some(ref base_credentials) => Ok(base_credentials.clone()),
+      None => spawn(ChainProvider::new().credentials(), &CREDENTIALS_RUNTIME.executor())
+        .wait()
+        .map_err(|err| format_err!("Failed to find any credentials: {}", err)),
+    }
+  }
+}
 
-    Ok(ProvideShallowCredentials::new(try!(
-      StsAssumeRoleSessionCredentialsProvider::new(
-        sts,
-        to_assume_frd,
-        "ec2-rs-role-assumer".to_owned(),
-        None,
-        None,
-        None,
-        None,
-      ).credentials()
-    )))
+impl CredentialsProvider for RoleAssumer {
+  fn get_credentials(&self, config: &Configuration, account: &str) -> Result<AwsCredentials, Error> {
+    self.assume_role(config, account)
   }
 
-  pub fn get_default_creds(&self) -> Result<ProvideShallowCredentials> {
-    Ok(ProvideShallowCredentials::new(
-      try!(try!(DefaultCredentialsProvider::new()).credentials()),
-    ))
+  /// `current` is named by the id of its account, everything else by the name it was asked for.
+  fn get_account_name(&self, config: &Configuration, account: &str) -> Result<String, Error> {
+    if account == CURRENT_ACCOUNT {
+      self.get_current_account_id(config)
+    } else {
+      Ok(account.to_owned())
+    }
+  }
+}
+
+/// Reads the credentials of a profile out of the AWS credentials file, for roles with a `source_profile`.
+fn read_profile_credentials(profile: &str) -> Result<AwsCredentials, Error> {
+  let mut provider = ProfileProvider::new()?;
+  provider.set_profile(profile);
+  provider
+    .credentials()
+    .wait()
+    .map_err(|err| format_err!("Failed to read the credentials of profile: {}: {}", profile, err))
+}
+
+/// Gets cached credentials, unless they're about to expire.
+fn still_good(cached: &Option<AwsCredentials>) -> Option<AwsCredentials> {
+  cached.as_ref().filter(|credentials| !expires_soon(credentials)).cloned()
+}
+
+/// Whether credentials expire within the next five minutes, which is too close to start a scan with.
+fn expires_soon(credentials: &AwsCredentials) -> bool {
+  match credentials.expires_at() {
+    Some(expires_at) => *expires_at <= Utc::now() + ChronoDuration::minutes(5),
+    None => false,
+  }
+}
+
+/// Gets who is running ec2-rs, for naming sessions after them.
+fn current_user() -> String {
+  match env::var("USER").or_else(|_| env::var("USERNAME")) {
+    Ok(ref user) if !user.is_empty() => user.to_owned(),
+    _ => "role-assumer".to_owned(),
+  }
+}
+
+/// Asks for the code of an MFA device on the terminal. stdin, and stdout belong to ansible, so this
+/// goes through the terminal itself.
+fn prompt_for_mfa_token(mfa_serial: &str) -> Result<String, Error> {
+  let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").map_err(|_| {
+    format_err!(
+      "{} needs an MFA code, but there's no terminal to ask for one on. Set EC2_RS_MFA_TOKEN instead.",
+      mfa_serial
+    )
+  })?;
+  write!(tty, "MFA code for {}: ", mfa_serial)?;
+  tty.flush()?;
+
+  let mut token = String::new();
+  BufReader::new(tty).read_line(&mut token)?;
+  let token = token.trim();
+  if token.is_empty() {
+    return Err(format_err!("No MFA code was entered for: {}", mfa_serial));
   }
+  Ok(token.to_owned())
 }
@@ -0,0 +1,123 @@
+use crate::config::Route53Configuration;
+
+use failure::Error;
+use fnv::FnvHashMap;
+use rusoto_ec2::Instance;
+use rusoto_route53::{HostedZone, ListHostedZonesRequest, ListResourceRecordSetsRequest, Route53};
+
+use std::time::Duration;
+
+/// The Route53 records of an account, keyed by what they point at (IP addresses, and DNS names).
+#[derive(Default)]
+pub struct Route53Records {
+  names_by_target: FnvHashMap<String, Vec<String>>,
+}
+
+impl Route53Records {
+  fn insert(&mut self, target: &str, name: &str) {
+    let names = self.names_by_target.entry(normalize_name(target)).or_default();
+    let name = normalize_name(name);
+    if !names.contains(&name) {
+      names.push(name);
+    }
+  }
+
+  /// Gets the names of every record pointing at one of the addresses of an instance, sorted.
+  pub fn get_names_for_instance(&self, instance: &Instance) -> Vec<String> {
+    let addresses = [
+      instance.public_ip_address.as_ref(),
+      instance.private_ip_address.as_ref(),
+      instance.public_dns_name.as_ref(),
+      instance.private_dns_name.as_ref(),
+    ];
+
+    let mut names: Vec<String> = addresses
+      .iter()
+      .filter_map(|address| *address)
+      .filter_map(|address| self.names_by_target.get(&normalize_name(address)))
+      .flatten()
+      .cloned()
+      .collect();
+    names.sort();
+    names.dedup();
+    names
+  }
+}
+
+/// Record names, and values come back fully qualified (`web.example.com.`), while EC2 hands us
+/// `web.example.com`. So strip the trailing dot, and lowercase everything so they line up.
+fn normalize_name(name: &str) -> String {
+  name.trim_end_matches('.').to_lowercase()
+}
+
+fn zone_matches(zone: &HostedZone, patterns: &[String]) -> bool {
+  let zone_name = normalize_name(&zone.name);
+  let zone_id = zone.id.trim_start_matches("/hostedzone/");
+  patterns
+    .iter()
+    .any(|pattern| normalize_name(pattern) == zone_name || pattern.trim_start_matches("/hostedzone/") == zone_id)
+}
+
+/// Lists the hosted zones to look in, following every page, and honouring the included/excluded zones.
+fn list_hosted_zones<R: Route53>(route53: &R, config: &Route53Configuration) -> Result<Vec<HostedZone>, Error> {
+  let mut request = ListHostedZonesRequest::default();
+  let mut zones = Vec::new();
+
+  loop {
+    let page = route53.list_hosted_zones(request.clone()).with_timeout(Duration::from_secs(300)).sync()?;
+    zones.extend(page.hosted_zones);
+
+    match page.next_marker {
+      Some(marker) if page.is_truncated => request.marker = Some(marker),
+      _ => break,
+    }
+  }
+
+  let included = config.get_hosted_zones();
+  let excluded = config.get_excluded_zones();
+  Ok(
+    zones
+      .into_iter()
+      .filter(|zone| included.as_ref().map(|included| zone_matches(zone, included)).unwrap_or(true))
+      .filter(|zone| !zone_matches(zone, &excluded))
+      .collect(),
+  )
+}
+
+/// Lists every A, and CNAME record in the configured hosted zones of an account.
+pub fn list_route53_records<R: Route53>(route53: &R, config: &Route53Configuration) -> Result<Route53Records, Error> {
+  let mut records = Route53Records::default();
+
+  for zone in list_hosted_zones(route53, config)? {
+    // Zone ids come back as `/hostedzone/<id>`, but go into the request path as just the id.
+    let mut request = ListResourceRecordSetsRequest {
+      hosted_zone_id: zone.id.trim_start_matches("/hostedzone/").to_owned(),
+      ..Default::default()
+    };
+
+    loop {
+      let page = route53
+        .list_resource_record_sets(request.clone())
+        .with_timeout(Duration::from_secs(300))
+        .sync()?;
+
+      for record_set in page.resource_record_sets {
+        if record_set.type_ != "A" && record_set.type_ != "CNAME" {
+          continue;
+        }
+        for record in record_set.resource_records.iter().flatten() {
+          records.insert(&record.value, &record_set.name);
+        }
+      }
+
+      if !page.is_truncated {
+        break;
+      }
+      request.start_record_name = page.next_record_name;
+      request.start_record_type = page.next_record_type;
+      request.start_record_identifier = page.next_record_identifier;
+    }
+  }
+
+  Ok(records)
+}
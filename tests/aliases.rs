@@ -0,0 +1,109 @@
+//! End to end tests of the places short names for roles are read from.
+
+mod common;
+
+use common::{assumed_roles, group_hosts, stub_with_two_accounts, TestDir, DEV, OPS};
+
+use std::fs;
+
+#[test]
+fn inline_aliases_are_resolved() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("[aliases.roles]\nops = \"{}\"\ndev = \"{}\"", OPS, DEV),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let inventory = dir.run_json("ops,dev", &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1"]);
+  assert_eq!(assumed_roles(&stub), vec![OPS.to_owned(), DEV.to_owned()]);
+}
+
+#[test]
+fn alias_files_are_read_in_order() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  fs::write(
+    dir.path.join("aliases.json"),
+    format!("{{\"ops\": \"{}\", \"dev\": {{\"role_arn\": \"{}\"}}}}", DEV, DEV),
+  )
+  .unwrap();
+  fs::write(dir.path.join("aliases.toml"), format!("ops = \"{}\"\n", OPS)).unwrap();
+  dir.write_config(
+    &stub,
+    &format!(
+      "[aliases]\nfiles = [\"{}\", \"{}\"]",
+      dir.path.join("aliases.json").display(),
+      dir.path.join("aliases.toml").display()
+    ),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let inventory = dir.run_json("ops", &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+
+  let inventory = dir.run_json("dev", &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.1.0.1"]);
+}
+
+#[test]
+fn missing_alias_files_fail() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "[aliases]\nfiles = [\"/nonexistent/aliases.json\"]", "regions = [\"us-east-1\"]");
+
+  let output = dir.run(OPS, &["--list"], &[]);
+
+  assert!(!output.status.success());
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(stderr.contains("/nonexistent/aliases.json"), "unexpected stderr: {}", stderr);
+}
+
+#[test]
+fn rapture_and_aws_config_profiles_are_resolved() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  fs::create_dir_all(dir.path.join(".rapture")).unwrap();
+  fs::write(dir.path.join(".rapture/aliases.json"), format!("{{\"ops\": \"{}\"}}", OPS)).unwrap();
+  fs::create_dir_all(dir.path.join(".aws")).unwrap();
+  fs::write(
+    dir.path.join(".aws/config"),
+    format!(
+      "[default]\nregion = us-east-1\n\n[profile dev]\nrole_arn = {}\nsource_profile = base\ns3 =\n  max_concurrent_requests = 2\n",
+      DEV
+    ),
+  )
+  .unwrap();
+  fs::write(
+    dir.path.join(".aws/credentials"),
+    "[base]\naws_access_key_id = profile-key\naws_secret_access_key = profile-secret\n",
+  )
+  .unwrap();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  let inventory = dir.run_json("ops,dev", &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1"]);
+  let assumed = stub.requests("AssumeRole");
+  let dev = assumed.iter().find(|request| request.param("RoleArn") == Some(DEV)).unwrap();
+  assert_eq!(dev.access_key, "profile-key");
+  let ops = assumed.iter().find(|request| request.param("RoleArn") == Some(OPS)).unwrap();
+  assert_eq!(ops.access_key, "base-key");
+}
+
+#[test]
+fn alias_sources_can_be_turned_off() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  fs::create_dir_all(dir.path.join(".rapture")).unwrap();
+  fs::write(dir.path.join(".rapture/aliases.json"), format!("{{\"ops\": \"{}\"}}", OPS)).unwrap();
+  dir.write_config(&stub, "[aliases]\nrapture = false", "regions = [\"us-east-1\"]");
+
+  let inventory = dir.run_json("ops", &["--list"], &[]);
+
+  assert!(group_hosts(&inventory, "ec2").is_empty());
+  assert_eq!(assumed_roles(&stub), vec!["ops".to_owned()]);
+}
@@ -0,0 +1,204 @@
+//! End to end tests of how roles are assumed through a stubbed out STS.
+
+mod common;
+
+use common::{group_hosts, stub_with_two_accounts, TestDir, DEV, OPS};
+
+use std::fs;
+use std::process::Stdio;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn role_settings_are_passed_to_assume_role() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!(
+      "[aliases.roles]\nops = \"{}\"\n\n[roles.ops]\nexternal_id = \"partner-id\"\nduration = 900\n\
+       session_name = \"inventory-{{alias}}-{{account_id}}-{{role}}\"\npolicy = '{{\"Version\":\"2012-10-17\"}}'\n\
+       mfa_serial = \"arn:aws:iam::000000000000:mfa/jane\"",
+      OPS
+    ),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let inventory = dir.run_json("ops", &["--list"], &[("EC2_RS_MFA_TOKEN", "123456")]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  let assumed = stub.requests("AssumeRole");
+  assert_eq!(assumed.len(), 1);
+  assert_eq!(assumed[0].param("RoleArn"), Some(OPS));
+  assert_eq!(assumed[0].param("ExternalId"), Some("partner-id"));
+  assert_eq!(assumed[0].param("DurationSeconds"), Some("900"));
+  assert_eq!(assumed[0].param("RoleSessionName"), Some("inventory-ops-111111111111-ops"));
+  assert_eq!(assumed[0].param("Policy"), Some("{\"Version\":\"2012-10-17\"}"));
+  // The MFA code starts a session, and the role is assumed from it.
+  assert_eq!(assumed[0].param("TokenCode"), None);
+  assert_eq!(assumed[0].access_key, "base-key-mfa");
+  let sessions = stub.requests("GetSessionToken");
+  assert_eq!(sessions.len(), 1);
+  assert_eq!(sessions[0].param("SerialNumber"), Some("arn:aws:iam::000000000000:mfa/jane"));
+  assert_eq!(sessions[0].param("TokenCode"), Some("123456"));
+  assert_eq!(sessions[0].access_key, "base-key");
+}
+
+#[test]
+fn roles_sharing_an_mfa_device_share_its_session() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!(
+      "[aliases.roles]\nops = \"{}\"\ndev = \"{}\"\n\n[roles.ops]\nmfa_serial = \"{mfa}\"\n\n\
+       [roles.dev]\nmfa_serial = \"{mfa}\"",
+      OPS,
+      DEV,
+      mfa = "arn:aws:iam::000000000000:mfa/jane"
+    ),
+    "regions = [\"us-east-1\"]",
+  );
+
+  // STS only accepts a code once, so both roles have to come out of one session.
+  let inventory = dir.run_json("ops,dev", &["--list"], &[("EC2_RS_MFA_TOKEN", "123456")]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1"]);
+  assert_eq!(stub.requests("GetSessionToken").len(), 1);
+  let assumed = stub.requests("AssumeRole");
+  assert_eq!(assumed.len(), 2);
+  assert!(assumed.iter().all(|request| request.access_key == "base-key-mfa"));
+}
+
+#[test]
+fn role_settings_can_be_keyed_by_arn() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("[roles.\"{}\"]\nexternal_id = \"dev-id\"", DEV),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let inventory = dir.run_json(&format!("{},{}", OPS, DEV), &["--list"], &[("USER", "jane")]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1"]);
+  let assumed = stub.requests("AssumeRole");
+  let dev = assumed.iter().find(|request| request.param("RoleArn") == Some(DEV)).unwrap();
+  assert_eq!(dev.param("ExternalId"), Some("dev-id"));
+  let ops = assumed.iter().find(|request| request.param("RoleArn") == Some(OPS)).unwrap();
+  assert_eq!(ops.param("ExternalId"), None);
+  assert_eq!(ops.param("RoleSessionName"), Some("ec2-rs-jane"));
+  assert_eq!(ops.param("TokenCode"), None);
+}
+
+#[test]
+fn roles_are_chained_through_a_shared_hub() {
+  const HUB: &str = "arn:aws:iam::999999999999:role/hub-admin";
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!(
+      "[aliases.roles]\nhub = \"{}\"\nops = \"{}\"\ndev = \"{}\"\n\n[roles.ops]\nvia = [\"hub\"]\n\n[roles.dev]\nvia = [\"hub\"]",
+      HUB, OPS, DEV
+    ),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let inventory = dir.run_json("ops,dev", &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1"]);
+  let assumed = stub.requests("AssumeRole");
+  let hub: Vec<_> = assumed.iter().filter(|request| request.param("RoleArn") == Some(HUB)).collect();
+  assert_eq!(hub.len(), 1);
+  assert_eq!(hub[0].access_key, "base-key");
+  let hub_key = stub.access_key_for(HUB);
+  for role in &[OPS, DEV] {
+    let request = assumed.iter().find(|request| request.param("RoleArn") == Some(role)).unwrap();
+    assert_eq!(request.access_key, hub_key);
+  }
+}
+
+#[test]
+fn chains_through_an_alias_and_its_arn_share_the_link() {
+  const HUB: &str = "arn:aws:iam::999999999999:role/hub-admin";
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!(
+      "[aliases.roles]\nhub = \"{}\"\nops = \"{}\"\ndev = \"{}\"\n\n[roles.ops]\nvia = [\"hub\"]\n\n\
+       [roles.dev]\nvia = [\"{}\"]",
+      HUB, OPS, DEV, HUB
+    ),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let inventory = dir.run_json("ops,dev", &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1"]);
+  let assumed = stub.requests("AssumeRole");
+  assert_eq!(assumed.iter().filter(|request| request.param("RoleArn") == Some(HUB)).count(), 1);
+}
+
+#[test]
+fn source_profiles_that_loop_fail() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  fs::create_dir_all(dir.path.join(".aws")).unwrap();
+  fs::write(
+    dir.path.join(".aws/config"),
+    format!(
+      "[profile ops]\nrole_arn = {}\nsource_profile = dev\n\n[profile dev]\nrole_arn = {}\nsource_profile = ops\n",
+      OPS, DEV
+    ),
+  )
+  .unwrap();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  let output = dir.run("ops", &["--list"], &[]);
+
+  assert!(!output.status.success());
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(stderr.contains("is assumed through itself"), "unexpected stderr: {}", stderr);
+  assert!(stub.requests("AssumeRole").is_empty());
+}
+
+#[test]
+fn source_profiles_that_loop_fail_when_both_are_targeted() {
+  let stub = stub_with_two_accounts();
+  let dir = TestDir::new();
+  fs::create_dir_all(dir.path.join(".aws")).unwrap();
+  fs::write(
+    dir.path.join(".aws/config"),
+    format!(
+      "[profile ops]\nrole_arn = {}\nsource_profile = dev\n\n[profile dev]\nrole_arn = {}\nsource_profile = ops\n",
+      OPS, DEV
+    ),
+  )
+  .unwrap();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  // Both roles are assumed in parallel, each through the other, which has to fail rather than hang.
+  let mut child = dir
+    .command("ops,dev", &["--list"], &[])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+  let started = Instant::now();
+  while child.try_wait().unwrap().is_none() {
+    if started.elapsed() > Duration::from_secs(30) {
+      child.kill().unwrap();
+      panic!("ec2-rs hung assuming roles that loop!");
+    }
+    thread::sleep(Duration::from_millis(50));
+  }
+  let output = child.wait_with_output().unwrap();
+
+  assert!(!output.status.success());
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(stderr.contains("is assumed through itself"), "unexpected stderr: {}", stderr);
+  assert!(stub.requests("AssumeRole").is_empty());
+}
@@ -0,0 +1,601 @@
+//! Tests of how ec2-rs reads, and writes its cache.
+
+mod common;
+
+use common::{group_hosts, StubAws, StubInstance, TestDir, DEV, OPS};
+use ec2_rs_lib::cache::Cache;
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn instances(count: usize) -> Vec<StubInstance> {
+  (1..=count)
+    .map(|idx| StubInstance::vpc(&format!("i-{:010}", idx), "us-east-1a", &format!("10.0.0.{}", idx)))
+    .collect()
+}
+
+/// Every file under a directory, recursively.
+fn files_under(path: &Path) -> Vec<String> {
+  let mut files = Vec::new();
+  for entry in fs::read_dir(path).unwrap() {
+    let path = entry.unwrap().path();
+    if path.is_dir() {
+      files.extend(files_under(&path));
+    } else {
+      files.push(path.display().to_string());
+    }
+  }
+  files
+}
+
+#[test]
+fn valid_cache_is_served_without_scanning() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(2)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+
+  let first = dir.run_json(OPS, &["--list"], &[]);
+  let second = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(first, second);
+  assert_eq!(stub.requests("DescribeInstances").len(), 1);
+}
+
+#[test]
+fn smaller_inventory_replaces_the_whole_cache() {
+  let big = StubAws::builder().instances(OPS, "us-east-1", instances(20)).start();
+  let small = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+
+  dir.write_config(&big, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list"], &[]);
+  dir.write_config(&small, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list", "--refresh-cache"], &[]);
+
+  let cached = dir.run_json(OPS, &["--list"], &[("EC2_RS_FORCE_CACHE", "1")]);
+  assert_eq!(group_hosts(&cached, "ec2"), vec!["10.0.0.1"]);
+}
+
+#[test]
+fn parallel_refreshes_leave_a_complete_cache() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(50)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+
+  let children: Vec<_> = (0..6)
+    .map(|_| {
+      dir
+        .command(OPS, &["--list", "--refresh-cache"], &[])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to spawn ec2-rs!")
+    })
+    .collect();
+  for mut child in children {
+    assert!(child.wait().unwrap().success());
+  }
+
+  let cached = dir.run_json(OPS, &["--list"], &[("EC2_RS_FORCE_CACHE", "1")]);
+  assert_eq!(group_hosts(&cached, "ec2").len(), 50);
+  let leftovers: Vec<String> = files_under(&dir.path.join("cache"))
+    .into_iter()
+    .filter(|file| file.ends_with(".tmp"))
+    .collect();
+  assert!(leftovers.is_empty(), "Temporary cache files were left behind: {:?}", leftovers);
+}
+
+#[test]
+fn expired_cache_is_rescanned() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 0", "regions = [\"us-east-1\"]");
+
+  dir.run_json(OPS, &["--list"], &[]);
+  dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(stub.requests("DescribeInstances").len(), 2);
+}
+
+#[test]
+fn config_changes_invalidate_the_cache() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(2)).start();
+  let dir = TestDir::new();
+
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list"], &[]);
+  // Only touching the cache settings keeps the cache around.
+  dir.write_config(&stub, "cache_max_age = 600", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(stub.requests("DescribeInstances").len(), 1);
+
+  dir.write_config(&stub, "cache_max_age = 600", "regions = [\"us-east-1\"]\nexclude_filter = \"\\\\.2$\"");
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(stub.requests("DescribeInstances").len(), 2);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+}
+
+#[test]
+fn regions_are_cached_independently() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", instances(1))
+    .instances(OPS, "us-west-2", vec![StubInstance::vpc("i-0000000009", "us-west-2a", "10.1.0.9")])
+    .start();
+  let dir = TestDir::new();
+
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list"], &[]);
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\", \"us-west-2\"]");
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.9"]);
+  let regions: Vec<String> = stub
+    .requests("DescribeInstances")
+    .iter()
+    .map(|request| request.region.to_owned())
+    .collect();
+  assert_eq!(regions, vec!["us-east-1", "us-west-2"]);
+}
+
+#[test]
+fn roles_share_cache_entries_in_any_combination() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", instances(1))
+    .instances(DEV, "us-east-1", vec![StubInstance::vpc("i-0000000009", "us-east-1a", "10.2.0.9")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+
+  let both = dir.run_json(&format!("{},{}", OPS, DEV), &["--list"], &[]);
+  let reversed = dir.run_json(&format!("{},{}", DEV, OPS), &["--list"], &[]);
+  let ops_only = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(both, reversed);
+  assert_eq!(group_hosts(&ops_only, "ec2"), vec!["10.0.0.1"]);
+  assert_eq!(stub.requests("DescribeInstances").len(), 2);
+}
+
+#[test]
+fn failed_regions_are_filled_in_from_their_own_cache_entry() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", instances(1))
+    .instances(OPS, "us-west-2", vec![StubInstance::vpc("i-0000000009", "us-west-2a", "10.1.0.9")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    "cache_max_age = 0\nfailure_policy = \"use_stale_cache_for_failed_region\"",
+    "regions = [\"us-east-1\", \"us-west-2\"]",
+  );
+
+  dir.run_json(OPS, &["--list"], &[]);
+  stub.replace_instances(OPS, "us-east-1", instances(2));
+  stub.start_failing(OPS, "us-west-2");
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.0.0.2", "10.1.0.9"]);
+  assert_eq!(group_hosts(&inventory, "us-west-2"), vec!["10.1.0.9"]);
+}
+
+#[test]
+fn unreadable_cache_entries_are_ignored() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list"], &[]);
+
+  // A bare inventory, like older versions of ec2-rs wrote, isn't trusted.
+  let entries: Vec<String> = files_under(&dir.path.join("cache"))
+    .into_iter()
+    .filter(|file| file.ends_with("us-east-1"))
+    .collect();
+  assert_eq!(entries.len(), 1);
+  fs::write(&entries[0], "{\"ec2\": [\"10.9.9.9\"], \"_meta\": {\"hostvars\": {}}}").unwrap();
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  assert_eq!(stub.requests("DescribeInstances").len(), 2);
+}
+
+/// Waits for a condition to come true, checking every so often, for up to ten seconds.
+fn wait_for<F: FnMut() -> bool>(mut condition: F) -> bool {
+  let started = Instant::now();
+  while started.elapsed() < Duration::from_secs(10) {
+    if condition() {
+      return true;
+    }
+    thread::sleep(Duration::from_millis(100));
+  }
+  false
+}
+
+#[test]
+fn stale_cache_is_served_while_refreshed_in_the_background() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 0\ncache_max_stale = 300", "regions = [\"us-east-1\"]");
+
+  dir.run_json(OPS, &["--list"], &[]);
+  stub.replace_instances(OPS, "us-east-1", instances(2));
+
+  let stale = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&stale, "ec2"), vec!["10.0.0.1"]);
+
+  assert!(
+    wait_for(|| {
+      let cached = dir.run_json(OPS, &["--list"], &[("EC2_RS_FORCE_CACHE", "1")]);
+      group_hosts(&cached, "ec2").len() == 2
+    }),
+    "The cache was never refreshed in the background!"
+  );
+}
+
+#[test]
+fn only_one_background_refresh_runs_at_a_time() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", instances(1))
+    .delay(Duration::from_secs(2))
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 0\ncache_max_stale = 300", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list"], &[]);
+
+  for _ in 0..5 {
+    let stale = dir.run_json(OPS, &["--list"], &[]);
+    assert_eq!(group_hosts(&stale, "ec2"), vec!["10.0.0.1"]);
+  }
+
+  assert!(wait_for(|| stub.requests("DescribeInstances").len() >= 2));
+  // Give any other refreshers a chance to (wrongly) get going before counting.
+  thread::sleep(Duration::from_secs(3));
+  assert_eq!(stub.requests("DescribeInstances").len(), 2);
+}
+
+#[test]
+fn caches_past_max_stale_are_rescanned_up_front() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 0\ncache_max_stale = 0", "regions = [\"us-east-1\"]");
+
+  dir.run_json(OPS, &["--list"], &[]);
+  stub.replace_instances(OPS, "us-east-1", instances(2));
+
+  let fresh = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&fresh, "ec2"), vec!["10.0.0.1", "10.0.0.2"]);
+}
+
+fn stdout_lines(output: &std::process::Output) -> Vec<String> {
+  assert!(output.status.success(), "ec2-rs failed: {}", String::from_utf8_lossy(&output.stderr));
+  String::from_utf8_lossy(&output.stdout).lines().map(|line| line.to_owned()).collect()
+}
+
+/// Runs ec2-rs under `ulimit -n`, so it can only hold a handful of files open at once.
+fn run_with_open_file_limit(dir: &TestDir, limit: usize, roles: &str, args: &[&str]) -> Output {
+  let ec2_rs = dir.command(roles, args, &[]);
+  let mut command = Command::new("sh");
+  command
+    .arg("-c")
+    .arg(format!("ulimit -n {} && exec \"$0\" \"$@\"", limit))
+    .arg(ec2_rs.get_program())
+    .args(ec2_rs.get_args())
+    .env_clear();
+  for (key, value) in ec2_rs.get_envs() {
+    if let Some(value) = value {
+      command.env(key, value);
+    }
+  }
+  command.output().expect("Failed to run ec2-rs!")
+}
+
+#[test]
+fn more_entries_than_open_files_can_be_read() {
+  let stub = StubAws::builder().instances(OPS, "region-000", instances(1)).start();
+  let regions: Vec<String> = (0..100).map(|idx| format!("\"region-{:03}\"", idx)).collect();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", &format!("regions = [{}]", regions.join(", ")));
+  dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(stub.requests("DescribeInstances").len(), 100);
+
+  let output = run_with_open_file_limit(&dir, 64, OPS, &["--list"]);
+  let lines = stdout_lines(&output);
+  let inventory: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  // Every region came out of the cache.
+  assert_eq!(stub.requests("DescribeInstances").len(), 100);
+
+  let listed = stdout_lines(&run_with_open_file_limit(&dir, 64, "", &["cache", "list"]));
+  assert_eq!(listed.len(), 101);
+}
+
+#[test]
+fn entries_replaced_after_their_header_was_read_arent_loaded() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list"], &[]);
+  let entries = Cache::new(&dir.config()).unwrap().list_entries().unwrap();
+  assert_eq!(entries.len(), 1);
+
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]\nall_instances = true");
+  dir.run_json(OPS, &["--list"], &[]);
+
+  let err = entries.into_iter().next().unwrap().load_inventory().err().unwrap();
+  assert!(err.to_string().contains("replaced"));
+}
+
+#[test]
+fn aliases_share_the_cache_of_their_role() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("cache_max_age = 300\n\n[aliases.roles]\nops = \"{}\"", OPS),
+    "regions = [\"us-east-1\"]",
+  );
+
+  dir.run_json("ops", &["--list"], &[]);
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  assert_eq!(stub.requests("DescribeInstances").len(), 1);
+
+  let listed = stdout_lines(&dir.run("", &["cache", "list"], &[]));
+  assert_eq!(listed.len(), 2);
+  assert!(listed[1].starts_with(OPS));
+  let purged = stdout_lines(&dir.run("", &["cache", "purge", "ops"], &[]));
+  assert_eq!(purged, vec!["Purged 1 cache entry(s)."]);
+}
+
+#[test]
+fn current_account_is_cached_by_its_account_id() {
+  let stub = StubAws::builder()
+    .instances("base-key", "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .instances("other-key", "us-east-1", vec![StubInstance::vpc("i-0000000002", "us-east-1a", "10.1.0.1")])
+    .caller_account("base-key", "111111111111")
+    .caller_account("other-key", "222222222222")
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+
+  // Both sets of base credentials run as `current`, but belong to different accounts.
+  let inventory = dir.run_json("current", &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  let inventory = dir.run_json("current", &["--list"], &[("AWS_ACCESS_KEY_ID", "other-key")]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.1.0.1"]);
+  let inventory = dir.run_json("current", &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  assert_eq!(stub.requests("DescribeInstances").len(), 2);
+
+  let listed = stdout_lines(&dir.run("", &["cache", "list"], &[]));
+  assert_eq!(listed.len(), 3);
+  assert!(listed[1].starts_with("111111111111"));
+  assert!(listed[2].starts_with("222222222222"));
+  let purged = stdout_lines(&dir.run("", &["cache", "purge", "current"], &[("AWS_ACCESS_KEY_ID", "other-key")]));
+  assert_eq!(purged, vec!["Purged 1 cache entry(s)."]);
+}
+
+#[test]
+fn cache_list_shows_every_entry() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", instances(3))
+    .instances(DEV, "us-east-1", instances(1))
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\", \"us-west-2\"]");
+  dir.run_json(&format!("{},{}", OPS, DEV), &["--list"], &[]);
+
+  let lines = stdout_lines(&dir.run("", &["cache", "list"], &[]));
+
+  let rows: Vec<Vec<&str>> = lines.iter().map(|line| line.split_whitespace().collect()).collect();
+  assert_eq!(rows[0], vec!["ROLE", "REGION", "AGE", "HOSTS", "STATUS"]);
+  assert_eq!(rows.len(), 5);
+  assert_eq!(rows[1][..2], [OPS, "us-east-1"]);
+  assert_eq!(rows[1][3..], ["3", "valid"]);
+  assert_eq!(rows[2][..2], [OPS, "us-west-2"]);
+  assert_eq!(rows[2][3..], ["0", "valid"]);
+  assert_eq!(rows[3][..2], [DEV, "us-east-1"]);
+  assert_eq!(rows[3][3..], ["1", "valid"]);
+
+  dir.write_config(&stub, "cache_max_age = 0", "regions = [\"us-east-1\", \"us-west-2\"]");
+  let lines = stdout_lines(&dir.run("", &["cache", "list"], &[]));
+  assert!(lines[1..].iter().all(|line| line.ends_with("expired")));
+
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]\nall_instances = true");
+  let lines = stdout_lines(&dir.run("", &["cache", "list"], &[]));
+  assert!(lines[1..].iter().all(|line| line.ends_with("outdated")));
+}
+
+#[test]
+fn cache_purge_removes_entries() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", instances(1))
+    .instances(DEV, "us-east-1", instances(1))
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\", \"us-west-2\"]");
+  dir.run_json(&format!("{},{}", OPS, DEV), &["--list"], &[]);
+
+  let purged = stdout_lines(&dir.run("", &["cache", "purge", OPS, "--region", "us-west-2"], &[]));
+  assert_eq!(purged, vec!["Purged 1 cache entry(s)."]);
+  assert_eq!(stdout_lines(&dir.run("", &["cache", "list"], &[])).len(), 4);
+
+  let purged = stdout_lines(&dir.run("", &["cache", "purge", DEV], &[]));
+  assert_eq!(purged, vec!["Purged 2 cache entry(s)."]);
+  assert_eq!(stdout_lines(&dir.run("", &["cache", "list"], &[])).len(), 2);
+
+  stdout_lines(&dir.run("", &["cache", "purge", "--all"], &[]));
+  assert_eq!(stdout_lines(&dir.run("", &["cache", "list"], &[])).len(), 1);
+
+  assert!(!dir.run("", &["cache", "purge"], &[]).status.success());
+}
+
+#[test]
+fn cache_warm_scans_without_printing_the_inventory() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", instances(2))
+    .instances(DEV, "us-east-1", instances(1))
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+
+  let warmed = stdout_lines(&dir.run(OPS, &["cache", "warm"], &[]));
+  assert_eq!(warmed, vec!["The cache is warm for 1 role(s) in 1 region(s)."]);
+  stdout_lines(&dir.run("", &["cache", "warm", "--roles", &format!("{},{}", OPS, DEV)], &[]));
+  // Only the DEV account was missing the second time around.
+  assert_eq!(stub.requests("DescribeInstances").len(), 2);
+
+  dir.run_json(&format!("{},{}", DEV, OPS), &["--list"], &[]);
+  assert_eq!(stub.requests("DescribeInstances").len(), 2);
+}
+
+#[test]
+fn cache_warm_counts_what_it_scanned() {
+  let stub = StubAws::builder()
+    .regions(OPS, &[("us-east-1", "opt-in-not-required"), ("eu-west-1", "opt-in-not-required")])
+    .instances(OPS, "us-east-1", instances(1))
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 0", "regions = \"all\"");
+
+  let warmed = stdout_lines(&dir.run(OPS, &["cache", "warm"], &[]));
+  assert_eq!(warmed, vec!["The cache is warm for 1 role(s) in 2 region(s)."]);
+  // Counting doesn't look the regions up a second time.
+  assert_eq!(stub.requests("DescribeRegions").len(), 1);
+}
+
+#[test]
+fn cache_warm_reports_what_it_couldnt_warm() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", instances(1))
+    .instances(OPS, "eu-west-1", instances(1))
+    .failing(OPS, "us-west-2")
+    .failing_databases(OPS, "us-east-1")
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    "cache_max_age = 300\nfailure_policy = \"skip_and_warn\"\n\n[rds]\nenabled = true",
+    "regions = [\"us-east-1\", \"us-west-2\", \"eu-west-1\"]",
+  );
+
+  let warmed = stdout_lines(&dir.run(OPS, &["cache", "warm"], &[]));
+
+  // us-east-1 scanned, but isn't cached without its RDS instances.
+  assert_eq!(warmed[0], "The cache is warm for 1 role(s) in 1 region(s).");
+  assert_eq!(warmed[1], "Couldn't warm 2 account/region pair(s):");
+  let mut failed = warmed[2..].to_vec();
+  failed.sort();
+  assert_eq!(failed.len(), 2);
+  assert!(failed[0].starts_with(&format!("  [{}] rds:us-east-1: ", OPS)), "unexpected failure: {}", failed[0]);
+  assert!(failed[1].starts_with(&format!("  [{}] us-west-2: ", OPS)), "unexpected failure: {}", failed[1]);
+  assert_eq!(files_under(&dir.path.join("cache")).iter().filter(|file| !file.ends_with(".lock")).count(), 1);
+}
+
+#[test]
+fn cache_warm_doesnt_load_valid_entries() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list"], &[]);
+
+  // A body that can't be loaded behind a valid header only breaks whoever loads it.
+  let entry = files_under(&dir.path.join("cache"))
+    .into_iter()
+    .find(|file| file.ends_with("us-east-1"))
+    .unwrap();
+  let (header, _) = read_entry(&dir, "us-east-1");
+  fs::write(&entry, format!("{}\nnot an inventory", header)).unwrap();
+
+  let warmed = stdout_lines(&dir.run(OPS, &["cache", "warm"], &[]));
+
+  assert_eq!(warmed, vec!["The cache is warm for 1 role(s) in 1 region(s)."]);
+  assert_eq!(stub.requests("DescribeInstances").len(), 1);
+}
+
+/// Splits the cache entry of a role, and region into its header line, and body.
+fn read_entry(dir: &TestDir, region: &str) -> (String, Vec<u8>) {
+  let entries: Vec<String> = files_under(&dir.path.join("cache"))
+    .into_iter()
+    .filter(|file| file.ends_with(region))
+    .collect();
+  assert_eq!(entries.len(), 1);
+  let contents = fs::read(&entries[0]).unwrap();
+  let newline = contents.iter().position(|byte| *byte == b'\n').unwrap();
+  (
+    String::from_utf8(contents[..newline].to_vec()).unwrap(),
+    contents[newline + 1..].to_vec(),
+  )
+}
+
+#[test]
+fn compressed_caches_serve_the_same_inventory() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(20)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+  let plain = dir.run_json(OPS, &["--list"], &[]);
+  let (_, plain_body) = read_entry(&dir, "us-east-1");
+
+  for (compression, magic) in &[("gzip", vec![0x1f, 0x8b]), ("zstd", vec![0x28, 0xb5, 0x2f, 0xfd])] {
+    let config = format!("cache_max_age = 300\ncache_compression = \"{}\"", compression);
+    dir.write_config(&stub, &config, "regions = [\"us-east-1\"]");
+    let scanned = dir.run_json(OPS, &["--list", "--refresh-cache"], &[]);
+    let cached = dir.run_json(OPS, &["--list"], &[]);
+
+    let (header, body) = read_entry(&dir, "us-east-1");
+    assert!(header.contains(&format!("\"compression\":\"{}\"", compression)));
+    assert!(header.contains("\"host_count\":20"));
+    assert!(body.starts_with(magic), "{} cache isn't compressed!", compression);
+    assert!(body.len() < plain_body.len());
+    assert_eq!(scanned, plain);
+    assert_eq!(cached, plain);
+  }
+  assert_eq!(stub.requests("DescribeInstances").len(), 3);
+
+  // Entries written with another compression are still read.
+  dir.write_config(&stub, "cache_max_age = 300", "regions = [\"us-east-1\"]");
+  assert_eq!(dir.run_json(OPS, &["--list"], &[]), plain);
+  assert_eq!(stub.requests("DescribeInstances").len(), 3);
+}
+
+#[test]
+fn expired_entries_are_never_read_past_the_header() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 0", "regions = [\"us-east-1\"]");
+  dir.run_json(OPS, &["--list"], &[]);
+
+  let entries: Vec<String> = files_under(&dir.path.join("cache"))
+    .into_iter()
+    .filter(|file| file.ends_with("us-east-1"))
+    .collect();
+  let (header, _) = read_entry(&dir, "us-east-1");
+  fs::write(&entries[0], format!("{}\nthis is not json", header)).unwrap();
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  let listed = stdout_lines(&dir.run("", &["cache", "list"], &[]));
+  assert!(listed[1].split_whitespace().any(|column| column == "1"));
+}
+
+#[test]
+fn unusable_cache_path_is_reported() {
+  let stub = StubAws::builder().instances(OPS, "us-east-1", instances(1)).start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+  let config = fs::read_to_string(dir.path.join("ec2-ini.toml")).unwrap();
+  let missing = dir.path.join("missing").join("parent").join("cache");
+  let config = config.replace(
+    &format!("cache_path = \"{}\"", dir.path.join("cache").display()),
+    &format!("cache_path = \"{}\"", missing.display()),
+  );
+  fs::write(dir.path.join("ec2-ini.toml"), config).unwrap();
+
+  let output = dir.run(OPS, &["--list"], &[]);
+
+  assert!(!output.status.success());
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(!stderr.contains("panicked"), "ec2-rs panicked: {}", stderr);
+  assert!(!stderr.is_empty());
+}
@@ -0,0 +1,1069 @@
+//! A tiny stand-in for the EC2, STS, and RDS query APIs, the Route53 REST API, and the Organizations JSON API, along
+//! with helpers for running the `ec2-rs` binary against it. Only what ec2-rs actually calls is implemented.
+
+#![allow(dead_code)]
+
+use ec2_rs_lib::config::{parse_configuration, Configuration};
+use serde_json::{json, Value as JsonValue};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The role of the `ops` account most tests scan.
+pub const OPS: &str = "arn:aws:iam::111111111111:role/ops";
+/// The role of the `dev` account most tests scan alongside `OPS`.
+pub const DEV: &str = "arn:aws:iam::222222222222:role/ops";
+
+/// An instance to hand back from DescribeInstances.
+#[derive(Clone)]
+pub struct StubInstance {
+  pub instance_id: String,
+  pub availability_zone: String,
+  pub private_ip_address: String,
+  pub private_dns_name: String,
+  pub instance_type: String,
+  pub key_name: String,
+  pub vpc_id: Option<String>,
+  pub subnet_id: Option<String>,
+  pub state_code: i64,
+  pub state_name: String,
+  pub security_groups: Vec<(String, String)>,
+  pub tags: Vec<(String, String)>,
+}
+
+impl StubInstance {
+  /// A running instance inside of a vpc.
+  pub fn vpc(instance_id: &str, availability_zone: &str, private_ip_address: &str) -> Self {
+    StubInstance {
+      instance_id: instance_id.to_owned(),
+      availability_zone: availability_zone.to_owned(),
+      private_ip_address: private_ip_address.to_owned(),
+      private_dns_name: format!("ip-{}.ec2.internal", private_ip_address.replace('.', "-")),
+      instance_type: "m5.large".to_owned(),
+      key_name: "ops".to_owned(),
+      vpc_id: Some("vpc-0a0a0a0a".to_owned()),
+      subnet_id: Some("subnet-0b0b0b0b".to_owned()),
+      state_code: 16,
+      state_name: "running".to_owned(),
+      security_groups: vec![("sg-0c0c0c0c".to_owned(), "web".to_owned())],
+      tags: Vec::new(),
+    }
+  }
+
+  /// A running EC2-Classic instance, which has no vpc, or subnet.
+  pub fn classic(instance_id: &str, availability_zone: &str, private_ip_address: &str) -> Self {
+    let mut instance = Self::vpc(instance_id, availability_zone, private_ip_address);
+    instance.vpc_id = None;
+    instance.subnet_id = None;
+    instance
+  }
+
+  pub fn tag(mut self, key: &str, value: &str) -> Self {
+    self.tags.push((key.to_owned(), value.to_owned()));
+    self
+  }
+
+  pub fn state(mut self, code: i64, name: &str) -> Self {
+    self.state_code = code;
+    self.state_name = name.to_owned();
+    self
+  }
+
+  fn to_xml(&self) -> String {
+    let mut xml = format!(
+      "<item><instanceId>{}</instanceId><imageId>ami-0d0d0d0d</imageId>\
+       <instanceState><code>{}</code><name>{}</name></instanceState>\
+       <privateDnsName>{}</privateDnsName><keyName>{}</keyName><instanceType>{}</instanceType>\
+       <placement><availabilityZone>{}</availabilityZone></placement>\
+       <privateIpAddress>{}</privateIpAddress>",
+      self.instance_id,
+      self.state_code,
+      self.state_name,
+      self.private_dns_name,
+      self.key_name,
+      self.instance_type,
+      self.availability_zone,
+      self.private_ip_address
+    );
+    if let Some(ref subnet_id) = self.subnet_id {
+      xml.push_str(&format!("<subnetId>{}</subnetId>", subnet_id));
+    }
+    if let Some(ref vpc_id) = self.vpc_id {
+      xml.push_str(&format!("<vpcId>{}</vpcId>", vpc_id));
+    }
+    xml.push_str("<groupSet>");
+    for (id, name) in &self.security_groups {
+      xml.push_str(&format!("<item><groupId>{}</groupId><groupName>{}</groupName></item>", id, name));
+    }
+    xml.push_str("</groupSet><tagSet>");
+    for (key, value) in &self.tags {
+      xml.push_str(&format!("<item><key>{}</key><value>{}</value></item>", key, value));
+    }
+    xml.push_str("</tagSet></item>");
+    xml
+  }
+}
+
+/// A DB instance to hand back from DescribeDBInstances.
+#[derive(Clone)]
+pub struct StubDatabase {
+  pub identifier: String,
+  pub availability_zone: String,
+  pub engine: String,
+  pub instance_class: String,
+  pub status: String,
+  pub tags: Vec<(String, String)>,
+}
+
+impl StubDatabase {
+  /// An available postgres instance.
+  pub fn new(identifier: &str, availability_zone: &str) -> Self {
+    StubDatabase {
+      identifier: identifier.to_owned(),
+      availability_zone: availability_zone.to_owned(),
+      engine: "postgres".to_owned(),
+      instance_class: "db.t3.micro".to_owned(),
+      status: "available".to_owned(),
+      tags: Vec::new(),
+    }
+  }
+
+  pub fn tag(mut self, key: &str, value: &str) -> Self {
+    self.tags.push((key.to_owned(), value.to_owned()));
+    self
+  }
+
+  pub fn status(mut self, status: &str) -> Self {
+    self.status = status.to_owned();
+    self
+  }
+
+  fn region(&self) -> &str {
+    &self.availability_zone[..self.availability_zone.len() - 1]
+  }
+
+  /// The endpoint address the instance is named by.
+  pub fn address(&self) -> String {
+    format!("{}.stub.{}.rds.amazonaws.com", self.identifier, self.region())
+  }
+
+  fn arn(&self) -> String {
+    format!("arn:aws:rds:{}:000000000000:db:{}", self.region(), self.identifier)
+  }
+
+  fn to_xml(&self) -> String {
+    format!(
+      "<DBInstance><DBInstanceIdentifier>{id}</DBInstanceIdentifier><DBInstanceArn>{arn}</DBInstanceArn>\
+       <DBInstanceClass>{class}</DBInstanceClass><Engine>{engine}</Engine><EngineVersion>11.1</EngineVersion>\
+       <DBInstanceStatus>{status}</DBInstanceStatus><AvailabilityZone>{az}</AvailabilityZone>\
+       <Endpoint><Address>{address}</Address><Port>5432</Port></Endpoint>\
+       <DBSubnetGroup><VpcId>vpc-0a0a0a0a</VpcId></DBSubnetGroup>\
+       <DBParameterGroups><DBParameterGroup><DBParameterGroupName>default.postgres11</DBParameterGroupName>\
+       </DBParameterGroup></DBParameterGroups></DBInstance>",
+      id = self.identifier,
+      arn = self.arn(),
+      class = self.instance_class,
+      engine = self.engine,
+      status = self.status,
+      az = self.availability_zone,
+      address = self.address()
+    )
+  }
+}
+
+/// An account to hand back from Organizations.
+#[derive(Clone)]
+pub struct StubAccount {
+  pub id: String,
+  /// The root, or organizational unit the account is in.
+  pub parent: String,
+  pub status: String,
+  pub tags: Vec<(String, String)>,
+}
+
+impl StubAccount {
+  /// An active account.
+  pub fn new(id: &str, parent: &str) -> Self {
+    StubAccount {
+      id: id.to_owned(),
+      parent: parent.to_owned(),
+      status: "ACTIVE".to_owned(),
+      tags: Vec::new(),
+    }
+  }
+
+  pub fn tag(mut self, key: &str, value: &str) -> Self {
+    self.tags.push((key.to_owned(), value.to_owned()));
+    self
+  }
+
+  pub fn suspended(mut self) -> Self {
+    self.status = "SUSPENDED".to_owned();
+    self
+  }
+
+  fn to_json(&self) -> JsonValue {
+    json!({
+      "Id": self.id,
+      "Arn": format!("arn:aws:organizations::000000000000:account/o-stub/{}", self.id),
+      "Name": self.id,
+      "Status": self.status,
+    })
+  }
+}
+
+/// A request the stub received.
+#[derive(Clone, Debug)]
+pub struct StubRequest {
+  pub action: String,
+  pub region: String,
+  /// The access key the request was signed with.
+  pub access_key: String,
+  pub role_arn: Option<String>,
+  pub params: Vec<(String, String)>,
+}
+
+impl StubRequest {
+  pub fn param(&self, name: &str) -> Option<&str> {
+    self
+      .params
+      .iter()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value.as_str())
+  }
+}
+
+/// A Route53 record as (name, type, value).
+type StubRecord = (String, String, String);
+
+#[derive(Default)]
+struct StubState {
+  /// Pages of instances, keyed by (role arn, region).
+  pages: HashMap<(String, String), Vec<Vec<StubInstance>>>,
+  /// (role arn, region) pairs that always fail.
+  failing: Vec<(String, String)>,
+  /// The regions of each role arn as (name, opt-in status), for DescribeRegions.
+  regions: HashMap<String, Vec<(String, String)>>,
+  /// How long to wait before answering DescribeInstances.
+  delay: Duration,
+  /// Every role we've handed out credentials for, the index is used as the access key.
+  roles: Vec<String>,
+  /// The accounts in the organization.
+  accounts: Vec<StubAccount>,
+  /// The organizational units in the organization, as (id, parent).
+  organizational_units: Vec<(String, String)>,
+  /// DB instances, keyed by (role arn, region).
+  databases: HashMap<(String, String), Vec<StubDatabase>>,
+  /// (role arn, region) pairs whose DescribeDBInstances calls always fail.
+  failing_databases: Vec<(String, String)>,
+  /// The hosted zones of each role arn, as (zone name, records).
+  hosted_zones: HashMap<String, Vec<(String, Vec<StubRecord>)>>,
+  /// Role arns whose Route53 calls always fail.
+  failing_route53: Vec<String>,
+  /// Every (MFA serial, code) STS has accepted, like STS a code is only accepted once.
+  used_token_codes: Vec<(String, String)>,
+  /// The account ids GetCallerIdentity answers with, keyed by base access key.
+  caller_accounts: HashMap<String, String>,
+  requests: Vec<StubRequest>,
+}
+
+/// Builds up what the stub should answer with, before starting it.
+#[derive(Default)]
+pub struct StubAwsBuilder {
+  state: StubState,
+}
+
+impl StubAwsBuilder {
+  /// Serves a single page of instances for a role, and region.
+  pub fn instances(self, role_arn: &str, region: &str, instances: Vec<StubInstance>) -> Self {
+    self.pages(role_arn, region, vec![instances])
+  }
+
+  /// Serves several pages of instances for a role, and region, linked by `nextToken`.
+  pub fn pages(mut self, role_arn: &str, region: &str, pages: Vec<Vec<StubInstance>>) -> Self {
+    self.state.pages.insert((role_arn.to_owned(), region.to_owned()), pages);
+    self
+  }
+
+  /// Fails every DescribeInstances call for a role, and region.
+  pub fn failing(mut self, role_arn: &str, region: &str) -> Self {
+    self.state.failing.push((role_arn.to_owned(), region.to_owned()));
+    self
+  }
+
+  /// Serves the regions of a role from DescribeRegions, as (name, opt-in status). Every DescribeRegions
+  /// call for a role without any fails.
+  pub fn regions(mut self, role_arn: &str, regions: &[(&str, &str)]) -> Self {
+    let regions = regions
+      .iter()
+      .map(|(name, opt_in_status)| (name.to_string(), opt_in_status.to_string()))
+      .collect();
+    self.state.regions.insert(role_arn.to_owned(), regions);
+    self
+  }
+
+  /// Adds an account to the organization.
+  pub fn account(mut self, account: StubAccount) -> Self {
+    self.state.accounts.push(account);
+    self
+  }
+
+  /// Adds an organizational unit to the organization, under a root, or another unit.
+  pub fn organizational_unit(mut self, id: &str, parent: &str) -> Self {
+    self.state.organizational_units.push((id.to_owned(), parent.to_owned()));
+    self
+  }
+
+  /// Serves the DB instances of a role, and region from DescribeDBInstances.
+  pub fn databases(mut self, role_arn: &str, region: &str, databases: Vec<StubDatabase>) -> Self {
+    self.state.databases.insert((role_arn.to_owned(), region.to_owned()), databases);
+    self
+  }
+
+  /// Fails every DescribeDBInstances call for a role, and region.
+  pub fn failing_databases(mut self, role_arn: &str, region: &str) -> Self {
+    self.state.failing_databases.push((role_arn.to_owned(), region.to_owned()));
+    self
+  }
+
+  /// Adds a hosted zone to the Route53 of a role, with its records as (name, type, value).
+  pub fn hosted_zone(mut self, role_arn: &str, name: &str, records: &[(&str, &str, &str)]) -> Self {
+    let records = records
+      .iter()
+      .map(|(name, type_, value)| (name.to_string(), type_.to_string(), value.to_string()))
+      .collect();
+    self
+      .state
+      .hosted_zones
+      .entry(role_arn.to_owned())
+      .or_default()
+      .push((name.to_owned(), records));
+    self
+  }
+
+  /// Fails every Route53 call for a role.
+  pub fn failing_route53(mut self, role_arn: &str) -> Self {
+    self.state.failing_route53.push(role_arn.to_owned());
+    self
+  }
+
+  /// Answers GetCallerIdentity for a base access key with an account id, instead of `000000000000`.
+  pub fn caller_account(mut self, access_key: &str, account_id: &str) -> Self {
+    self.state.caller_accounts.insert(access_key.to_owned(), account_id.to_owned());
+    self
+  }
+
+  /// Waits before answering every DescribeInstances call, to simulate a slow scan.
+  pub fn delay(mut self, delay: Duration) -> Self {
+    self.state.delay = delay;
+    self
+  }
+
+  pub fn start(self) -> StubAws {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind stub server!");
+    let port = listener.local_addr().unwrap().port();
+    let state = Arc::new(Mutex::new(self.state));
+
+    let thread_state = state.clone();
+    thread::spawn(move || {
+      for stream in listener.incoming().flatten() {
+        let connection_state = thread_state.clone();
+        thread::spawn(move || handle_connection(stream, connection_state));
+      }
+    });
+
+    StubAws { port, state }
+  }
+}
+
+/// A running stub of the EC2, and STS APIs.
+pub struct StubAws {
+  port: u16,
+  state: Arc<Mutex<StubState>>,
+}
+
+impl StubAws {
+  pub fn builder() -> StubAwsBuilder {
+    StubAwsBuilder::default()
+  }
+
+  pub fn endpoint(&self) -> String {
+    format!("http://127.0.0.1:{}", self.port)
+  }
+
+  /// Replaces the instances served for a role, and region, while the stub is running.
+  pub fn replace_instances(&self, role_arn: &str, region: &str, instances: Vec<StubInstance>) {
+    let mut state = self.state.lock().unwrap();
+    state.pages.insert((role_arn.to_owned(), region.to_owned()), vec![instances]);
+  }
+
+  /// Fails every DescribeInstances call for a role, and region from now on.
+  pub fn start_failing(&self, role_arn: &str, region: &str) {
+    let mut state = self.state.lock().unwrap();
+    state.failing.push((role_arn.to_owned(), region.to_owned()));
+  }
+
+  /// The access key the stub treats as having assumed a role, for handing out credentials without STS.
+  pub fn access_key_for(&self, role_arn: &str) -> String {
+    let mut state = self.state.lock().unwrap();
+    let idx = match state.roles.iter().position(|known| known == role_arn) {
+      Some(idx) => idx,
+      None => {
+        state.roles.push(role_arn.to_owned());
+        state.roles.len() - 1
+      }
+    };
+    format!("STUBKEY{}", idx)
+  }
+
+  /// Every request with a given action, in the order they were received.
+  pub fn requests(&self, action: &str) -> Vec<StubRequest> {
+    let state = self.state.lock().unwrap();
+    state
+      .requests
+      .iter()
+      .filter(|request| request.action == action)
+      .cloned()
+      .collect()
+  }
+}
+
+fn handle_connection(stream: TcpStream, state: Arc<Mutex<StubState>>) {
+  let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone stream!"));
+  let mut writer = stream;
+
+  loop {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+      return;
+    }
+
+    let mut headers = HashMap::new();
+    loop {
+      let mut line = String::new();
+      if reader.read_line(&mut line).unwrap_or(0) == 0 {
+        return;
+      }
+      let line = line.trim_end();
+      if line.is_empty() {
+        break;
+      }
+      if let Some(idx) = line.find(':') {
+        headers.insert(line[..idx].trim().to_lowercase(), line[idx + 1..].trim().to_owned());
+      }
+    }
+
+    let length: usize = headers.get("content-length").and_then(|len| len.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    if reader.read_exact(&mut body).is_err() {
+      return;
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, response) = respond(&state, path, &headers, &String::from_utf8_lossy(&body));
+    let written = write!(
+      writer,
+      "HTTP/1.1 {}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\r\n{}",
+      status,
+      response.len(),
+      response
+    );
+    if written.is_err() {
+      return;
+    }
+  }
+}
+
+fn percent_decode(value: &str) -> String {
+  let bytes = value.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut idx = 0;
+  while idx < bytes.len() {
+    match bytes[idx] {
+      b'+' => decoded.push(b' '),
+      b'%' if idx + 2 < bytes.len() => {
+        let hex = std::str::from_utf8(&bytes[idx + 1..idx + 3]).unwrap();
+        decoded.push(u8::from_str_radix(hex, 16).unwrap());
+        idx += 2;
+      }
+      other => decoded.push(other),
+    }
+    idx += 1;
+  }
+  String::from_utf8(decoded).unwrap()
+}
+
+fn parse_json_params(body: &str) -> Vec<(String, String)> {
+  let parsed: JsonValue = serde_json::from_str(body).unwrap_or_default();
+  parsed
+    .as_object()
+    .map(|params| {
+      params
+        .iter()
+        .map(|(key, value)| (key.to_owned(), value.as_str().map(|value| value.to_owned()).unwrap_or_default()))
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Gets every value of the query API filter with a name, e.g. `Filter.1.Value.1`.
+fn filter_values<'a>(params: &'a [(String, String)], name: &str) -> Vec<&'a str> {
+  params
+    .iter()
+    .filter(|(key, value)| key.starts_with("Filter.") && key.ends_with(".Name") && value == name)
+    .flat_map(|(key, _)| {
+      let prefix = format!("{}.Value.", key.trim_end_matches(".Name"));
+      params
+        .iter()
+        .filter(move |(value_key, _)| value_key.starts_with(&prefix))
+        .map(|(_, value)| value.as_str())
+    })
+    .collect()
+}
+
+/// Pages a list two at a time by `NextToken`, like Organizations does (with a bigger page).
+fn page_json(items: Vec<JsonValue>, request: &StubRequest, key: &str) -> (&'static str, String) {
+  let page_idx: usize = request
+    .param("NextToken")
+    .and_then(|token| token.trim_start_matches("page-").parse().ok())
+    .unwrap_or(0);
+  let page: Vec<JsonValue> = items.iter().skip(page_idx * 2).take(2).cloned().collect();
+  let mut response = json!({ key: page });
+  if (page_idx + 1) * 2 < items.len() {
+    response["NextToken"] = json!(format!("page-{}", page_idx + 1));
+  }
+  ("200 OK", response.to_string())
+}
+
+fn parse_form(body: &str) -> Vec<(String, String)> {
+  body
+    .split('&')
+    .filter(|pair| !pair.is_empty())
+    .map(|pair| {
+      let mut split = pair.splitn(2, '=');
+      let key = percent_decode(split.next().unwrap_or(""));
+      let value = percent_decode(split.next().unwrap_or(""));
+      (key, value)
+    })
+    .collect()
+}
+
+/// Pulls the access key, and region out of a SigV4 `Authorization` header.
+fn parse_credential_scope(headers: &HashMap<String, String>) -> (String, String) {
+  let auth = headers.get("authorization").cloned().unwrap_or_default();
+  let scope = auth
+    .split("Credential=")
+    .nth(1)
+    .and_then(|rest| rest.split(',').next())
+    .unwrap_or("");
+  let mut parts = scope.split('/');
+  let access_key = parts.next().unwrap_or("").to_owned();
+  let region = parts.nth(1).unwrap_or("").to_owned();
+  (access_key, region)
+}
+
+fn respond(
+  shared_state: &Arc<Mutex<StubState>>,
+  path: &str,
+  headers: &HashMap<String, String>,
+  body: &str,
+) -> (&'static str, String) {
+  // JSON APIs (like Organizations) name the action in a header, and send their parameters as JSON.
+  let (action, params) = match headers.get("x-amz-target") {
+    Some(target) => (
+      target.rsplit('.').next().unwrap_or("").to_owned(),
+      parse_json_params(body),
+    ),
+    // REST APIs (like Route53) name the action by the path, and send their parameters in the query string.
+    None if path.starts_with("/2013-04-01/") => {
+      let mut split = path.splitn(2, '?');
+      let resource = split.next().unwrap_or("");
+      let mut params = parse_form(split.next().unwrap_or(""));
+      let action = if resource.ends_with("/rrset") {
+        let zone = resource.trim_start_matches("/2013-04-01/hostedzone/").trim_end_matches("/rrset");
+        params.push(("Id".to_owned(), percent_decode(zone)));
+        "ListResourceRecordSets"
+      } else {
+        "ListHostedZones"
+      };
+      (action.to_owned(), params)
+    }
+    None => {
+      let params = parse_form(body);
+      let action = params
+        .iter()
+        .find(|(key, _)| key == "Action")
+        .map(|(_, value)| value.to_owned())
+        .unwrap_or_default();
+      (action, params)
+    }
+  };
+  let (access_key, region) = parse_credential_scope(headers);
+
+  let mut state = shared_state.lock().unwrap();
+  let role_arn = access_key
+    .trim_start_matches("STUBKEY")
+    .parse::<usize>()
+    .ok()
+    .and_then(|idx| state.roles.get(idx).cloned());
+  let request = StubRequest {
+    action: action.clone(),
+    region: region.clone(),
+    access_key: access_key.clone(),
+    role_arn: role_arn.clone(),
+    params: params.clone(),
+  };
+  state.requests.push(request.clone());
+  let route53_failing = state.failing_route53.contains(role_arn.as_ref().unwrap_or(&access_key));
+
+  if let (Some(serial), Some(code)) = (request.param("SerialNumber"), request.param("TokenCode")) {
+    let token = (serial.to_owned(), code.to_owned());
+    if state.used_token_codes.contains(&token) {
+      return (
+        "403 Forbidden",
+        "<ErrorResponse><Error><Type>Sender</Type><Code>AccessDenied</Code>\
+         <Message>MultiFactorAuthentication failed, the code was already used</Message></Error>\
+         <RequestId>stub</RequestId></ErrorResponse>"
+          .to_owned(),
+      );
+    }
+    state.used_token_codes.push(token);
+  }
+
+  match action.as_str() {
+    "GetSessionToken" => (
+      "200 OK",
+      format!(
+        "<GetSessionTokenResponse xmlns=\"https://sts.amazonaws.com/doc/2011-06-15/\"><GetSessionTokenResult>\
+         <Credentials><AccessKeyId>{}-mfa</AccessKeyId><SecretAccessKey>secret</SecretAccessKey>\
+         <SessionToken>token</SessionToken><Expiration>2099-01-01T00:00:00Z</Expiration></Credentials>\
+         </GetSessionTokenResult><ResponseMetadata><RequestId>stub</RequestId></ResponseMetadata>\
+         </GetSessionTokenResponse>",
+        access_key
+      ),
+    ),
+    "AssumeRole" => {
+      let role = request.param("RoleArn").unwrap_or("").to_owned();
+      let idx = match state.roles.iter().position(|known| known == &role) {
+        Some(idx) => idx,
+        None => {
+          state.roles.push(role.clone());
+          state.roles.len() - 1
+        }
+      };
+      (
+        "200 OK",
+        format!(
+          "<AssumeRoleResponse xmlns=\"https://sts.amazonaws.com/doc/2011-06-15/\"><AssumeRoleResult>\
+           <Credentials><AccessKeyId>STUBKEY{}</AccessKeyId><SecretAccessKey>secret</SecretAccessKey>\
+           <SessionToken>token</SessionToken><Expiration>2099-01-01T00:00:00Z</Expiration></Credentials>\
+           <AssumedRoleUser><Arn>{}</Arn><AssumedRoleId>AROA:default</AssumedRoleId></AssumedRoleUser>\
+           </AssumeRoleResult><ResponseMetadata><RequestId>stub</RequestId></ResponseMetadata></AssumeRoleResponse>",
+          idx, role
+        ),
+      )
+    }
+    "GetCallerIdentity" => {
+      // Assumed roles belong to the account in their arn, and base credentials to whichever account they were given.
+      let (arn, account) = match role_arn {
+        Some(role_arn) => {
+          let account = role_arn.split(':').nth(4).unwrap_or("").to_owned();
+          (role_arn, account)
+        }
+        None => {
+          let account = state
+            .caller_accounts
+            .get(&access_key)
+            .cloned()
+            .unwrap_or_else(|| "000000000000".to_owned());
+          (format!("arn:aws:iam::{}:user/stub", account), account)
+        }
+      };
+      (
+        "200 OK",
+        format!(
+          "<GetCallerIdentityResponse xmlns=\"https://sts.amazonaws.com/doc/2011-06-15/\"><GetCallerIdentityResult>\
+           <Arn>{}</Arn><UserId>stub</UserId><Account>{}</Account></GetCallerIdentityResult>\
+           <ResponseMetadata><RequestId>stub</RequestId></ResponseMetadata></GetCallerIdentityResponse>",
+          arn, account
+        ),
+      )
+    }
+    "DescribeInstances" => {
+      if state.delay > Duration::from_secs(0) {
+        let delay = state.delay;
+        drop(state);
+        thread::sleep(delay);
+        state = shared_state.lock().unwrap();
+      }
+      // Requests that weren't signed with an assumed role are served by their access key instead.
+      let role = role_arn.unwrap_or(access_key);
+      if state.failing.contains(&(role.clone(), region.clone())) {
+        return (
+          "503 Service Unavailable",
+          "<Response><Errors><Error><Code>Unavailable</Code><Message>stubbed failure</Message></Error></Errors>\
+           <RequestID>stub</RequestID></Response>"
+            .to_owned(),
+        );
+      }
+
+      let pages = state.pages.get(&(role, region)).cloned().unwrap_or_default();
+      let page_idx: usize = request
+        .param("NextToken")
+        .and_then(|token| token.trim_start_matches("page-").parse().ok())
+        .unwrap_or(0);
+      let instance_ids = filter_values(&params, "instance-id");
+
+      let items: String = pages
+        .get(page_idx)
+        .cloned()
+        .unwrap_or_default()
+        .iter()
+        .filter(|instance| instance_ids.is_empty() || instance_ids.contains(&instance.instance_id.as_str()))
+        .map(|instance| instance.to_xml())
+        .collect();
+      let next_token = if page_idx + 1 < pages.len() {
+        format!("<nextToken>page-{}</nextToken>", page_idx + 1)
+      } else {
+        String::new()
+      };
+
+      (
+        "200 OK",
+        format!(
+          "<DescribeInstancesResponse xmlns=\"http://ec2.amazonaws.com/doc/2016-11-15/\">\
+           <requestId>stub</requestId><reservationSet><item><reservationId>r-0e0e0e0e</reservationId>\
+           <ownerId>000000000000</ownerId><instancesSet>{}</instancesSet></item></reservationSet>{}\
+           </DescribeInstancesResponse>",
+          items, next_token
+        ),
+      )
+    }
+    "DescribeRegions" => {
+      let role = role_arn.unwrap_or(access_key);
+      let regions = match state.regions.get(&role) {
+        Some(regions) if !state.failing.contains(&(role.clone(), region.clone())) => regions,
+        _ => {
+          return (
+            "503 Service Unavailable",
+            "<Response><Errors><Error><Code>Unavailable</Code><Message>stubbed failure</Message></Error></Errors>\
+             <RequestID>stub</RequestID></Response>"
+              .to_owned(),
+          )
+        }
+      };
+      // Like EC2, regions that haven't been opted into are left out unless they're asked for.
+      let opt_in_statuses = filter_values(&params, "opt-in-status");
+      let items: String = regions
+        .iter()
+        .filter(|(_, status)| {
+          if opt_in_statuses.is_empty() {
+            status != "not-opted-in"
+          } else {
+            opt_in_statuses.contains(&status.as_str())
+          }
+        })
+        .map(|(name, _)| {
+          format!(
+            "<item><regionName>{}</regionName><regionEndpoint>ec2.{}.amazonaws.com</regionEndpoint></item>",
+            name, name
+          )
+        })
+        .collect();
+      (
+        "200 OK",
+        format!(
+          "<DescribeRegionsResponse xmlns=\"http://ec2.amazonaws.com/doc/2016-11-15/\">\
+           <requestId>stub</requestId><regionInfo>{}</regionInfo></DescribeRegionsResponse>",
+          items
+        ),
+      )
+    }
+    "ListAccounts" => page_json(state.accounts.iter().map(|account| account.to_json()).collect(), &request, "Accounts"),
+    "ListAccountsForParent" => {
+      let parent = request.param("ParentId").unwrap_or("");
+      let accounts = state
+        .accounts
+        .iter()
+        .filter(|account| account.parent == parent)
+        .map(|account| account.to_json())
+        .collect();
+      page_json(accounts, &request, "Accounts")
+    }
+    "ListOrganizationalUnitsForParent" => {
+      let parent = request.param("ParentId").unwrap_or("");
+      let units = state
+        .organizational_units
+        .iter()
+        .filter(|(_, unit_parent)| unit_parent == parent)
+        .map(|(id, _)| json!({ "Id": id, "Name": id }))
+        .collect();
+      page_json(units, &request, "OrganizationalUnits")
+    }
+    "ListHostedZones" | "ListResourceRecordSets" if route53_failing => (
+      "503 Service Unavailable",
+      "<ErrorResponse><Error><Type>Receiver</Type><Code>ServiceUnavailable</Code><Message>stubbed failure</Message>\
+       </Error><RequestId>stub</RequestId></ErrorResponse>"
+        .to_owned(),
+    ),
+    "ListHostedZones" => {
+      let role = role_arn.unwrap_or(access_key);
+      let zones: String = state
+        .hosted_zones
+        .get(&role)
+        .iter()
+        .flat_map(|zones| zones.iter())
+        .enumerate()
+        .map(|(idx, (name, _))| {
+          format!(
+            "<HostedZone><Id>/hostedzone/Z{}</Id><Name>{}.</Name><CallerReference>stub</CallerReference>\
+             </HostedZone>",
+            idx, name
+          )
+        })
+        .collect();
+      (
+        "200 OK",
+        format!(
+          "<ListHostedZonesResponse xmlns=\"https://route53.amazonaws.com/doc/2013-04-01/\"><HostedZones>{}\
+           </HostedZones><IsTruncated>false</IsTruncated><MaxItems>100</MaxItems></ListHostedZonesResponse>",
+          zones
+        ),
+      )
+    }
+    "ListResourceRecordSets" => {
+      let role = role_arn.unwrap_or(access_key);
+      let zone_idx: Option<usize> = request
+        .param("Id")
+        .map(|id| id.trim_start_matches("/hostedzone/"))
+        .and_then(|id| id.trim_start_matches('Z').parse().ok());
+      let records: String = state
+        .hosted_zones
+        .get(&role)
+        .and_then(|zones| zone_idx.and_then(|idx| zones.get(idx)))
+        .iter()
+        .flat_map(|(_, records)| records.iter())
+        .map(|(name, type_, value)| {
+          format!(
+            "<ResourceRecordSet><Name>{}.</Name><Type>{}</Type><TTL>300</TTL><ResourceRecords>\
+             <ResourceRecord><Value>{}</Value></ResourceRecord></ResourceRecords></ResourceRecordSet>",
+            name, type_, value
+          )
+        })
+        .collect();
+      (
+        "200 OK",
+        format!(
+          "<ListResourceRecordSetsResponse xmlns=\"https://route53.amazonaws.com/doc/2013-04-01/\">\
+           <ResourceRecordSets>{}</ResourceRecordSets><IsTruncated>false</IsTruncated><MaxItems>100</MaxItems>\
+           </ListResourceRecordSetsResponse>",
+          records
+        ),
+      )
+    }
+    "DescribeDBInstances" => {
+      let role = role_arn.unwrap_or(access_key);
+      if state.failing_databases.contains(&(role.clone(), region.clone())) {
+        return (
+          "503 Service Unavailable",
+          "<ErrorResponse><Error><Type>Receiver</Type><Code>Unavailable</Code><Message>stubbed failure</Message>\
+           </Error><RequestId>stub</RequestId></ErrorResponse>"
+            .to_owned(),
+        );
+      }
+      let items: String = state
+        .databases
+        .get(&(role, region))
+        .iter()
+        .flat_map(|databases| databases.iter())
+        .map(|database| database.to_xml())
+        .collect();
+      (
+        "200 OK",
+        format!(
+          "<DescribeDBInstancesResponse xmlns=\"http://rds.amazonaws.com/doc/2014-10-31/\">\
+           <DescribeDBInstancesResult><DBInstances>{}</DBInstances></DescribeDBInstancesResult>\
+           <ResponseMetadata><RequestId>stub</RequestId></ResponseMetadata></DescribeDBInstancesResponse>",
+          items
+        ),
+      )
+    }
+    // RDS shares the action name with Organizations, but is a query API.
+    "ListTagsForResource" if !headers.contains_key("x-amz-target") => {
+      let resource = request.param("ResourceName").unwrap_or("");
+      let items: String = state
+        .databases
+        .values()
+        .flatten()
+        .filter(|database| database.arn() == resource)
+        .flat_map(|database| database.tags.iter())
+        .map(|(key, value)| format!("<Tag><Key>{}</Key><Value>{}</Value></Tag>", key, value))
+        .collect();
+      (
+        "200 OK",
+        format!(
+          "<ListTagsForResourceResponse xmlns=\"http://rds.amazonaws.com/doc/2014-10-31/\">\
+           <ListTagsForResourceResult><TagList>{}</TagList></ListTagsForResourceResult>\
+           <ResponseMetadata><RequestId>stub</RequestId></ResponseMetadata></ListTagsForResourceResponse>",
+          items
+        ),
+      )
+    }
+    "ListTagsForResource" => {
+      let resource = request.param("ResourceId").unwrap_or("");
+      let tags = state
+        .accounts
+        .iter()
+        .filter(|account| account.id == resource)
+        .flat_map(|account| account.tags.iter())
+        .map(|(key, value)| json!({ "Key": key, "Value": value }))
+        .collect();
+      page_json(tags, &request, "Tags")
+    }
+    _ => (
+      "400 Bad Request",
+      "<Response><Errors><Error><Code>InvalidAction</Code><Message>not stubbed</Message></Error></Errors>\
+       <RequestID>stub</RequestID></Response>"
+        .to_owned(),
+    ),
+  }
+}
+
+/// A scratch directory for a single run of ec2-rs, holding its config, cache, and home directory.
+pub struct TestDir {
+  pub path: PathBuf,
+}
+
+impl TestDir {
+  pub fn new() -> Self {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!(
+      "ec2-rs-test-{}-{}",
+      std::process::id(),
+      COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).expect("Failed to create test dir!");
+    TestDir { path }
+  }
+
+  /// Writes an `ec2-ini.toml` pointing EC2, STS, Route53, and RDS at the stub, with `extra` appended to the top level,
+  /// and `ec2` appended to the `[ec2]` section.
+  pub fn write_config(&self, stub: &StubAws, extra: &str, ec2: &str) {
+    let config = format!(
+      "cache_path = \"{cache}\"\nsts_endpoint = \"{endpoint}\"\nec2_endpoint = \"{endpoint}\"\n\
+       route53_endpoint = \"{endpoint}\"\nrds_endpoint = \"{endpoint}\"\n{extra}\n\n[ec2]\n{ec2}\n",
+      cache = self.path.join("cache").display(),
+      endpoint = stub.endpoint(),
+      extra = extra,
+      ec2 = ec2
+    );
+    fs::write(self.path.join("ec2-ini.toml"), config).expect("Failed to write config!");
+  }
+
+  /// Parses the `ec2-ini.toml` written by `write_config`, for using ec2-rs as a library.
+  pub fn config(&self) -> Configuration {
+    parse_configuration(&self.path.join("ec2-ini.toml")).expect("Failed to parse config!")
+  }
+
+  /// Builds a command running ec2-rs with the given roles, arguments, and extra environment variables.
+  pub fn command(&self, roles: &str, args: &[&str], envs: &[(&str, &str)]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ec2-rs"));
+    command
+      .args(args)
+      .env_clear()
+      .env("PATH", env::var("PATH").unwrap_or_default())
+      .env("HOME", &self.path)
+      .env("EC2_RS_PATH", &self.path)
+      .env("EC2_RS_ASSUME_ROLE", roles)
+      .env("AWS_ACCESS_KEY_ID", "base-key")
+      .env("AWS_SECRET_ACCESS_KEY", "base-secret");
+    for (key, value) in envs {
+      command.env(key, value);
+    }
+    command
+  }
+
+  /// Runs ec2-rs with the given roles, arguments, and extra environment variables.
+  pub fn run(&self, roles: &str, args: &[&str], envs: &[(&str, &str)]) -> Output {
+    self.command(roles, args, envs).output().expect("Failed to run ec2-rs!")
+  }
+
+  /// Runs ec2-rs, expecting it to succeed, and print JSON.
+  pub fn run_json(&self, roles: &str, args: &[&str], envs: &[(&str, &str)]) -> JsonValue {
+    let output = self.run(roles, args, envs);
+    assert!(
+      output.status.success(),
+      "ec2-rs failed: {}",
+      String::from_utf8_lossy(&output.stderr)
+    );
+    serde_json::from_slice(&output.stdout).expect("ec2-rs didn't print JSON!")
+  }
+}
+
+impl Drop for TestDir {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.path);
+  }
+}
+
+/// Gets the sorted hosts of a group, whether it's a plain list, or has `hosts`, and `children`.
+pub fn group_hosts(inventory: &JsonValue, group: &str) -> Vec<String> {
+  let group = &inventory[group];
+  let hosts = if group.is_array() { group } else { &group["hosts"] };
+  let mut hosts: Vec<String> = hosts
+    .as_array()
+    .map(|hosts| hosts.iter().filter_map(|host| host.as_str().map(|host| host.to_owned())).collect())
+    .unwrap_or_default();
+  hosts.sort();
+  hosts
+}
+
+/// Gets every role arn the stub was asked to assume, sorted.
+pub fn assumed_roles(stub: &StubAws) -> Vec<String> {
+  let mut roles: Vec<String> = stub
+    .requests("AssumeRole")
+    .iter()
+    .filter_map(|request| request.param("RoleArn").map(|role| role.to_owned()))
+    .collect();
+  roles.sort();
+  roles
+}
+
+/// A stub with one running instance in `us-east-1` of both `OPS` (10.0.0.1), and `DEV` (10.1.0.1).
+pub fn stub_with_two_accounts() -> StubAws {
+  StubAws::builder()
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .instances(DEV, "us-east-1", vec![StubInstance::vpc("i-0000000002", "us-east-1a", "10.1.0.1")])
+    .start()
+}
+
+/// A stub with `OPS` in `us-east-1` (a web, and a db host across two zones, plus a stopped instance), and `DEV` in
+/// `us-west-2` (a web host in EC2-Classic, named by its DNS name).
+pub fn stub_with_two_regions() -> StubAws {
+  StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![
+        StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1").tag("Role", "web"),
+        StubInstance::vpc("i-0000000002", "us-east-1b", "10.0.0.2").tag("Role", "db"),
+        StubInstance::vpc("i-0000000003", "us-east-1b", "10.0.0.3").state(80, "stopped"),
+      ],
+    )
+    .instances(
+      DEV,
+      "us-west-2",
+      vec![StubInstance::classic("i-0000000004", "us-west-2a", "10.1.0.1").tag("Role", "web")],
+    )
+    .start()
+}
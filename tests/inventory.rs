@@ -0,0 +1,414 @@
+//! End to end tests of `--list`, and `--host` against a stubbed out EC2, and STS.
+
+mod common;
+
+use common::{group_hosts, StubAws, StubInstance, TestDir, DEV, OPS};
+use serde_json::json;
+
+#[test]
+fn list_renders_groups_and_hostvars() {
+  let stub = StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![
+        StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1").tag("Role", "web"),
+        StubInstance::vpc("i-0000000002", "us-east-1b", "10.0.0.2").tag("Role", "db"),
+        StubInstance::vpc("i-0000000003", "us-east-1b", "10.0.0.3").state(80, "stopped"),
+      ],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(group_hosts(&inventory, "us-east-1"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(group_hosts(&inventory, "us-east-1a"), vec!["10.0.0.1"]);
+  assert_eq!(group_hosts(&inventory, "i-0000000002"), vec!["10.0.0.2"]);
+  assert_eq!(group_hosts(&inventory, "type_m5_large"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(group_hosts(&inventory, "key_ops"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(group_hosts(&inventory, "vpc_id_vpc-0a0a0a0a"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(group_hosts(&inventory, "security_group_web"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(group_hosts(&inventory, "tag_role_web"), vec!["10.0.0.1"]);
+  assert_eq!(group_hosts(&inventory, "tag_role_db"), vec!["10.0.0.2"]);
+  assert!(inventory.get("i-0000000003").is_none());
+
+  let hostvars = &inventory["_meta"]["hostvars"];
+  assert_eq!(hostvars.as_object().unwrap().len(), 2);
+  assert_eq!(hostvars["10.0.0.1"]["ec2_id"], json!("i-0000000001"));
+  assert_eq!(hostvars["10.0.0.1"]["ec2_account_value"], json!(OPS));
+  assert_eq!(hostvars["10.0.0.1"]["ec2_placement"], json!("us-east-1a"));
+  assert_eq!(hostvars["10.0.0.1"]["ec2_state"], json!("running"));
+  assert_eq!(hostvars["10.0.0.1"]["ec2_tag_role"], json!("web"));
+  assert_eq!(hostvars["10.0.0.2"]["ec2_vpc_id"], json!("vpc-0a0a0a0a"));
+}
+
+#[test]
+fn list_includes_stopped_instances_with_all_instances() {
+  let stub = StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![
+        StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1"),
+        StubInstance::vpc("i-0000000002", "us-east-1a", "10.0.0.2").state(80, "stopped"),
+      ],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]\nall_instances = true");
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(inventory["_meta"]["hostvars"]["10.0.0.2"]["ec2_state"], json!("stopped"));
+}
+
+#[test]
+fn host_looks_up_by_destination_variable_and_instance_id() {
+  let stub = StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![
+        StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1"),
+        StubInstance::vpc("i-0000000002", "us-east-1a", "10.0.0.2"),
+      ],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  let by_dest = dir.run_json(OPS, &["--host", "10.0.0.2"], &[]);
+  assert_eq!(by_dest["ec2_id"], json!("i-0000000002"));
+
+  let by_id = dir.run_json(OPS, &["--host", "i-0000000001"], &[]);
+  assert_eq!(by_id["ec2_id"], json!("i-0000000001"));
+
+  let missing = dir.run_json(OPS, &["--host", "10.9.9.9"], &[]);
+  assert_eq!(missing, json!({}));
+}
+
+#[test]
+fn ec2_hosts_filters_by_instance_id() {
+  let stub = StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![
+        StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1"),
+        StubInstance::vpc("i-0000000002", "us-east-1a", "10.0.0.2"),
+      ],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  let hosts = dir.run_json(OPS, &[], &[("EC2_HOSTS", "i-0000000002")]);
+
+  let hosts = hosts.as_array().expect("EC2_HOSTS should print a list of hostvars!");
+  assert_eq!(hosts.len(), 1);
+  assert_eq!(hosts[0]["ec2_id"], json!("i-0000000002"));
+
+  let request = &stub.requests("DescribeInstances")[0];
+  assert_eq!(request.param("Filter.1.Name"), Some("instance-id"));
+  assert_eq!(request.param("Filter.1.Value.1"), Some("i-0000000002"));
+}
+
+#[test]
+fn instance_filters_are_sent_to_ec2() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    "",
+    "regions = [\"us-east-1\"]\ninstance_filters = {\"tag:ansible\" = \"true\"}",
+  );
+
+  dir.run_json(OPS, &["--list"], &[]);
+
+  let requests = stub.requests("DescribeInstances");
+  assert_eq!(requests.len(), 1);
+  assert_eq!(requests[0].param("Filter.1.Name"), Some("tag:ansible"));
+  assert_eq!(requests[0].param("Filter.1.Value.1"), Some("true"));
+}
+
+#[test]
+fn include_and_exclude_filters_match_the_destination_variable() {
+  let stub = StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![
+        StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1"),
+        StubInstance::vpc("i-0000000002", "us-east-1a", "10.0.0.2"),
+        StubInstance::vpc("i-0000000003", "us-east-1a", "10.1.0.3"),
+      ],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    "",
+    "regions = [\"us-east-1\"]\ninclude_filter = \"^10\\\\.0\\\\.\"\nexclude_filter = \"\\\\.2$\"",
+  );
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  let hostvars = inventory["_meta"]["hostvars"].as_object().unwrap();
+  assert_eq!(hostvars.keys().collect::<Vec<_>>(), vec!["10.0.0.1"]);
+}
+
+#[test]
+fn multiple_accounts_are_merged() {
+  let stub = StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1").tag("Role", "web")],
+    )
+    .instances(
+      DEV,
+      "us-east-1",
+      vec![StubInstance::vpc("i-0000000002", "us-east-1a", "10.2.0.1").tag("Role", "web")],
+    )
+    .instances(
+      DEV,
+      "us-west-2",
+      vec![StubInstance::vpc("i-0000000003", "us-west-2a", "10.2.1.1")],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\", \"us-west-2\"]");
+
+  let inventory = dir.run_json(&format!("{},{}", OPS, DEV), &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.2.0.1", "10.2.1.1"]);
+  assert_eq!(group_hosts(&inventory, "tag_role_web"), vec!["10.0.0.1", "10.2.0.1"]);
+  assert_eq!(group_hosts(&inventory, "us-west-2"), vec!["10.2.1.1"]);
+
+  let hostvars = &inventory["_meta"]["hostvars"];
+  assert_eq!(hostvars["10.0.0.1"]["ec2_account_value"], json!(OPS));
+  assert_eq!(hostvars["10.2.0.1"]["ec2_account_value"], json!(DEV));
+  assert_eq!(hostvars["10.2.1.1"]["ec2_region"], json!("us-west-2"));
+
+  let mut assumed: Vec<String> = stub
+    .requests("AssumeRole")
+    .iter()
+    .filter_map(|request| request.param("RoleArn").map(|arn| arn.to_owned()))
+    .collect();
+  assumed.sort();
+  assumed.dedup();
+  assert_eq!(assumed, vec![OPS.to_owned(), DEV.to_owned()]);
+}
+
+#[test]
+fn vpc_and_classic_instances_use_their_own_destination_variable() {
+  let stub = StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![
+        StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1"),
+        StubInstance::classic("i-0000000002", "us-east-1a", "10.0.0.2"),
+      ],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "ip-10-0-0-2.ec2.internal"]);
+  assert!(inventory.get("vpc_id_vpc-0a0a0a0a").is_some());
+
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    "",
+    "regions = [\"us-east-1\"]\ndestination_variable = \"private_ip_address\"\n\
+     vpc_destination_variable = \"private_dns_name\"",
+  );
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.2", "ip-10-0-0-1.ec2.internal"]);
+}
+
+#[test]
+fn every_page_of_instances_is_followed() {
+  let stub = StubAws::builder()
+    .pages(
+      OPS,
+      "us-east-1",
+      vec![
+        vec![
+          StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1"),
+          StubInstance::vpc("i-0000000002", "us-east-1a", "10.0.0.2"),
+        ],
+        vec![StubInstance::vpc("i-0000000003", "us-east-1a", "10.0.0.3")],
+        vec![StubInstance::vpc("i-0000000004", "us-east-1a", "10.0.0.4")],
+      ],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]\nmax_results = 5");
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(
+    group_hosts(&inventory, "ec2"),
+    vec!["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4"]
+  );
+  let requests = stub.requests("DescribeInstances");
+  let tokens: Vec<Option<&str>> = requests.iter().map(|request| request.param("NextToken")).collect();
+  assert_eq!(tokens, vec![None, Some("page-1"), Some("page-2")]);
+  assert!(requests.iter().all(|request| request.param("MaxResults") == Some("5")));
+}
+
+#[test]
+fn failed_regions_follow_the_failure_policy() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .failing(OPS, "us-west-2")
+    .start();
+
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\", \"us-west-2\"]");
+  let output = dir.run(OPS, &["--list"], &[]);
+  assert!(!output.status.success());
+  assert!(String::from_utf8_lossy(&output.stderr).contains("us-west-2"));
+
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    "failure_policy = \"skip_and_warn\"\nfailures_in_meta = true",
+    "regions = [\"us-east-1\", \"us-west-2\"]",
+  );
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  let failures = inventory["_meta"]["failures"].as_array().unwrap();
+  assert_eq!(failures.len(), 1);
+  assert_eq!(failures[0]["account"], json!(OPS));
+  assert_eq!(failures[0]["region"], json!("us-west-2"));
+}
+
+#[test]
+fn current_account_is_scanned_without_assuming_a_role() {
+  let stub = StubAws::builder()
+    .instances("base-key", "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  let mut command = dir.command("", &["--list"], &[]);
+  command.env_remove("EC2_RS_ASSUME_ROLE");
+  let output = command.output().unwrap();
+
+  assert!(output.status.success(), "ec2-rs failed: {}", String::from_utf8_lossy(&output.stderr));
+  let inventory: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  assert!(stub.requests("AssumeRole").is_empty());
+}
+
+#[test]
+fn current_account_mixes_with_assumed_roles() {
+  let stub = StubAws::builder()
+    .instances("base-key", "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000002", "us-east-1a", "10.1.0.1")])
+    .caller_account("base-key", "123456789012")
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]\ngroup_by_aws_account = true");
+
+  let inventory = dir.run_json(&format!("current,{}", OPS), &["--list"], &[]);
+
+  // The current account is named by its account id, rather than `current`.
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1"]);
+  assert_eq!(group_hosts(&inventory, "account_123456789012"), vec!["10.0.0.1"]);
+  assert!(inventory.get("account_current").is_none());
+  assert_eq!(inventory["_meta"]["hostvars"]["10.0.0.1"]["ec2_account_value"], json!("123456789012"));
+  let assumed = stub.requests("AssumeRole");
+  assert_eq!(assumed.len(), 1);
+  assert_eq!(assumed[0].param("RoleArn"), Some(OPS));
+  assert_eq!(assumed[0].access_key, "base-key");
+}
+
+#[test]
+fn group_by_options_turn_their_groups_off() {
+  let stub = StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")
+        .tag("Role", "web")
+        .tag("Flags", "blue,canary")],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    "",
+    "regions = [\"us-east-1\"]\ngroup_by_instance_type = false\ngroup_by_key_pair = false\n\
+     group_by_tag_keys = false\ngroup_by_security_group = false\ngroup_by_ami_id = true\ngroup_by_subnet_id = true",
+  );
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  for removed in ["type_m5_large", "key_ops", "tag_role_web", "tag_flags_blue_canary", "security_group_web"] {
+    assert!(inventory.get(removed).is_none(), "{} should be turned off", removed);
+  }
+  for kept in [
+    "ec2",
+    "i-0000000001",
+    "us-east-1",
+    "us-east-1a",
+    "vpc_id_vpc-0a0a0a0a",
+    "ami-0d0d0d0d",
+    "subnet_id_subnet-0b0b0b0b",
+    "flag_blue",
+    "flag_canary",
+  ] {
+    assert_eq!(group_hosts(&inventory, kept), vec!["10.0.0.1"], "{} should be kept", kept);
+  }
+}
+
+#[test]
+fn nested_groups_nest_regions_and_tags() {
+  let stub = StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![
+        StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1").tag("Role", "web"),
+        StubInstance::vpc("i-0000000002", "us-east-1b", "10.0.0.2").tag("Role", "db"),
+      ],
+    )
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "nested_groups = true", "regions = [\"us-east-1\"]");
+
+  let nested = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(nested["regions"], json!({ "children": ["us-east-1"] }));
+  assert_eq!(nested["us-east-1"]["children"], json!(["us-east-1a", "us-east-1b"]));
+  assert_eq!(group_hosts(&nested, "us-east-1"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(nested["zones"], json!({ "children": ["us-east-1a", "us-east-1b"] }));
+  assert_eq!(nested["tags"], json!({ "children": ["tag_role"] }));
+  assert_eq!(nested["tag_role"], json!({ "children": ["tag_role_db", "tag_role_web"] }));
+  assert_eq!(nested["tag_role_web"], json!(["10.0.0.1"]));
+  assert_eq!(nested["instances"], json!({ "children": ["i-0000000001", "i-0000000002"] }));
+
+  dir.write_config(&stub, "nested_groups = false", "regions = [\"us-east-1\"]");
+  let flat = dir.run_json(OPS, &["--list"], &[]);
+
+  for parent in ["regions", "zones", "tags", "tag_role", "instances"] {
+    assert!(flat.get(parent).is_none(), "{} shouldn't be rendered without nesting", parent);
+  }
+  assert!(flat["us-east-1"].is_array());
+  assert_eq!(group_hosts(&flat, "us-east-1"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(flat["tag_role_web"], json!(["10.0.0.1"]));
+}
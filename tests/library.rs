@@ -0,0 +1,388 @@
+//! Tests of embedding ec2-rs as a library through `Inventory`, against a stubbed out EC2.
+
+mod common;
+
+use common::{stub_with_two_regions, StubAws, TestDir, DEV, OPS};
+use ec2_rs_lib::config::Configuration;
+use ec2_rs_lib::instance_source::MemoryInstanceSource;
+use ec2_rs_lib::inventory::{ClientFactory, CredentialsProvider, Inventory, RusotoClientFactory};
+use ec2_rs_lib::role_assumer::RoleAssumer;
+use failure::{err_msg, Error};
+use rusoto_credential::AwsCredentials;
+use rusoto_ec2::{Ec2Client, Instance, InstanceState, Placement, Tag};
+use rusoto_rds::RdsClient;
+use rusoto_route53::Route53Client;
+use serde_json::json;
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+
+/// Hands out the stub's credentials for each role, without going through STS.
+struct StubCredentials {
+  access_keys: HashMap<String, String>,
+}
+
+impl StubCredentials {
+  fn new(stub: &StubAws, roles: &[&str]) -> Self {
+    let access_keys = roles
+      .iter()
+      .map(|role| (role.to_string(), stub.access_key_for(role)))
+      .collect();
+    StubCredentials { access_keys }
+  }
+}
+
+impl CredentialsProvider for StubCredentials {
+  fn get_credentials(&self, _config: &Configuration, account: &str) -> Result<AwsCredentials, Error> {
+    let access_key = self
+      .access_keys
+      .get(account)
+      .ok_or_else(|| err_msg("no stubbed credentials"))?;
+    Ok(AwsCredentials::new(access_key.to_owned(), "secret", None, None))
+  }
+}
+
+/// Refuses to hand out any credentials, for making sure nothing talks to AWS.
+struct NoCredentials;
+
+impl CredentialsProvider for NoCredentials {
+  fn get_credentials(&self, _config: &Configuration, _account: &str) -> Result<AwsCredentials, Error> {
+    Err(err_msg("AWS shouldn't be needed"))
+  }
+}
+
+/// Creates rusoto clients, remembering which regions EC2 clients were created for.
+#[derive(Default)]
+struct RecordingClientFactory {
+  ec2_regions: Mutex<Vec<String>>,
+}
+
+impl ClientFactory for &'static RecordingClientFactory {
+  fn ec2_client(&self, config: &Configuration, credentials: &AwsCredentials, region: &str) -> Result<Ec2Client, Error> {
+    self.ec2_regions.lock().unwrap().push(region.to_owned());
+    RusotoClientFactory.ec2_client(config, credentials, region)
+  }
+
+  fn route53_client(&self, config: &Configuration, credentials: &AwsCredentials) -> Result<Route53Client, Error> {
+    RusotoClientFactory.route53_client(config, credentials)
+  }
+
+  fn rds_client(&self, config: &Configuration, credentials: &AwsCredentials, region: &str) -> Result<RdsClient, Error> {
+    RusotoClientFactory.rds_client(config, credentials, region)
+  }
+}
+
+#[test]
+fn hosts_are_typed() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\", \"us-west-2\"]");
+
+  let inventory = Inventory::builder()
+    .config(dir.config())
+    .roles(vec![OPS, DEV])
+    .credentials_provider(StubCredentials::new(&stub, &[OPS, DEV]))
+    .build()
+    .unwrap();
+  let hosts = inventory.hosts().unwrap();
+
+  let summary: Vec<(&str, &str, &str)> = hosts
+    .iter()
+    .map(|host| (host.name.as_str(), host.account.as_str(), host.region.as_str()))
+    .collect();
+  assert_eq!(
+    summary,
+    vec![
+      ("10.0.0.1", OPS, "us-east-1"),
+      ("10.0.0.2", OPS, "us-east-1"),
+      ("ip-10-1-0-1.ec2.internal", DEV, "us-west-2"),
+    ]
+  );
+  assert_eq!(hosts[0].vars["ec2_id"], json!("i-0000000001"));
+  assert_eq!(hosts[2].vars["ec2_tag_role"], json!("web"));
+}
+
+#[test]
+fn groups_are_typed() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "nested_groups = true", "regions = [\"us-east-1\", \"us-west-2\"]");
+
+  let inventory = Inventory::builder()
+    .config(dir.config())
+    .roles(vec![OPS, DEV])
+    .credentials_provider(StubCredentials::new(&stub, &[OPS, DEV]))
+    .build()
+    .unwrap();
+  let groups = inventory.groups().unwrap();
+  let group = |name: &str| groups.iter().find(|group| group.name == name).unwrap().clone();
+
+  assert_eq!(group("tag_role_web").hosts, vec!["10.0.0.1", "ip-10-1-0-1.ec2.internal"]);
+  assert_eq!(group("us-east-1").hosts, vec!["10.0.0.1", "10.0.0.2"]);
+  assert_eq!(group("us-east-1").children, vec!["us-east-1a", "us-east-1b"]);
+  assert_eq!(group("regions").children, vec!["us-east-1", "us-west-2"]);
+  assert!(group("regions").hosts.is_empty());
+  let names: Vec<&str> = groups.iter().map(|group| group.name.as_str()).collect();
+  let mut sorted = names.clone();
+  sorted.sort();
+  assert_eq!(names, sorted);
+}
+
+#[test]
+fn hosts_groups_and_json_share_one_scan() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\", \"us-west-2\"]");
+
+  let inventory = Inventory::builder()
+    .config(dir.config())
+    .roles(vec![OPS, DEV])
+    .credentials_provider(StubCredentials::new(&stub, &[OPS, DEV]))
+    .build()
+    .unwrap();
+  let hosts = inventory.hosts().unwrap();
+  let groups = inventory.groups().unwrap();
+  let rendered = inventory.to_json().unwrap();
+
+  assert_eq!(stub.requests("DescribeInstances").len(), 4);
+  assert_eq!(hosts.len(), 3);
+  let ec2 = groups.iter().find(|group| group.name == "ec2").unwrap();
+  assert_eq!(ec2.hosts.len(), hosts.len());
+  assert_eq!(rendered["_meta"]["hostvars"].as_object().unwrap().len(), hosts.len());
+
+  // Scanning by hand always scans again.
+  inventory.scan();
+  assert_eq!(stub.requests("DescribeInstances").len(), 8);
+}
+
+#[test]
+fn regions_override_the_configuration() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\", \"us-west-2\"]");
+  let factory: &'static RecordingClientFactory = Box::leak(Box::default());
+
+  let inventory = Inventory::builder()
+    .config(dir.config())
+    .roles(vec![DEV])
+    .regions(vec!["us-west-2"])
+    .credentials_provider(StubCredentials::new(&stub, &[DEV]))
+    .client_factory(factory)
+    .build()
+    .unwrap();
+  let hosts = inventory.hosts().unwrap();
+
+  assert_eq!(hosts.len(), 1);
+  assert_eq!(*factory.ec2_regions.lock().unwrap(), vec!["us-west-2"]);
+  let requests = stub.requests("DescribeInstances");
+  assert_eq!(requests.len(), 1);
+  assert_eq!(requests[0].region, "us-west-2");
+  assert!(stub.requests("AssumeRole").is_empty());
+}
+
+#[test]
+fn to_json_matches_the_binary() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\", \"us-west-2\"]");
+
+  let inventory = Inventory::builder()
+    .config(dir.config())
+    .roles(vec![OPS, DEV])
+    .credentials_provider(StubCredentials::new(&stub, &[OPS, DEV]))
+    .build()
+    .unwrap();
+  let mut from_library = inventory.to_json().unwrap();
+  let mut from_binary = dir.run_json(&format!("{},{}", OPS, DEV), &["--list"], &[]);
+
+  // Group members come back in whatever order the regions finished scanning in.
+  for rendered in [&mut from_library, &mut from_binary] {
+    for group in rendered.as_object_mut().unwrap().values_mut() {
+      if let Some(hosts) = group.as_array_mut() {
+        hosts.sort_by_key(|host| host.to_string());
+      }
+    }
+  }
+  assert_eq!(from_library, from_binary);
+}
+
+#[test]
+fn credential_failures_follow_the_failure_policy() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  let inventory = Inventory::builder()
+    .config(dir.config())
+    .roles(vec![OPS, DEV])
+    .credentials_provider(StubCredentials::new(&stub, &[OPS]))
+    .build()
+    .unwrap();
+
+  let err = inventory.hosts().unwrap_err().to_string();
+  assert!(err.contains(DEV), "unexpected error: {}", err);
+  assert!(err.contains("no stubbed credentials"), "unexpected error: {}", err);
+
+  let results = inventory.scan();
+  assert_eq!(results.hosts().len(), 2);
+  assert_eq!(results.failures.len(), 1);
+  assert_eq!(results.failures[0].account, DEV);
+}
+
+#[test]
+fn building_needs_a_configuration_and_roles() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  assert!(Inventory::builder().roles(vec![OPS]).build().is_err());
+  assert!(Inventory::builder().config(dir.config()).build().is_err());
+}
+
+fn memory_instance(instance_id: &str, availability_zone: &str, private_ip_address: &str) -> Instance {
+  Instance {
+    instance_id: Some(instance_id.to_owned()),
+    instance_type: Some("m5.large".to_owned()),
+    placement: Some(Placement {
+      availability_zone: Some(availability_zone.to_owned()),
+      ..Placement::default()
+    }),
+    private_ip_address: Some(private_ip_address.to_owned()),
+    state: Some(InstanceState {
+      code: Some(16),
+      name: Some("running".to_owned()),
+    }),
+    tags: Some(vec![Tag {
+      key: Some("Role".to_owned()),
+      value: Some("web".to_owned()),
+    }]),
+    subnet_id: Some("subnet-0b0b0b0b".to_owned()),
+    vpc_id: Some("vpc-0a0a0a0a".to_owned()),
+    ..Instance::default()
+  }
+}
+
+/// Writes a configuration that doesn't point anywhere, as a memory source needs no AWS.
+fn write_offline_config(dir: &TestDir) -> Configuration {
+  fs::write(
+    dir.path.join("ec2-ini.toml"),
+    format!(
+      "cache_path = \"{}\"\n\n[ec2]\nregions = [\"us-east-1\", \"us-west-2\"]\n",
+      dir.path.join("cache").display()
+    ),
+  )
+  .unwrap();
+  dir.config()
+}
+
+#[test]
+fn memory_instance_source_needs_no_aws() {
+  let dir = TestDir::new();
+  let source = MemoryInstanceSource::new()
+    .with_instances(OPS, "us-east-1", vec![memory_instance("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .with_instances(DEV, "us-west-2", vec![memory_instance("i-0000000002", "us-west-2a", "10.1.0.1")]);
+
+  let inventory = Inventory::builder()
+    .config(write_offline_config(&dir))
+    .roles(vec![OPS, DEV])
+    .credentials_provider(NoCredentials)
+    .instance_source(source)
+    .build()
+    .unwrap();
+  let results = inventory.scan();
+
+  assert!(results.failures.is_empty());
+  let hosts: Vec<(String, String)> = results.hosts().into_iter().map(|host| (host.name, host.account)).collect();
+  assert_eq!(
+    hosts,
+    vec![("10.0.0.1".to_owned(), OPS.to_owned()), ("10.1.0.1".to_owned(), DEV.to_owned())]
+  );
+  let groups = results.groups();
+  let web = groups.iter().find(|group| group.name == "tag_role_web").unwrap();
+  assert_eq!(web.hosts, vec!["10.0.0.1", "10.1.0.1"]);
+  assert_eq!(results.hosts()[0].vars["ec2_placement"], json!("us-east-1a"));
+}
+
+#[test]
+fn memory_instance_source_applies_instance_ids() {
+  let dir = TestDir::new();
+  let source = MemoryInstanceSource::new().with_instances(
+    OPS,
+    "us-east-1",
+    vec![
+      memory_instance("i-0000000001", "us-east-1a", "10.0.0.1"),
+      memory_instance("i-0000000002", "us-east-1a", "10.0.0.2"),
+    ],
+  );
+
+  let inventory = Inventory::builder()
+    .config(write_offline_config(&dir))
+    .roles(vec![OPS])
+    .instance_ids(vec!["i-0000000002".to_owned()])
+    .credentials_provider(NoCredentials)
+    .instance_source(source)
+    .build()
+    .unwrap();
+  let hosts = inventory.hosts().unwrap();
+
+  assert_eq!(hosts.len(), 1);
+  assert_eq!(hosts[0].name, "i-0000000002");
+}
+
+#[test]
+fn roles_are_assumed_once_per_account() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\", \"us-west-2\"]");
+
+  let role_assumer = RoleAssumer::new()
+    .unwrap()
+    .with_base_credentials(AwsCredentials::new("base-key", "base-secret", None, None))
+    .with_alias("dev", DEV);
+  assert_eq!(role_assumer.resolve_alias("dev"), DEV);
+  assert_eq!(role_assumer.resolve_alias(OPS), OPS);
+  let session_name = role_assumer.get_session_name();
+  assert!(session_name.starts_with("ec2-rs-"), "unexpected session name: {}", session_name);
+
+  let inventory = Inventory::builder()
+    .config(dir.config())
+    .roles(vec![OPS, "dev"])
+    .credentials_provider(role_assumer)
+    .build()
+    .unwrap();
+  let first = inventory.scan();
+  let second = inventory.scan();
+
+  assert!(first.failures.is_empty() && second.failures.is_empty());
+  assert_eq!(first.hosts().len(), 3);
+  assert_eq!(stub.requests("DescribeInstances").len(), 8);
+  let assumed = stub.requests("AssumeRole");
+  let mut role_arns: Vec<&str> = assumed.iter().filter_map(|request| request.param("RoleArn")).collect();
+  role_arns.sort();
+  assert_eq!(role_arns, vec![OPS, DEV]);
+  assert!(assumed
+    .iter()
+    .all(|request| request.param("RoleSessionName") == Some(session_name.as_str())));
+}
+
+#[test]
+fn mfa_roles_fail_without_prompts_or_a_code() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("[roles.\"{}\"]\nmfa_serial = \"arn:aws:iam::000000000000:mfa/jane\"", OPS),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let role_assumer = RoleAssumer::new()
+    .unwrap()
+    .with_base_credentials(AwsCredentials::new("base-key", "base-secret", None, None))
+    .without_mfa_prompts();
+  let err = role_assumer.assume_role(&dir.config(), OPS).unwrap_err();
+
+  assert!(err.to_string().contains("EC2_RS_MFA_TOKEN"), "unexpected error: {}", err);
+  assert!(stub.requests("GetSessionToken").is_empty());
+  assert!(role_assumer.assume_role(&dir.config(), DEV).is_ok());
+}
@@ -0,0 +1,89 @@
+//! End to end tests of finding the accounts to scan through a stubbed out Organizations.
+
+mod common;
+
+use common::{assumed_roles, group_hosts, StubAccount, StubAws, StubInstance, TestDir, DEV, OPS};
+
+const NESTED: &str = "arn:aws:iam::444444444444:role/ops";
+
+fn stub_with_an_organization() -> StubAws {
+  StubAws::builder()
+    .organizational_unit("ou-a", "r-root")
+    .organizational_unit("ou-b", "ou-a")
+    .account(StubAccount::new("111111111111", "r-root"))
+    .account(StubAccount::new("222222222222", "ou-a").tag("env", "prod"))
+    .account(StubAccount::new("333333333333", "ou-a").suspended())
+    .account(StubAccount::new("444444444444", "ou-b").tag("env", "dev"))
+    .account(StubAccount::new("555555555555", "r-root"))
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .instances(DEV, "us-east-1", vec![StubInstance::vpc("i-0000000002", "us-east-1a", "10.1.0.1")])
+    .instances(NESTED, "us-east-1", vec![StubInstance::vpc("i-0000000004", "us-east-1a", "10.4.0.1")])
+    .start()
+}
+
+fn write_organization_config(dir: &TestDir, stub: &StubAws, organizations: &str) {
+  dir.write_config(
+    stub,
+    &format!(
+      "organizations_endpoint = \"{}\"\n\n[organizations]\nrole_arn = \"arn:aws:iam::{{account_id}}:role/ops\"\n\
+       exclude_accounts = [\"555555555555\"]\n{}",
+      stub.endpoint(),
+      organizations
+    ),
+    "regions = [\"us-east-1\"]",
+  );
+}
+
+#[test]
+fn every_active_account_in_the_organization_is_scanned() {
+  let stub = stub_with_an_organization();
+  let dir = TestDir::new();
+  write_organization_config(&dir, &stub, "\n[organizations.assume_role]\nexternal_id = \"org-id\"");
+
+  let inventory = dir.run_json("organization", &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1", "10.4.0.1"]);
+  let listed = stub.requests("ListAccounts");
+  assert_eq!(listed.len(), 3);
+  assert!(listed.iter().all(|request| request.access_key == "base-key"));
+  assert_eq!(assumed_roles(&stub), vec![OPS, DEV, NESTED]);
+  assert!(stub
+    .requests("AssumeRole")
+    .iter()
+    .all(|request| request.param("ExternalId") == Some("org-id")));
+}
+
+#[test]
+fn accounts_can_be_restricted_by_organizational_unit_and_tags() {
+  let stub = stub_with_an_organization();
+  let dir = TestDir::new();
+  write_organization_config(&dir, &stub, "organizational_units = [\"ou-a\"]\ntags = { env = \"prod\" }");
+
+  let inventory = dir.run_json(&format!("organization,{}", OPS), &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1"]);
+  assert!(stub.requests("ListAccounts").is_empty());
+  let mut parents: Vec<String> = stub
+    .requests("ListAccountsForParent")
+    .iter()
+    .filter_map(|request| request.param("ParentId").map(|parent| parent.to_owned()))
+    .collect();
+  parents.sort();
+  assert_eq!(parents, vec!["ou-a", "ou-b"]);
+  assert_eq!(assumed_roles(&stub), vec![OPS, DEV]);
+}
+
+#[test]
+fn accounts_found_are_cached() {
+  let stub = stub_with_an_organization();
+  let dir = TestDir::new();
+  write_organization_config(&dir, &stub, "");
+
+  dir.run_json("organization", &["--list"], &[]);
+  let inventory = dir.run_json("organization", &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1", "10.4.0.1"]);
+  assert_eq!(stub.requests("ListAccounts").len(), 3);
+
+  dir.run_json("organization", &["--list", "--refresh-cache"], &[]);
+  assert_eq!(stub.requests("ListAccounts").len(), 6);
+}
@@ -0,0 +1,123 @@
+//! Tests of scanning RDS instances alongside EC2 with `[rds] enabled = true`.
+
+mod common;
+
+use common::{group_hosts, StubAws, StubDatabase, StubInstance, TestDir, OPS};
+use serde_json::json;
+
+const RDS: &str = "[rds]\nenabled = true";
+
+fn stub_with_databases() -> StubAws {
+  StubAws::builder()
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .databases(
+      OPS,
+      "us-east-1",
+      vec![
+        StubDatabase::new("orders", "us-east-1b").tag("Team", "data"),
+        StubDatabase::new("reports", "us-east-1a"),
+        StubDatabase::new("archive", "us-east-1a").status("stopped"),
+      ],
+    )
+    .start()
+}
+
+#[test]
+fn rds_instances_are_grouped_alongside_ec2() {
+  let stub = stub_with_databases();
+  let dir = TestDir::new();
+  dir.write_config(&stub, RDS, "regions = [\"us-east-1\"]");
+  let orders = StubDatabase::new("orders", "us-east-1b").address();
+  let reports = StubDatabase::new("reports", "us-east-1a").address();
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "rds"), vec![orders.clone(), reports.clone()]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  assert_eq!(group_hosts(&inventory, "us-east-1"), vec!["10.0.0.1".to_owned(), orders.clone(), reports.clone()]);
+  assert_eq!(group_hosts(&inventory, "us-east-1b"), vec![orders.clone()]);
+  assert_eq!(group_hosts(&inventory, "orders"), vec![orders.clone()]);
+  assert_eq!(group_hosts(&inventory, "rds_postgres"), vec![orders.clone(), reports.clone()]);
+  assert_eq!(group_hosts(&inventory, "type_db_t3_micro"), vec![orders.clone(), reports]);
+  assert_eq!(group_hosts(&inventory, "tag_team_data"), vec![orders.clone()]);
+
+  let host = dir.run_json(OPS, &["--host", &orders], &[]);
+  assert_eq!(host["rds_engine"], json!("postgres"));
+  assert_eq!(host["rds_port"], json!(5432));
+  assert_eq!(host["rds_region"], json!("us-east-1"));
+  assert_eq!(host["rds_tag_team"], json!("data"));
+  assert_eq!(host["rds_account_value"], json!(OPS));
+}
+
+#[test]
+fn rds_hosts_follow_the_include_and_exclude_filters() {
+  let stub = stub_with_databases();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("{}\nall_instances = true", RDS),
+    "regions = [\"us-east-1\"]\ninclude_filter = \"^(10|orders|archive)\"\nexclude_filter = \"^archive\"",
+  );
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "rds"), vec![StubDatabase::new("orders", "us-east-1b").address()]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+}
+
+#[test]
+fn rds_failures_keep_the_ec2_hosts_of_the_region() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .failing_databases(OPS, "us-east-1")
+    .start();
+
+  let dir = TestDir::new();
+  dir.write_config(&stub, RDS, "regions = [\"us-east-1\"]");
+  let output = dir.run(OPS, &["--list"], &[]);
+  assert!(!output.status.success());
+  assert!(String::from_utf8_lossy(&output.stderr).contains("rds:us-east-1"));
+
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("failure_policy = \"skip_and_warn\"\nfailures_in_meta = true\n{}", RDS),
+    "regions = [\"us-east-1\"]",
+  );
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  assert!(group_hosts(&inventory, "rds").is_empty());
+  let failures = inventory["_meta"]["failures"].as_array().unwrap();
+  assert_eq!(failures.len(), 1);
+  assert_eq!(failures[0]["account"], json!(OPS));
+  assert_eq!(failures[0]["region"], json!("rds:us-east-1"));
+
+  // The region isn't cached without its RDS instances, so it's scanned again.
+  let described = stub.requests("DescribeInstances").len();
+  dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(stub.requests("DescribeInstances").len(), described + 1);
+}
+
+#[test]
+fn rds_failures_arent_filled_in_from_the_cache() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .failing_databases(OPS, "us-east-1")
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("failure_policy = \"use_stale_cache_for_failed_region\"\n{}", RDS),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let output = dir.run(OPS, &["--list"], &[("EC2_RS_LOG_TO_CONSOLE", "1")]);
+
+  assert!(output.status.success(), "ec2-rs failed: {}", String::from_utf8_lossy(&output.stderr));
+  let inventory: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  // The EC2 hosts of the region were kept, so there's nothing to fill in.
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(stderr.contains("rds:us-east-1"), "unexpected stderr: {}", stderr);
+  assert!(!stderr.contains("No cache to fill"), "unexpected stderr: {}", stderr);
+}
@@ -0,0 +1,171 @@
+//! End to end tests of `ec2-rs record`, and `--replay` against a stubbed out EC2, and STS.
+
+mod common;
+
+use common::{group_hosts, stub_with_two_regions, StubAws, StubInstance, TestDir, DEV, OPS};
+use serde_json::{json, Value as JsonValue};
+
+use std::fs;
+
+/// Sorts the hosts of every group, as they come back in whatever order the regions finished scanning in.
+fn sort_groups(mut inventory: JsonValue) -> JsonValue {
+  for group in inventory.as_object_mut().unwrap().values_mut() {
+    if let Some(hosts) = group.as_array_mut() {
+      hosts.sort_by_key(|host| host.to_string());
+    }
+  }
+  inventory
+}
+
+#[test]
+fn record_writes_every_instance_of_each_account_and_region() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\", \"us-west-2\"]");
+  let recording = dir.path.join("recording");
+
+  let output = dir.run(&format!("{},{}", OPS, DEV), &["record", recording.to_str().unwrap()], &[]);
+  assert!(output.status.success(), "ec2-rs failed: {}", String::from_utf8_lossy(&output.stderr));
+  assert!(String::from_utf8_lossy(&output.stdout).contains("Recorded 4 account/region pair(s)"));
+
+  let recorded: JsonValue = serde_json::from_slice(
+    &fs::read(recording.join("arn_aws_iam__111111111111_role_ops").join("us-east-1.json")).unwrap(),
+  )
+  .unwrap();
+  assert_eq!(recorded["account"], json!(OPS));
+  assert_eq!(recorded["region"], json!("us-east-1"));
+  // Instances are recorded as EC2 handed them back, stopped ones included.
+  let instances = recorded["instances"].as_array().unwrap();
+  assert_eq!(instances.len(), 3);
+  assert_eq!(instances[2]["instance_id"], json!("i-0000000003"));
+  assert_eq!(instances[2]["state_name"], json!("stopped"));
+  assert_eq!(instances[0]["tags"], json!([["Role", "web"]]));
+
+  let empty: JsonValue = serde_json::from_slice(
+    &fs::read(recording.join("arn_aws_iam__222222222222_role_ops").join("us-east-1.json")).unwrap(),
+  )
+  .unwrap();
+  assert_eq!(empty["instances"], json!([]));
+  assert!(!dir.path.join("cache").exists());
+}
+
+#[test]
+fn replay_matches_a_live_run_without_touching_aws() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "nested_groups = true", "regions = [\"us-east-1\", \"us-west-2\"]");
+  let recording = dir.path.join("recording");
+  let roles = format!("{},{}", OPS, DEV);
+
+  let output = dir.run(&roles, &["record", recording.to_str().unwrap()], &[]);
+  assert!(output.status.success(), "ec2-rs failed: {}", String::from_utf8_lossy(&output.stderr));
+  let described = stub.requests("DescribeInstances").len();
+  let assumed = stub.requests("AssumeRole").len();
+
+  let mut replay = dir.command("", &["--list", "--replay", recording.to_str().unwrap()], &[]);
+  replay.env_remove("EC2_RS_ASSUME_ROLE");
+  let output = replay.output().unwrap();
+  assert!(output.status.success(), "ec2-rs failed: {}", String::from_utf8_lossy(&output.stderr));
+  let replayed: JsonValue = serde_json::from_slice(&output.stdout).unwrap();
+
+  assert_eq!(stub.requests("DescribeInstances").len(), described);
+  assert_eq!(stub.requests("AssumeRole").len(), assumed);
+  assert!(!dir.path.join("cache").exists());
+
+  let live = dir.run_json(&roles, &["--list"], &[]);
+  assert_eq!(sort_groups(replayed.clone()), sort_groups(live));
+  assert_eq!(group_hosts(&replayed, "ec2"), vec!["10.0.0.1", "10.0.0.2", "ip-10-1-0-1.ec2.internal"]);
+}
+
+#[test]
+fn replay_names_the_current_account_like_a_live_run() {
+  let stub = StubAws::builder()
+    .instances("base-key", "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .caller_account("base-key", "123456789012")
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]\ngroup_by_aws_account = true");
+  let recording = dir.path.join("recording");
+  let output = dir.run("current", &["record", recording.to_str().unwrap()], &[]);
+  assert!(output.status.success(), "ec2-rs failed: {}", String::from_utf8_lossy(&output.stderr));
+
+  let replayed = dir.run_json("", &["--list", "--replay", recording.to_str().unwrap()], &[]);
+
+  let live = dir.run_json("current", &["--list"], &[]);
+  assert_eq!(sort_groups(replayed.clone()), sort_groups(live));
+  assert_eq!(group_hosts(&replayed, "account_123456789012"), vec!["10.0.0.1"]);
+  assert_eq!(replayed["_meta"]["hostvars"]["10.0.0.1"]["ec2_account_value"], json!("123456789012"));
+}
+
+#[test]
+fn replay_from_the_environment_serves_host_and_instance_ids() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\", \"us-west-2\"]");
+  let recording = dir.path.join("recording");
+  let output = dir.run(&format!("{},{}", OPS, DEV), &["record", recording.to_str().unwrap()], &[]);
+  assert!(output.status.success(), "ec2-rs failed: {}", String::from_utf8_lossy(&output.stderr));
+  let replay_env = [("EC2_RS_REPLAY", recording.to_str().unwrap())];
+
+  let host = dir.run_json(OPS, &["--host", "i-0000000004"], &replay_env);
+  assert_eq!(host["ec2_account_value"], json!(DEV));
+
+  let hosts = dir.run_json(OPS, &[], &[replay_env[0], ("EC2_HOSTS", "i-0000000003")]);
+  let hosts = hosts.as_array().unwrap();
+  assert_eq!(hosts.len(), 1);
+  assert_eq!(hosts[0]["ec2_state"], json!("stopped"));
+}
+
+#[test]
+fn replay_leaves_out_route53_and_rds() {
+  let stub = stub_with_two_regions();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+  let recording = dir.path.join("recording");
+  let output = dir.run(OPS, &["record", recording.to_str().unwrap()], &[]);
+  assert!(output.status.success(), "ec2-rs failed: {}", String::from_utf8_lossy(&output.stderr));
+  let assumed = stub.requests("AssumeRole").len();
+
+  dir.write_config(&stub, "[route53]\nenabled = true\n\n[rds]\nenabled = true", "regions = [\"us-east-1\"]");
+  let inventory = dir.run_json("", &["--list", "--replay", recording.to_str().unwrap()], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.0.0.2"]);
+  assert!(inventory.get("route53").is_none());
+  assert!(inventory.get("rds").is_none());
+  assert_eq!(stub.requests("AssumeRole").len(), assumed);
+}
+
+#[test]
+fn replay_accepts_hand_written_recordings() {
+  let stub = StubAws::builder().start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]\ngroup_by_aws_account = true");
+  let account_dir = dir.path.join("recording").join("ops");
+  fs::create_dir_all(&account_dir).unwrap();
+  fs::write(
+    account_dir.join("eu-west-1.json"),
+    r#"{"account": "ops", "region": "eu-west-1", "recorded_at": 0, "instances": [
+      {"instance_id": "i-0000000009", "availability_zone": "eu-west-1c", "subnet_id": "subnet-1",
+       "private_ip_address": "10.9.0.9", "state_code": 16}
+    ]}"#,
+  )
+  .unwrap();
+
+  let inventory = dir.run_json(OPS, &["--replay", dir.path.join("recording").to_str().unwrap()], &[]);
+
+  assert_eq!(group_hosts(&inventory, "eu-west-1c"), vec!["10.9.0.9"]);
+  assert_eq!(group_hosts(&inventory, "account_ops"), vec!["10.9.0.9"]);
+  assert!(stub.requests("DescribeInstances").is_empty());
+}
+
+#[test]
+fn replaying_a_missing_recording_fails() {
+  let stub = StubAws::builder().start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = [\"us-east-1\"]");
+
+  let output = dir.run(OPS, &["--replay", dir.path.join("nowhere").to_str().unwrap()], &[]);
+
+  assert!(!output.status.success());
+  assert!(String::from_utf8_lossy(&output.stderr).contains("There's no recording at"));
+}
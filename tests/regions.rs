@@ -0,0 +1,177 @@
+//! Tests of scanning every region enabled in each account with `regions = "all"`.
+
+mod common;
+
+use common::{group_hosts, StubAws, StubInstance, TestDir, DEV, OPS};
+use ec2_rs_lib::inventory::Inventory;
+use ec2_rs_lib::role_assumer::RoleAssumer;
+use rusoto_credential::AwsCredentials;
+use serde_json::json;
+
+fn scanned_regions(stub: &StubAws, role_arn: &str) -> Vec<String> {
+  let mut regions: Vec<String> = stub
+    .requests("DescribeInstances")
+    .into_iter()
+    .filter(|request| request.role_arn.as_deref() == Some(role_arn))
+    .map(|request| request.region)
+    .collect();
+  regions.sort();
+  regions
+}
+
+#[test]
+fn all_scans_every_enabled_region_except_excluded_ones() {
+  let stub = StubAws::builder()
+    .regions(
+      OPS,
+      &[
+        ("us-east-1", "opt-in-not-required"),
+        ("us-west-2", "opt-in-not-required"),
+        ("eu-south-1", "opted-in"),
+        ("af-south-1", "not-opted-in"),
+      ],
+    )
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .instances(OPS, "us-west-2", vec![StubInstance::vpc("i-0000000002", "us-west-2a", "10.0.0.2")])
+    .instances(OPS, "eu-south-1", vec![StubInstance::vpc("i-0000000003", "eu-south-1a", "10.0.0.3")])
+    .instances(OPS, "af-south-1", vec![StubInstance::vpc("i-0000000004", "af-south-1a", "10.0.0.4")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = \"all\"\nexclude_regions = [\"us-west-2\"]");
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.0.0.3"]);
+  assert_eq!(scanned_regions(&stub, OPS), vec!["eu-south-1", "us-east-1"]);
+  let described = stub.requests("DescribeRegions");
+  assert_eq!(described.len(), 1);
+  assert_eq!(described[0].role_arn.as_deref(), Some(OPS));
+  assert_eq!(described[0].param("Filter.1.Name"), Some("opt-in-status"));
+}
+
+#[test]
+fn regions_are_found_for_each_account() {
+  let stub = StubAws::builder()
+    .regions(OPS, &[("us-east-1", "opt-in-not-required")])
+    .regions(DEV, &[("us-west-2", "opt-in-not-required"), ("ap-east-1", "opted-in")])
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .instances(DEV, "ap-east-1", vec![StubInstance::vpc("i-0000000002", "ap-east-1a", "10.1.0.1")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = \"all\"");
+
+  let inventory = dir.run_json(&format!("{},{}", OPS, DEV), &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1", "10.1.0.1"]);
+  assert_eq!(scanned_regions(&stub, OPS), vec!["us-east-1"]);
+  assert_eq!(scanned_regions(&stub, DEV), vec!["ap-east-1", "us-west-2"]);
+}
+
+#[test]
+fn found_regions_are_cached() {
+  let stub = StubAws::builder()
+    .regions(OPS, &[("us-east-1", "opt-in-not-required")])
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = \"all\"");
+
+  dir.run_json(OPS, &["--list"], &[]);
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  assert_eq!(stub.requests("DescribeRegions").len(), 1);
+  assert_eq!(stub.requests("DescribeInstances").len(), 1);
+
+  dir.run_json(OPS, &["--list", "--refresh-cache"], &[]);
+  assert_eq!(stub.requests("DescribeRegions").len(), 2);
+}
+
+#[test]
+fn accounts_whose_regions_cant_be_found_follow_the_failure_policy() {
+  let stub = StubAws::builder()
+    .regions(OPS, &[("us-east-1", "opt-in-not-required")])
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .start();
+  let roles = format!("{},{}", OPS, DEV);
+
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = \"all\"");
+  let output = dir.run(&roles, &["--list"], &[]);
+  assert!(!output.status.success());
+  assert!(String::from_utf8_lossy(&output.stderr).contains(DEV));
+
+  let dir = TestDir::new();
+  dir.write_config(&stub, "failure_policy = \"skip_and_warn\"\nfailures_in_meta = true", "regions = \"all\"");
+  let inventory = dir.run_json(&roles, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  let failures = inventory["_meta"]["failures"].as_array().unwrap();
+  assert_eq!(failures.len(), 1);
+  assert_eq!(failures[0]["account"], json!(DEV));
+  assert_eq!(failures[0]["region"], json!("regions"));
+}
+
+#[test]
+fn inventories_find_regions_for_each_account() {
+  let stub = StubAws::builder()
+    .regions(OPS, &[("us-east-1", "opt-in-not-required"), ("eu-south-1", "opted-in")])
+    .instances(OPS, "eu-south-1", vec![StubInstance::vpc("i-0000000001", "eu-south-1a", "10.0.0.1")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = \"all\"\nexclude_regions = [\"us-east-1\"]");
+
+  let hosts = Inventory::builder()
+    .config(dir.config())
+    .roles(vec![OPS])
+    .credentials_provider(
+      RoleAssumer::new()
+        .unwrap()
+        .with_base_credentials(AwsCredentials::new("base-key", "base-secret", None, None)),
+    )
+    .build()
+    .unwrap()
+    .hosts()
+    .unwrap();
+
+  let summary: Vec<(&str, &str)> = hosts.iter().map(|host| (host.name.as_str(), host.region.as_str())).collect();
+  assert_eq!(summary, vec![("10.0.0.1", "eu-south-1")]);
+  assert_eq!(scanned_regions(&stub, OPS), vec!["eu-south-1"]);
+}
+
+#[test]
+fn regions_are_found_from_the_discovery_region() {
+  let stub = StubAws::builder()
+    .regions(OPS, &[("us-gov-west-1", "opt-in-not-required")])
+    .instances(OPS, "us-gov-west-1", vec![StubInstance::vpc("i-0000000001", "us-gov-west-1a", "10.0.0.1")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "", "regions = \"all\"\ndiscovery_region = \"us-gov-west-1\"");
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  let described = stub.requests("DescribeRegions");
+  assert_eq!(described.len(), 1);
+  assert_eq!(described[0].region, "us-gov-west-1");
+}
+
+#[test]
+fn changing_the_discovery_region_looks_regions_up_again() {
+  let stub = StubAws::builder()
+    .regions(OPS, &[("us-gov-west-1", "opt-in-not-required")])
+    .instances(OPS, "us-gov-west-1", vec![StubInstance::vpc("i-0000000001", "us-gov-west-1a", "10.0.0.1")])
+    .start();
+  let dir = TestDir::new();
+  dir.write_config(&stub, "cache_max_age = 300", "regions = \"all\"\ndiscovery_region = \"us-gov-west-1\"");
+  dir.run_json(OPS, &["--list"], &[]);
+  dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(stub.requests("DescribeRegions").len(), 1);
+
+  dir.write_config(&stub, "cache_max_age = 300", "regions = \"all\"\ndiscovery_region = \"cn-north-1\"");
+  dir.run_json(OPS, &["--list"], &[]);
+
+  let described = stub.requests("DescribeRegions");
+  assert_eq!(described.len(), 2);
+  assert_eq!(described[1].region, "cn-north-1");
+  // The instances themselves are still cached, only the regions depend on where they were looked up.
+  assert_eq!(stub.requests("DescribeInstances").len(), 1);
+}
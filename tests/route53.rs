@@ -0,0 +1,122 @@
+//! Tests of naming, and grouping hosts by the Route53 records pointing at them with `[route53] enabled = true`.
+
+mod common;
+
+use common::{group_hosts, StubAws, StubInstance, TestDir, OPS};
+use serde_json::json;
+
+const ROUTE53: &str = "[route53]\nenabled = true";
+
+fn stub_with_records() -> StubAws {
+  StubAws::builder()
+    .instances(
+      OPS,
+      "us-east-1",
+      vec![
+        StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1"),
+        StubInstance::vpc("i-0000000002", "us-east-1b", "10.0.0.2"),
+      ],
+    )
+    .hosted_zone(
+      OPS,
+      "example.com",
+      &[
+        ("web.example.com", "A", "10.0.0.1"),
+        ("www.example.com", "CNAME", "ip-10-0-0-1.ec2.internal"),
+        ("mail.example.com", "MX", "10 10.0.0.2"),
+      ],
+    )
+    .hosted_zone(OPS, "internal.example.com", &[("db.internal.example.com", "A", "10.0.0.2")])
+    .start()
+}
+
+#[test]
+fn route53_records_become_groups() {
+  let stub = stub_with_records();
+  let dir = TestDir::new();
+  dir.write_config(&stub, ROUTE53, "regions = [\"us-east-1\"]");
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "web_example_com"), vec!["10.0.0.1"]);
+  assert_eq!(group_hosts(&inventory, "www_example_com"), vec!["10.0.0.1"]);
+  assert_eq!(group_hosts(&inventory, "db_internal_example_com"), vec!["10.0.0.2"]);
+  assert!(inventory.get("mail_example_com").is_none());
+  assert_eq!(stub.requests("ListHostedZones").len(), 1);
+  let listed: Vec<String> = stub
+    .requests("ListResourceRecordSets")
+    .iter()
+    .filter_map(|request| request.param("Id").map(|id| id.to_owned()))
+    .collect();
+  assert_eq!(listed, vec!["Z0", "Z1"]);
+}
+
+#[test]
+fn route53_hosted_zones_can_be_left_out() {
+  let stub = stub_with_records();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("{}\nexcluded_zones = [\"internal.example.com\"]", ROUTE53),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  assert_eq!(group_hosts(&inventory, "web_example_com"), vec!["10.0.0.1"]);
+  assert!(inventory.get("db_internal_example_com").is_none());
+  assert_eq!(stub.requests("ListResourceRecordSets").len(), 1);
+}
+
+#[test]
+fn route53_hostnames_name_hosts_by_the_first_matching_record() {
+  let stub = stub_with_records();
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("{}\nhostnames = \"\\\\.example\\\\.com$\"", ROUTE53),
+    "regions = [\"us-east-1\"]",
+  );
+
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+
+  // Records are matched alphabetically, and hosts without a match keep their destination variable.
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["db.internal.example.com", "web.example.com"]);
+  assert_eq!(group_hosts(&inventory, "www_example_com"), vec!["web.example.com"]);
+  assert_eq!(inventory["_meta"]["hostvars"]["web.example.com"]["ec2_id"], json!("i-0000000001"));
+
+  let host = dir.run_json(OPS, &["--host", "db.internal.example.com"], &[]);
+  assert_eq!(host["ec2_id"], json!("i-0000000002"));
+}
+
+#[test]
+fn route53_failures_follow_the_failure_policy_and_skip_the_cache() {
+  let stub = StubAws::builder()
+    .instances(OPS, "us-east-1", vec![StubInstance::vpc("i-0000000001", "us-east-1a", "10.0.0.1")])
+    .failing_route53(OPS)
+    .start();
+
+  let dir = TestDir::new();
+  dir.write_config(&stub, ROUTE53, "regions = [\"us-east-1\"]");
+  let output = dir.run(OPS, &["--list"], &[]);
+  assert!(!output.status.success());
+  assert!(String::from_utf8_lossy(&output.stderr).contains("route53"));
+
+  let dir = TestDir::new();
+  dir.write_config(
+    &stub,
+    &format!("failure_policy = \"skip_and_warn\"\nfailures_in_meta = true\n{}", ROUTE53),
+    "regions = [\"us-east-1\"]",
+  );
+  let inventory = dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(group_hosts(&inventory, "ec2"), vec!["10.0.0.1"]);
+  let failures = inventory["_meta"]["failures"].as_array().unwrap();
+  assert_eq!(failures.len(), 1);
+  assert_eq!(failures[0]["account"], json!(OPS));
+  assert_eq!(failures[0]["region"], json!("route53"));
+
+  // The region isn't cached without its Route53 records, so it's scanned again.
+  let described = stub.requests("DescribeInstances").len();
+  dir.run_json(OPS, &["--list"], &[]);
+  assert_eq!(stub.requests("DescribeInstances").len(), described + 1);
+}